@@ -45,24 +45,27 @@ with open(cache_file, "r") as f:
     for line in f:
         parts = line.split()
         if len(parts) > 13:
+            # newer results have a fifth cost column, for deadline
+            c = 1 if len(parts) > 15 else 0
             entry = dict()
             entry["params"] = parse_parameters(parts[0], skip=["search_depth", "total_forward_t", "max_steps", "safety_margin_low", "safety_margin_high", "accel", "steer"])
-            entry["crashed"] = float(parts[5])
-            entry["end_t"] = float(parts[6])
-            entry["dist_travelled"] = float(parts[7])
-            entry["efficiency"] = float(parts[8])
-            entry["mean_ts"] = float(parts[9])
-            entry["95_ts"] = float(parts[10])
-            entry["997_ts"] = float(parts[11])
-            entry["max_ts"] = float(parts[12])
-            entry["stddev_ts"] = float(parts[13])
+            entry["crashed"] = float(parts[5 + c])
+            entry["end_t"] = float(parts[6 + c])
+            entry["dist_travelled"] = float(parts[7 + c])
+            entry["efficiency"] = float(parts[8 + c])
+            entry["mean_ts"] = float(parts[9 + c])
+            entry["95_ts"] = float(parts[10 + c])
+            entry["997_ts"] = float(parts[11 + c])
+            entry["max_ts"] = float(parts[12 + c])
+            entry["stddev_ts"] = float(parts[13 + c])
 
             entry["cost.efficiency"] = float(parts[1])
             entry["cost.safety"] = float(parts[2])
             entry["cost.accel"] = float(parts[3])
             entry["cost.steer"] = float(parts[4])
+            entry["cost.deadline"] = float(parts[5]) if c else 0.0
             entry["cost"] = entry["cost.efficiency"] + entry["cost.safety"] + \
-                entry["cost.accel"] + entry["cost.steer"]
+                entry["cost.accel"] + entry["cost.steer"] + entry["cost.deadline"]
 
             results.append(entry)
         else:
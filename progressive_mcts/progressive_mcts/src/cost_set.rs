@@ -1,8 +1,18 @@
+use core::cmp::Ordering;
 use core::fmt::Debug;
 use core::ops::AddAssign;
 use num_traits::{cast::FromPrimitive, float::Float, identities::One, identities::Zero};
 use rolling_stats::Stats;
 
+// Kish's effective sample size of importance weights, from their sum and sum of squares
+pub fn effective_sample_size<F: Float>(sum_weights: F, sum_sq_weights: F) -> F {
+    if sum_sq_weights > F::zero() {
+        sum_weights * sum_weights / sum_sq_weights
+    } else {
+        F::zero()
+    }
+}
+
 #[derive(Clone)]
 pub struct CostSet<
     F: Float + Zero + One + AddAssign + FromPrimitive + PartialEq + Debug = f64,
@@ -10,6 +20,19 @@ pub struct CostSet<
 > {
     costs: Vec<(F, T)>,
     stats: Stats<F>,
+    // importance weights, in the same order as costs
+    weights: Vec<F>,
+    weighted: WeightedStats<F>,
+}
+
+// West's incremental weighted mean and variance
+#[derive(Clone)]
+struct WeightedStats<F> {
+    sum_weights: F,
+    sum_sq_weights: F,
+    mean: F,
+    s: F,
+    all_ones: bool,
 }
 
 impl std::fmt::Debug for CostSet {
@@ -18,39 +41,101 @@ impl std::fmt::Debug for CostSet {
             .field("costs", &self.costs)
             .field("mean", &self.stats.mean)
             .field("std_dev", &self.stats.std_dev)
+            .field("weights", &self.weights)
             .finish()
     }
 }
 
-impl<F: Float + Zero + One + AddAssign + FromPrimitive + PartialEq + Debug, T: Clone + Default>
+impl<F: Float + Zero + One + AddAssign + FromPrimitive + PartialEq + Debug, T: Clone> Default
+    for CostSet<F, T>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Float + Zero + One + AddAssign + FromPrimitive + PartialEq + Debug, T: Clone>
     CostSet<F, T>
 {
     pub fn new() -> Self {
         Self {
             costs: Vec::new(),
             stats: Stats::new(),
+            weights: Vec::new(),
+            weighted: WeightedStats {
+                sum_weights: F::zero(),
+                sum_sq_weights: F::zero(),
+                mean: F::zero(),
+                s: F::zero(),
+                all_ones: true,
+            },
         }
     }
 
     pub fn push(&mut self, cost: (F, T)) {
+        self.push_weighted(cost, F::one());
+    }
+
+    pub fn push_weighted(&mut self, cost: (F, T), weight: F) {
         let cost_val = cost.0;
 
         self.costs.push(cost);
         self.stats.update(cost_val);
+
+        self.weights.push(weight);
+        let w = &mut self.weighted;
+        w.all_ones &= weight == F::one();
+        w.sum_weights += weight;
+        w.sum_sq_weights += weight * weight;
+        if w.sum_weights > F::zero() {
+            let mean_old = w.mean;
+            w.mean = mean_old + (weight / w.sum_weights) * (cost_val - mean_old);
+            w.s += weight * (cost_val - mean_old) * (cost_val - w.mean);
+        }
+    }
+
+    // whether any of the costs has an importance weight other than one
+    pub fn is_weighted(&self) -> bool {
+        !self.weighted.all_ones
     }
 
     pub fn mean(&self) -> F {
-        self.stats.mean
+        if self.is_weighted() {
+            self.weighted.mean
+        } else {
+            self.stats.mean
+        }
     }
 
     pub fn std_dev(&self) -> F {
-        if self.stats.std_dev.is_finite() {
+        let std_dev = if self.is_weighted() {
+            // with the weights taken as reliability weights
+            let w = &self.weighted;
+            let denom = w.sum_weights - w.sum_sq_weights / w.sum_weights;
+            (w.s / denom).sqrt()
+        } else {
             self.stats.std_dev
+        };
+        if std_dev.is_finite() {
+            std_dev
         } else {
             F::from_f64(1e12).unwrap()
         }
     }
 
+    // Kish's effective sample size, which is just the length without weights
+    pub fn effective_len(&self) -> F {
+        if self.is_weighted() {
+            effective_sample_size(self.weighted.sum_weights, self.weighted.sum_sq_weights)
+        } else {
+            F::from_usize(self.len()).unwrap()
+        }
+    }
+
+    pub fn sum_weights(&self) -> F {
+        self.weighted.sum_weights
+    }
+
     pub fn len(&self) -> usize {
         self.costs.len()
     }
@@ -62,4 +147,83 @@ impl<F: Float + Zero + One + AddAssign + FromPrimitive + PartialEq + Debug, T: C
     pub fn iter(&self) -> impl Iterator<Item = &(F, T)> {
         self.costs.iter()
     }
+
+    pub fn iter_weighted(&self) -> impl Iterator<Item = (&(F, T), F)> {
+        self.costs.iter().zip(self.weights.iter().copied())
+    }
+
+    // the statistics don't depend on the order, so they stay as they are
+    pub fn sort_by(&mut self, mut compare: impl FnMut(&(F, T), &(F, T)) -> Ordering) {
+        if !self.is_weighted() {
+            self.costs.sort_by(compare);
+            return;
+        }
+        let mut entries = self
+            .costs
+            .drain(..)
+            .zip(self.weights.drain(..))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| compare(&a.0, &b.0));
+        for (cost, weight) in entries {
+            self.costs.push(cost);
+            self.weights.push(weight);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn weighted_stats() {
+        let mut costs = CostSet::<f64, ()>::new();
+        for &(cost, weight) in &[(1.0, 1.0), (2.0, 3.0), (4.0, 0.5), (3.0, 1.5)] {
+            costs.push_weighted((cost, ()), weight);
+        }
+        assert!(costs.is_weighted());
+
+        let sum_weights = 6.0;
+        let mean = (1.0 + 2.0 * 3.0 + 4.0 * 0.5 + 3.0 * 1.5) / sum_weights;
+        assert_abs_diff_eq!(costs.mean(), mean, epsilon = 1e-12);
+
+        let sum_sq_weights: f64 = 1.0 + 9.0 + 0.25 + 2.25;
+        let s = (1.0 - mean).powi(2)
+            + 3.0 * (2.0 - mean).powi(2)
+            + 0.5 * (4.0 - mean).powi(2)
+            + 1.5 * (3.0 - mean).powi(2);
+        let std_dev = (s / (sum_weights - sum_sq_weights / sum_weights)).sqrt();
+        assert_abs_diff_eq!(costs.std_dev(), std_dev, epsilon = 1e-12);
+        assert_abs_diff_eq!(
+            costs.effective_len(),
+            sum_weights * sum_weights / sum_sq_weights,
+            epsilon = 1e-12
+        );
+
+        costs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let sorted = costs
+            .iter_weighted()
+            .map(|(c, w)| (c.0, w))
+            .collect::<Vec<_>>();
+        assert_eq!(sorted, vec![(1.0, 1.0), (2.0, 3.0), (3.0, 1.5), (4.0, 0.5)]);
+    }
+
+    #[test]
+    fn unit_weights_match_unweighted() {
+        let mut costs = CostSet::<f64, ()>::new();
+        for cost in [1.0, 5.0, 2.5] {
+            costs.push((cost, ()));
+        }
+        assert!(!costs.is_weighted());
+        assert_abs_diff_eq!(costs.mean(), 8.5 / 3.0, epsilon = 1e-12);
+        assert_abs_diff_eq!(costs.effective_len(), 3.0);
+    }
+
+    #[test]
+    fn effective_sample_size_of_weights() {
+        assert_abs_diff_eq!(effective_sample_size(4.0, 4.0), 4.0);
+        assert_abs_diff_eq!(effective_sample_size(2.0, 2.5), 1.6);
+        assert_eq!(effective_sample_size(0.0, 0.0), 0.0);
+    }
 }
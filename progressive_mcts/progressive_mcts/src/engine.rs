@@ -0,0 +1,836 @@
+use core::fmt::Debug;
+use core::ops::{Add, Sub};
+
+use rand::prelude::SliceRandom;
+
+use crate::cost_set::CostSet;
+use crate::klucb::klucb_bernoulli;
+use crate::{ChildSelectionMode, CostBoundMode, EarlyStopMode};
+
+// every root child needs this many trials of its own search before it can stop early
+const EARLY_STOP_MIN_TRIALS: usize = 4;
+
+// The parameters the search itself needs, whatever else the problem uses.
+pub trait MctsParams {
+    fn selection_mode(&self) -> ChildSelectionMode;
+    fn bound_mode(&self) -> CostBoundMode;
+    fn ucb_const(&self) -> f64;
+    fn ucbv_const(&self) -> f64;
+    fn ucbd_const(&self) -> f64;
+    fn klucb_max_cost(&self) -> f64;
+    fn cvar_alpha(&self) -> f64;
+}
+
+// A cost that may have several components, but is compared by its total.
+pub trait TrialCost: Copy + Debug + PartialOrd + Add<Output = Self> + Sub<Output = Self> {
+    const ZERO: Self;
+
+    fn total(&self) -> f64;
+
+    // the mean of a set of costs, which is kept by their totals
+    fn mean<T: Clone>(costs: &CostSet<f64, T>, cost: impl Fn(&T) -> Self) -> Self;
+}
+
+impl TrialCost for f64 {
+    const ZERO: Self = 0.0;
+
+    fn total(&self) -> f64 {
+        *self
+    }
+
+    // the totals are the costs, so the running mean will do
+    fn mean<T: Clone>(costs: &CostSet<f64, T>, _cost: impl Fn(&T) -> Self) -> Self {
+        costs.mean()
+    }
+}
+
+// One forward simulation of the problem, stepped through by the search
+// one action per tree level.
+pub trait Simulator {
+    type Params: MctsParams;
+    type Action: Clone;
+    type Cost: TrialCost;
+    // kept by each node along with a trial's cost, to be able to repeat that trial
+    type Particle: Clone;
+    type Rng: rand::Rng;
+
+    fn take_step(&mut self, params: &Self::Params, action: &Self::Action, rng: &mut Self::Rng);
+
+    fn cost(&self) -> Self::Cost;
+
+    fn particle_id(&self) -> usize;
+
+    // the importance weight of the particle being simulated
+    fn weight(&self) -> f64 {
+        1.0
+    }
+
+    // How expected costs rank when choosing between children, in backups as well as
+    // for the final choice: whether the cost is in a worse class, like one breaking
+    // a constraint, and then its value. By default, just the total.
+    fn choice_key(_params: &Self::Params, cost: &Self::Cost) -> (bool, f64) {
+        (false, cost.total())
+    }
+}
+
+pub fn compute_selection_index(
+    params: &impl MctsParams,
+    total_n: f64,
+    ln_total_n: f64,
+    n_trials: usize,
+    cost: f64,
+    variance: Option<f64>,
+) -> Option<f64> {
+    if n_trials == 0 {
+        return None;
+    }
+
+    let mean_cost = cost;
+    let n = n_trials as f64;
+    let ln_t_over_n = ln_total_n / n;
+    let index = match params.selection_mode() {
+        ChildSelectionMode::UCB => {
+            let upper_margin = params.ucb_const() * ln_t_over_n.sqrt();
+            assert!(upper_margin.is_finite(), "{}", n);
+            mean_cost + upper_margin
+        }
+        ChildSelectionMode::UCBV => {
+            let variance = variance.unwrap();
+            let upper_margin = params.ucb_const()
+                * (params.ucbv_const() * (variance * ln_t_over_n).sqrt() + ln_t_over_n);
+            mean_cost + upper_margin
+        }
+        ChildSelectionMode::UCBd => {
+            let a = (1.0 + n) / (n * n);
+            let b = (total_n * (1.0 + n).sqrt() / params.ucbd_const()).ln();
+            let upper_margin = params.ucb_const() * (a * (1.0 + 2.0 * b)).sqrt();
+            assert!(
+                upper_margin.is_finite(),
+                "a = {}, b = {}, n = {}, total_n = {}",
+                a,
+                b,
+                n,
+                total_n
+            );
+            mean_cost + upper_margin
+        }
+        ChildSelectionMode::KLUCB => {
+            let scaled_mean = (1.0 - mean_cost / params.klucb_max_cost()).clamp(0.0, 1.0);
+            -klucb_bernoulli(scaled_mean, params.ucb_const().abs() * ln_t_over_n)
+        }
+        ChildSelectionMode::KLUCBP => {
+            let scaled_mean = (1.0 - mean_cost / params.klucb_max_cost()).clamp(0.0, 1.0);
+            -klucb_bernoulli(
+                scaled_mean,
+                params.ucb_const().abs() * (total_n / n).ln() / n,
+            )
+        }
+        ChildSelectionMode::Uniform => n,
+    };
+    Some(index)
+}
+
+// A node of the search tree, with the statistics of the trials through it.
+// Anything else a particular search needs per node goes in data.
+pub struct MctsNode<'a, S: Simulator, X = ()> {
+    pub params: &'a S::Params,
+    pub policy_choices: &'a [S::Action],
+
+    pub policy: Option<S::Action>,
+    pub depth: u32,
+    pub n_trials: usize,
+    pub expected_cost: Option<S::Cost>,
+    pub expected_cost_std_dev: Option<f64>,
+
+    pub costs: CostSet<f64, (S::Cost, S::Particle)>,
+    pub intermediate_costs: CostSet<f64, S::Cost>,
+    pub marginal_costs: CostSet<f64, S::Cost>,
+    // how many of the costs were carried over from an earlier search
+    pub n_retained_trials: usize,
+
+    seen_particles: Vec<bool>,
+    pub n_particles_repeated: usize,
+
+    pub sub_nodes: Option<Vec<Self>>,
+    pub data: X,
+}
+
+impl<'a, S: Simulator, X: Default> MctsNode<'a, S, X> {
+    pub fn new(
+        params: &'a S::Params,
+        policy_choices: &'a [S::Action],
+        policy: Option<S::Action>,
+        depth: u32,
+    ) -> Self {
+        Self {
+            params,
+            policy_choices,
+            policy,
+            depth,
+            n_trials: 0,
+            expected_cost: None,
+            expected_cost_std_dev: None,
+            costs: CostSet::new(),
+            intermediate_costs: CostSet::new(),
+            marginal_costs: CostSet::new(),
+            n_retained_trials: 0,
+            seen_particles: Vec::new(),
+            n_particles_repeated: 0,
+            sub_nodes: None,
+            data: X::default(),
+        }
+    }
+
+    // expand node?
+    pub fn get_or_expand_sub_nodes_mut(&mut self) -> &mut Vec<Self> {
+        if self.sub_nodes.is_none() {
+            let params = self.params;
+            let policy_choices = self.policy_choices;
+            let depth = self.depth + 1;
+            self.sub_nodes = Some(
+                policy_choices
+                    .iter()
+                    .map(|p| Self::new(params, policy_choices, Some(p.clone()), depth))
+                    .collect(),
+            );
+        }
+
+        self.sub_nodes.as_mut().unwrap()
+    }
+
+    pub fn get_or_expand_sub_nodes(&mut self) -> &Vec<Self> {
+        self.get_or_expand_sub_nodes_mut()
+    }
+
+    // Takes this node's step of the trial, if it has a policy,
+    // returning the cost so far.
+    pub fn run_step(&mut self, sim: &mut S, rng: &mut S::Rng) -> Option<S::Cost> {
+        let policy = self.policy.as_ref()?;
+        let prev_cost = sim.cost();
+        sim.take_step(self.params, policy, rng);
+        let cost = sim.cost();
+        let weight = sim.weight();
+        self.intermediate_costs
+            .push_weighted((cost.total(), cost), weight);
+        let marginal_cost = cost - prev_cost;
+        self.marginal_costs
+            .push_weighted((marginal_cost.total(), marginal_cost), weight);
+        Some(cost)
+    }
+
+    // records the final cost of a trial through this node
+    pub fn record_trial(&mut self, particle_id: usize, cost: S::Cost, particle: S::Particle) {
+        self.record_weighted_trial(particle_id, cost, particle, 1.0);
+    }
+
+    pub fn record_weighted_trial(
+        &mut self,
+        particle_id: usize,
+        cost: S::Cost,
+        particle: S::Particle,
+        weight: f64,
+    ) {
+        self.costs
+            .push_weighted((cost.total(), (cost, particle)), weight);
+        if self.seen_particles.len() <= particle_id {
+            self.seen_particles.resize(particle_id + 1, false);
+        }
+        self.seen_particles[particle_id] = true;
+        self.n_trials = self.costs.len();
+    }
+
+    pub fn has_seen_particle(&self, particle_id: usize) -> bool {
+        self.seen_particles
+            .get(particle_id)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn clear_costs(&mut self) {
+        self.costs = CostSet::new();
+        self.intermediate_costs = CostSet::new();
+        self.marginal_costs = CostSet::new();
+        self.n_retained_trials = 0;
+        self.seen_particles.clear();
+        self.n_trials = 0;
+    }
+
+    pub fn variance(&self) -> f64 {
+        self.costs.std_dev().powi(2)
+    }
+
+    pub fn mean_cost(&self) -> S::Cost {
+        S::Cost::mean(&self.costs, |(c, _)| *c)
+    }
+
+    fn std_dev_of_mean(costs: &CostSet<f64, impl Clone>) -> f64 {
+        if costs.is_empty() {
+            0.0
+        } else {
+            costs.std_dev() / costs.effective_len().sqrt()
+        }
+    }
+
+    pub fn intermediate_cost(&self) -> S::Cost {
+        if self.intermediate_costs.is_empty() {
+            S::Cost::ZERO
+        } else {
+            S::Cost::mean(&self.intermediate_costs, |c| *c)
+        }
+    }
+
+    pub fn marginal_cost(&self) -> S::Cost {
+        if self.marginal_costs.is_empty() {
+            S::Cost::ZERO
+        } else {
+            S::Cost::mean(&self.marginal_costs, |c| *c)
+        }
+    }
+
+    // the costs in the worst alpha fraction, by weight, of this node's trials
+    fn worst_costs(&self, alpha: f64) -> CostSet<f64, S::Cost> {
+        let mut sorted = self
+            .costs
+            .iter_weighted()
+            .map(|((total, (cost, _)), weight)| (*total, *cost, weight))
+            .collect::<Vec<_>>();
+        sorted.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+        let mut tail_weight = alpha * self.costs.sum_weights();
+        let mut worst = CostSet::new();
+        for (total, cost, weight) in sorted {
+            // always at least the worst one
+            if !worst.is_empty() && tail_weight <= 0.0 {
+                break;
+            }
+            // the one on the boundary only counts as far as it fills the tail
+            let tail_part = if tail_weight > 0.0 {
+                weight.min(tail_weight)
+            } else {
+                weight
+            };
+            worst.push_weighted((total, cost), tail_part);
+            tail_weight -= tail_part;
+        }
+        worst
+    }
+
+    pub fn choice_key(&self, cost: &S::Cost) -> (bool, f64) {
+        S::choice_key(self.params, cost)
+    }
+
+    // the best of the children's expected costs, by choice_key
+    fn min_child_expected_cost_and_std_dev(&self) -> Option<(S::Cost, f64)> {
+        self.sub_nodes.as_ref().and_then(|sub_nodes| {
+            sub_nodes
+                .iter()
+                .filter_map(|n| Some((n.expected_cost?, n.expected_cost_std_dev?)))
+                .min_by(|a, b| {
+                    let key_a = self.choice_key(&a.0);
+                    let key_b = self.choice_key(&b.0);
+                    key_a.partial_cmp(&key_b).unwrap()
+                })
+        })
+    }
+
+    pub fn compute_expected_cost_index(&self, total_n: f64, ln_total_n: f64) -> Option<f64> {
+        let variance = if self.params.selection_mode() == ChildSelectionMode::UCBV {
+            Some(self.variance())
+        } else {
+            None
+        };
+
+        compute_selection_index(
+            self.params,
+            total_n,
+            ln_total_n,
+            self.costs.len(),
+            self.expected_cost.unwrap().total(),
+            variance,
+        )
+    }
+
+    pub fn update_expected_cost(&mut self, bound_mode: CostBoundMode) {
+        let min_child = self.min_child_expected_cost_and_std_dev();
+        let (expected_cost, std_dev) = self.expected_cost_from(bound_mode, min_child);
+        self.expected_cost = Some(expected_cost);
+        self.expected_cost_std_dev = Some(std_dev);
+    }
+
+    // the expected cost and its std dev with the given mode,
+    // given the best of the children's where the mode uses it
+    fn expected_cost_from(
+        &self,
+        bound_mode: CostBoundMode,
+        min_child: Option<(S::Cost, f64)>,
+    ) -> (S::Cost, f64) {
+        match bound_mode {
+            CostBoundMode::Classic => (self.mean_cost(), Self::std_dev_of_mean(&self.costs)),
+            CostBoundMode::Expectimax => {
+                min_child.unwrap_or_else(|| (self.mean_cost(), Self::std_dev_of_mean(&self.costs)))
+            }
+            CostBoundMode::LowerBound => {
+                let (mut expected_cost, mut std_dev) = min_child.unwrap_or((S::Cost::ZERO, 0.0));
+                let intermediate_cost = self.intermediate_cost();
+                if intermediate_cost > expected_cost {
+                    expected_cost = intermediate_cost;
+                    std_dev = Self::std_dev_of_mean(&self.intermediate_costs);
+                }
+                (expected_cost, std_dev)
+            }
+            CostBoundMode::Marginal => {
+                let (expected_cost, std_dev) = min_child.unwrap_or((S::Cost::ZERO, 0.0));
+                (
+                    expected_cost + self.marginal_cost(),
+                    std_dev.hypot(Self::std_dev_of_mean(&self.marginal_costs)),
+                )
+            }
+            CostBoundMode::CVaR => {
+                let worst = self.worst_costs(self.params.cvar_alpha());
+                (S::Cost::mean(&worst, |c| *c), Self::std_dev_of_mean(&worst))
+            }
+            CostBoundMode::Same => panic!("Bound mode cannot be 'Same'"),
+        }
+    }
+
+    // The expected cost and its std dev as set_final_choice_expected_values would
+    // leave them, without changing the tree.
+    pub fn final_choice_expected_cost(
+        &self,
+        final_choice_mode: CostBoundMode,
+    ) -> Option<(S::Cost, f64)> {
+        if self.n_trials == 0 {
+            return None;
+        }
+        let bound_mode = self.params.bound_mode();
+        let mode = if final_choice_mode == CostBoundMode::Same {
+            bound_mode
+        } else {
+            final_choice_mode
+        };
+        if mode == bound_mode {
+            return Some((self.expected_cost?, self.expected_cost_std_dev?));
+        }
+
+        let min_child = self.sub_nodes.as_ref().and_then(|sub_nodes| {
+            sub_nodes
+                .iter()
+                .filter_map(|n| n.final_choice_expected_cost(final_choice_mode))
+                .min_by(|a, b| {
+                    let key_a = self.choice_key(&a.0);
+                    let key_b = self.choice_key(&b.0);
+                    key_a.partial_cmp(&key_b).unwrap()
+                })
+        });
+        Some(self.expected_cost_from(mode, min_child))
+    }
+
+    // Re-evaluates the expected costs of the whole tree with the given mode,
+    // where Same keeps the bound mode used during the search.
+    pub fn set_final_choice_expected_values(&mut self, final_choice_mode: CostBoundMode) {
+        if let Some(sub_nodes) = &mut self.sub_nodes {
+            for sub_node in sub_nodes.iter_mut() {
+                sub_node.set_final_choice_expected_values(final_choice_mode);
+            }
+        }
+
+        if self.n_trials == 0 {
+            return;
+        }
+
+        let final_choice_mode = if final_choice_mode == CostBoundMode::Same {
+            self.params.bound_mode()
+        } else {
+            final_choice_mode
+        };
+        self.update_expected_cost(final_choice_mode);
+    }
+
+    // Picks the child to run the next trial through: any unexplored one,
+    // or else the one with the best selection index.
+    pub fn choose_sub_node(&mut self, rng: &mut S::Rng) -> usize {
+        let total_n = self.n_trials as f64;
+        let sub_nodes = self.get_or_expand_sub_nodes();
+
+        let unexplored = sub_nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.n_trials == 0)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if !unexplored.is_empty() {
+            return *unexplored.choose(rng).unwrap();
+        }
+
+        let ln_total_n = total_n.ln();
+        let (_best_index, chosen_i) = sub_nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let index = node
+                    .compute_expected_cost_index(total_n, ln_total_n)
+                    .unwrap();
+                (index, i)
+            })
+            .min_by(|a, b| a.partial_cmp(b).unwrap())
+            .unwrap();
+        chosen_i
+    }
+
+    // The confidence bounds on the expected cost, as (lower, upper).
+    // For KL-UCB, confidence is the divergence allowed over one trial.
+    pub fn expected_cost_bounds(&self, mode: EarlyStopMode, confidence: f64) -> Option<(f64, f64)> {
+        self.bounds_around(self.expected_cost?.total(), mode, confidence)
+    }
+
+    // the confidence bounds on an estimate of cost from this node's trials
+    fn bounds_around(&self, cost: f64, mode: EarlyStopMode, confidence: f64) -> Option<(f64, f64)> {
+        match mode {
+            EarlyStopMode::Never => None,
+            EarlyStopMode::StdDev => {
+                // the trial costs themselves, as the bound mode's std dev can be
+                // swamped by barely visited nodes deeper down
+                let margin = confidence * Self::std_dev_of_mean(&self.costs);
+                Some((cost - margin, cost + margin))
+            }
+            EarlyStopMode::KLUCB => {
+                let max_cost = self.params.klucb_max_cost();
+                let scaled_cost = (cost / max_cost).clamp(0.0, 1.0);
+                let max_divergence = confidence / self.costs.effective_len();
+                let upper = klucb_bernoulli(scaled_cost, max_divergence);
+                let lower = 1.0 - klucb_bernoulli(1.0 - scaled_cost, max_divergence);
+                Some((lower * max_cost, upper * max_cost))
+            }
+        }
+    }
+
+    // Whether the child the final choice would make is confidently better than all
+    // the others, by its upper bound ranking before each of their lower bounds.
+    // The expected costs are the final choice's, ranked by choice_key.
+    pub fn best_child_is_separated(
+        &self,
+        mode: EarlyStopMode,
+        confidence: f64,
+        final_choice_mode: CostBoundMode,
+    ) -> bool {
+        let sub_nodes = match &self.sub_nodes {
+            Some(sub_nodes) => sub_nodes,
+            None => return false,
+        };
+        if mode == EarlyStopMode::Never
+            || sub_nodes
+                .iter()
+                .any(|n| n.costs.len() - n.n_retained_trials < EARLY_STOP_MIN_TRIALS)
+        {
+            return false;
+        }
+
+        // each child's rank and the bounds on its value
+        let ranked = sub_nodes
+            .iter()
+            .map(|n| {
+                let (cost, _) = n.final_choice_expected_cost(final_choice_mode)?;
+                let (worse_class, value) = self.choice_key(&cost);
+                let (lower, upper) = n.bounds_around(value, mode, confidence)?;
+                Some(((worse_class, value), lower, upper))
+            })
+            .collect::<Option<Vec<_>>>();
+        let ranked = match ranked {
+            Some(ranked) => ranked,
+            None => return false,
+        };
+
+        let best_i = (0..ranked.len())
+            .min_by(|&a, &b| ranked[a].0.partial_cmp(&ranked[b].0).unwrap())
+            .unwrap();
+        let ((best_class, _), _, best_upper) = ranked[best_i];
+        ranked
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != best_i)
+            .all(|(_, &((class, _), lower, _))| (best_class, best_upper) < (class, lower))
+    }
+
+    pub fn get_best_child_by_cost(&self) -> Option<&Self> {
+        self.sub_nodes.as_ref()?.iter().min_by(|a, b| {
+            let unexplored = (true, f64::MAX);
+            let key_a = a.expected_cost.map_or(unexplored, |c| self.choice_key(&c));
+            let key_b = b.expected_cost.map_or(unexplored, |c| self.choice_key(&c));
+            key_a.partial_cmp(&key_b).unwrap()
+        })
+    }
+
+    pub fn get_best_child_by_visits(&self) -> Option<&Self> {
+        self.sub_nodes
+            .as_ref()?
+            .iter()
+            .max_by(|a, b| a.costs.len().cmp(&b.costs.len()))
+    }
+
+    // whether the most visited child is also the one with the best expected cost
+    pub fn best_children_agree(&self) -> bool {
+        match (
+            self.get_best_child_by_visits(),
+            self.get_best_child_by_cost(),
+        ) {
+            (Some(best_visits), Some(best_cost)) => std::ptr::eq(best_visits, best_cost),
+            (best_visits, best_cost) => best_visits.is_none() && best_cost.is_none(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use rand::{prelude::StdRng, SeedableRng};
+
+    struct TestParams;
+
+    impl MctsParams for TestParams {
+        fn selection_mode(&self) -> ChildSelectionMode {
+            ChildSelectionMode::UCB
+        }
+
+        fn bound_mode(&self) -> CostBoundMode {
+            CostBoundMode::Marginal
+        }
+
+        fn ucb_const(&self) -> f64 {
+            1.0
+        }
+
+        fn ucbv_const(&self) -> f64 {
+            0.0
+        }
+
+        fn ucbd_const(&self) -> f64 {
+            0.0
+        }
+
+        fn klucb_max_cost(&self) -> f64 {
+            0.0
+        }
+
+        fn cvar_alpha(&self) -> f64 {
+            0.25
+        }
+    }
+
+    // each action costs its value
+    struct TestSimulator {
+        cost: f64,
+        particle_id: usize,
+    }
+
+    impl Simulator for TestSimulator {
+        type Params = TestParams;
+        type Action = f64;
+        type Cost = f64;
+        type Particle = ();
+        type Rng = StdRng;
+
+        fn take_step(&mut self, _params: &TestParams, action: &f64, _rng: &mut StdRng) {
+            self.cost += action;
+        }
+
+        fn cost(&self) -> f64 {
+            self.cost
+        }
+
+        fn particle_id(&self) -> usize {
+            self.particle_id
+        }
+    }
+
+    fn run_trial(node: &mut MctsNode<TestSimulator>, sim: &mut TestSimulator, rng: &mut StdRng) {
+        node.run_step(sim, rng);
+        if node.depth < 2 {
+            let sub_node_i = node.choose_sub_node(rng);
+            run_trial(&mut node.sub_nodes.as_mut().unwrap()[sub_node_i], sim, rng);
+        }
+        node.record_trial(sim.particle_id, sim.cost, ());
+        node.update_expected_cost(CostBoundMode::Marginal);
+    }
+
+    #[test]
+    fn finds_cheapest_actions() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let actions = [3.0, 1.0, 2.0];
+        let mut node = MctsNode::<TestSimulator>::new(&TestParams, &actions, None, 0);
+        for particle_id in 0..200 {
+            let mut sim = TestSimulator {
+                cost: 0.0,
+                particle_id,
+            };
+            run_trial(&mut node, &mut sim, &mut rng);
+        }
+
+        let best = node.get_best_child_by_cost().unwrap();
+        assert_eq!(best.policy, Some(1.0));
+        assert_abs_diff_eq!(best.expected_cost.unwrap(), 2.0);
+        assert!(node.best_children_agree());
+        assert!(node.has_seen_particle(199));
+    }
+
+    #[test]
+    fn cvar_is_the_mean_of_the_worst_costs() {
+        let actions = [1.0];
+        let mut node = MctsNode::<TestSimulator>::new(&TestParams, &actions, None, 0);
+        for (particle_id, &cost) in [4.0, 1.0, 8.0, 2.0, 3.0, 6.0, 5.0, 7.0].iter().enumerate() {
+            node.record_trial(particle_id, cost, ());
+        }
+
+        node.update_expected_cost(CostBoundMode::CVaR);
+        assert_abs_diff_eq!(node.expected_cost.unwrap(), 7.5);
+        node.update_expected_cost(CostBoundMode::Classic);
+        assert_abs_diff_eq!(node.expected_cost.unwrap(), 4.5);
+
+        // the tail ends partway through the third worst
+        let worst = node.worst_costs(0.3);
+        assert_abs_diff_eq!(worst.sum_weights(), 2.4, epsilon = 1e-12);
+        assert_abs_diff_eq!(worst.mean(), (8.0 + 7.0 + 0.4 * 6.0) / 2.4, epsilon = 1e-12);
+        // and never leaves out the worst one
+        assert_abs_diff_eq!(node.worst_costs(0.0).mean(), 8.0);
+    }
+
+    #[test]
+    fn stops_once_best_child_is_separated() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let actions = [3.0, 1.0, 2.0];
+        let mut node = MctsNode::<TestSimulator>::new(&TestParams, &actions, None, 0);
+        assert!(!node.best_child_is_separated(EarlyStopMode::StdDev, 2.0, CostBoundMode::Same));
+
+        // every root child needs enough trials, so visit them in turn
+        let mut particle_id = 0;
+        while !node.best_child_is_separated(EarlyStopMode::StdDev, 2.0, CostBoundMode::Same) {
+            let mut sim = TestSimulator {
+                cost: 0.0,
+                particle_id,
+            };
+            let sub_node_i = particle_id % actions.len();
+            run_trial(
+                &mut node.get_or_expand_sub_nodes_mut()[sub_node_i],
+                &mut sim,
+                &mut rng,
+            );
+            node.record_trial(particle_id, sim.cost, ());
+            node.update_expected_cost(CostBoundMode::Marginal);
+            particle_id += 1;
+            assert!(particle_id < 200);
+        }
+
+        let best = node.get_best_child_by_cost().unwrap();
+        assert_eq!(best.policy, Some(1.0));
+        let (_, best_upper) = best
+            .expected_cost_bounds(EarlyStopMode::StdDev, 2.0)
+            .unwrap();
+        assert!(best_upper < 3.0);
+        assert!(!node.best_child_is_separated(EarlyStopMode::Never, 2.0, CostBoundMode::Same));
+    }
+
+    // a root whose children have the given trial costs, which the root has as well
+    fn node_with_trials<'a, S>(actions: &'a [f64], trial_costs: &[[f64; 4]]) -> MctsNode<'a, S>
+    where
+        S: Simulator<Params = TestParams, Action = f64, Cost = f64, Particle = ()>,
+    {
+        let mut node = MctsNode::<S>::new(&TestParams, actions, None, 0);
+        let mut particle_id = 0;
+        for (sub_node, costs) in node
+            .get_or_expand_sub_nodes_mut()
+            .iter_mut()
+            .zip(trial_costs.iter())
+        {
+            for &cost in costs.iter() {
+                sub_node.record_trial(particle_id, cost, ());
+                particle_id += 1;
+            }
+            // leaves with no marginal costs, which the search's bound mode puts at zero
+            sub_node.update_expected_cost(CostBoundMode::Marginal);
+        }
+        for &cost in trial_costs.iter().flatten() {
+            node.record_trial(particle_id, cost, ());
+            particle_id += 1;
+        }
+        node
+    }
+
+    // as if the cheapest costs broke a constraint, so they rank after all the others
+    struct ConstrainedSimulator;
+
+    impl Simulator for ConstrainedSimulator {
+        type Params = TestParams;
+        type Action = f64;
+        type Cost = f64;
+        type Particle = ();
+        type Rng = StdRng;
+
+        fn take_step(&mut self, _params: &TestParams, _action: &f64, _rng: &mut StdRng) {}
+
+        fn cost(&self) -> f64 {
+            0.0
+        }
+
+        fn particle_id(&self) -> usize {
+            0
+        }
+
+        fn choice_key(_params: &TestParams, cost: &f64) -> (bool, f64) {
+            (*cost < 1.5, *cost)
+        }
+    }
+
+    #[test]
+    fn separation_follows_the_final_choice() {
+        let actions = [1.0, 2.0, 3.0];
+        let trial_costs = [[1.0; 4], [1.5, 2.5, 1.5, 2.5], [2.1; 4]];
+        let mut node = node_with_trials::<TestSimulator>(&actions, &trial_costs);
+        let separated = |node: &MctsNode<TestSimulator>, final_choice_mode| {
+            node.best_child_is_separated(EarlyStopMode::StdDev, 2.0, final_choice_mode)
+        };
+        assert!(!separated(&node, CostBoundMode::Same));
+        assert!(separated(&node, CostBoundMode::Classic));
+
+        // with the cheapest child ruled out, the other two are too close to call
+        let constrained = node_with_trials::<ConstrainedSimulator>(&actions, &trial_costs);
+        assert!(!constrained.best_child_is_separated(
+            EarlyStopMode::StdDev,
+            2.0,
+            CostBoundMode::Classic
+        ));
+
+        // and trials from an earlier search don't count towards the minimum
+        node.get_or_expand_sub_nodes_mut()[0].n_retained_trials = 1;
+        assert!(!separated(&node, CostBoundMode::Classic));
+    }
+
+    #[test]
+    fn backups_rank_children_by_choice_key() {
+        let actions = [1.0, 2.0];
+        let trial_costs = [[1.0; 4], [2.0; 4]];
+        for bound_mode in [CostBoundMode::Expectimax, CostBoundMode::Marginal] {
+            let mut node = node_with_trials::<TestSimulator>(&actions, &trial_costs);
+            let mut constrained = node_with_trials::<ConstrainedSimulator>(&actions, &trial_costs);
+            for sub_node in node.get_or_expand_sub_nodes_mut() {
+                sub_node.update_expected_cost(CostBoundMode::Classic);
+            }
+            for sub_node in constrained.get_or_expand_sub_nodes_mut() {
+                sub_node.update_expected_cost(CostBoundMode::Classic);
+            }
+            node.update_expected_cost(bound_mode);
+            constrained.update_expected_cost(bound_mode);
+
+            // the root's own marginal cost is zero, so it backs up its best child's
+            assert_abs_diff_eq!(node.expected_cost.unwrap(), 1.0);
+            assert_abs_diff_eq!(constrained.expected_cost.unwrap(), 2.0);
+            assert_eq!(
+                constrained.get_best_child_by_cost().unwrap().policy,
+                Some(2.0)
+            );
+            let (final_cost, _) = constrained
+                .final_choice_expected_cost(CostBoundMode::Expectimax)
+                .unwrap();
+            assert_abs_diff_eq!(final_cost, 2.0);
+        }
+    }
+}
@@ -1,14 +1,18 @@
 pub mod cost_set;
+pub mod engine;
 pub mod klucb;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum CostBoundMode {
     Classic,
     Expectimax,
     LowerBound,
     Marginal,
+    // the mean of the worst cvar_alpha fraction of the costs
+    #[serde(rename = "cvar")]
+    CVaR,
     Same,
 }
 
@@ -19,6 +23,7 @@ impl std::fmt::Display for CostBoundMode {
             Self::Expectimax => write!(f, "expectimax"),
             Self::LowerBound => write!(f, "lower_bound"),
             Self::Marginal => write!(f, "marginal"),
+            Self::CVaR => write!(f, "cvar"),
             Self::Same => write!(f, "same"),
         }
     }
@@ -33,13 +38,14 @@ impl std::str::FromStr for CostBoundMode {
             "expectimax" => Ok(Self::Expectimax),
             "lower_bound" => Ok(Self::LowerBound),
             "marginal" => Ok(Self::Marginal),
+            "cvar" => Ok(Self::CVaR),
             "same" => Ok(Self::Same),
             _ => Err(format!("Invalid CostBoundMode '{}'", s)),
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum ChildSelectionMode {
     UCB,
@@ -79,3 +85,35 @@ impl std::str::FromStr for ChildSelectionMode {
         }
     }
 }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EarlyStopMode {
+    Never,
+    StdDev,
+    #[serde(rename = "klucb")]
+    KLUCB,
+}
+
+impl std::fmt::Display for EarlyStopMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Never => write!(f, "never"),
+            Self::StdDev => write!(f, "std_dev"),
+            Self::KLUCB => write!(f, "klucb"),
+        }
+    }
+}
+
+impl std::str::FromStr for EarlyStopMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "std_dev" => Ok(Self::StdDev),
+            "klucb" => Ok(Self::KLUCB),
+            _ => Err(format!("Invalid EarlyStopMode '{}'", s)),
+        }
+    }
+}
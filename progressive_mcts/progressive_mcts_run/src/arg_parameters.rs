@@ -9,15 +9,16 @@ use std::{
 };
 
 use crate::parameters_sql::{
-    create_table_sql, insert_sql, make_insert_specifiers, parse_parameters, specifier_params,
-    specifiers_hash,
+    add_missing_columns_sql, create_table_sql, insert_sql, make_insert_specifiers,
+    parse_parameters, specifier_params, specifiers_hash,
 };
 #[allow(unused)]
 use fstrings::{format_args_f, format_f, println_f};
 use itertools::Itertools;
+use progressive_mcts::engine::MctsParams;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-use crate::{run_with_parameters, ChildSelectionMode, CostBoundMode};
+use crate::{run_with_parameters, ChildSelectionMode, CostBoundMode, EarlyStopMode};
 
 #[derive(Clone, Debug)]
 pub struct Parameters {
@@ -27,6 +28,7 @@ pub struct Parameters {
     pub ucbv_const: f64,
     pub ucbd_const: f64,
     pub klucb_max_cost: f64,
+    pub cvar_alpha: f64,
     pub rng_seed: u64,
     pub samples_n: usize,
 
@@ -35,6 +37,9 @@ pub struct Parameters {
     pub selection_mode: ChildSelectionMode,
     pub repeat_const: f64,
     pub most_visited_best_cost_consistency: bool,
+    // stop before samples_n once the best root child is confidently the best
+    pub early_stop_mode: EarlyStopMode,
+    pub early_stop_const: f64,
 
     pub thread_limit: usize,
     pub specifiers_hash: Option<i64>,
@@ -44,8 +49,38 @@ pub struct Parameters {
     pub is_single_run: bool,
 }
 
+impl MctsParams for Parameters {
+    fn selection_mode(&self) -> ChildSelectionMode {
+        self.selection_mode
+    }
+
+    fn bound_mode(&self) -> CostBoundMode {
+        self.bound_mode
+    }
+
+    fn ucb_const(&self) -> f64 {
+        self.ucb_const
+    }
+
+    fn ucbv_const(&self) -> f64 {
+        self.ucbv_const
+    }
+
+    fn ucbd_const(&self) -> f64 {
+        self.ucbd_const
+    }
+
+    fn klucb_max_cost(&self) -> f64 {
+        self.klucb_max_cost
+    }
+
+    fn cvar_alpha(&self) -> f64 {
+        self.cvar_alpha
+    }
+}
+
 impl Parameters {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             search_depth: 4,
             n_actions: 5,
@@ -53,6 +88,7 @@ impl Parameters {
             ucbv_const: 0.001,
             ucbd_const: 0.1,
             klucb_max_cost: 4700.0,
+            cvar_alpha: 0.1,
             rng_seed: 0,
             samples_n: 64,
             bound_mode: CostBoundMode::Marginal,
@@ -60,6 +96,8 @@ impl Parameters {
             selection_mode: ChildSelectionMode::KLUCB,
             repeat_const: -1.0,
             most_visited_best_cost_consistency: true,
+            early_stop_mode: EarlyStopMode::Never,
+            early_stop_const: 2.0,
 
             thread_limit: 0,
             specifiers_hash: None,
@@ -86,8 +124,9 @@ fn create_scenarios(
         || name.starts_with("expectimax.") && base_p.bound_mode != CostBoundMode::Expectimax
         || name.starts_with("lower_bound.") && base_p.bound_mode != CostBoundMode::LowerBound
         || name.starts_with("marginal.") && base_p.bound_mode != CostBoundMode::Marginal
+        || name.starts_with("cvar.") && base_p.bound_mode != CostBoundMode::CVaR
     {
-        return create_scenarios(&base_p, &name_value_pairs[1..]);
+        return create_scenarios(base_p, &name_value_pairs[1..]);
     }
 
     if name.starts_with("ucb.") && base_p.selection_mode != ChildSelectionMode::UCB
@@ -96,7 +135,7 @@ fn create_scenarios(
         || name.starts_with("klucb.") && base_p.selection_mode != ChildSelectionMode::KLUCB
         || name.starts_with("klucb+.") && base_p.selection_mode != ChildSelectionMode::KLUCBP
     {
-        return create_scenarios(&base_p, &name_value_pairs[1..]);
+        return create_scenarios(base_p, &name_value_pairs[1..]);
     }
 
     for value in values.iter() {
@@ -190,7 +229,7 @@ pub fn run_parallel_scenarios() {
     let thread_limit = scenarios[0].thread_limit;
     if thread_limit > 0 {
         rayon::ThreadPoolBuilder::new()
-            .num_threads(thread_limit as usize)
+            .num_threads(thread_limit)
             .build_global()
             .unwrap();
     }
@@ -202,6 +241,17 @@ pub fn run_parallel_scenarios() {
     // create if doesn't exist (lazy way, ignoring an error)
     let _ = conn.execute(&create_table_sql(), []);
 
+    let existing_columns = conn
+        .prepare("PRAGMA table_info(results);")
+        .expect("prepare table_info")
+        .query_map([], |r| r.get::<_, String>(1))
+        .unwrap()
+        .filter_map(|a| a.ok())
+        .collect_vec();
+    for sql in add_missing_columns_sql(&existing_columns) {
+        conn.execute(&sql, []).expect("add column");
+    }
+
     let mut specifiers_hash_statement = conn
         .prepare("SELECT specifiers_hash FROM results;")
         .expect("prepare select specifiers_hash");
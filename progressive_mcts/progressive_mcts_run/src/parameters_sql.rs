@@ -1,6 +1,6 @@
 use std::{collections::hash_map::DefaultHasher, hash::Hasher};
 
-use crate::{arg_parameters::Parameters, RunResults};
+use crate::{arg_parameters::Parameters, CostBoundMode, EarlyStopMode, RunResults};
 use itertools::Itertools;
 use paste::paste;
 use rusqlite::ToSql;
@@ -35,20 +35,42 @@ macro_rules! define_params {
     (@hasher $hasher:expr, INTEGER, $params:ident, $($param:ident),*) => {
         // $($hasher.write_u64($param as u64);)*
         use std::hash::Hash;
-        $($params.$param.hash($hasher);)*
+        $(if !LATER_PARAMS.contains(&stringify!($param)) {
+            $params.$param.hash($hasher);
+        })*
     };
     (@hasher $hasher:expr, TEXT, $params:ident, $($param:ident),*) => {
         // $($hasher.write_u64($param as u64);)*
         use std::hash::Hash;
-        $($params.$param.hash($hasher);)*
+        $(if !LATER_PARAMS.contains(&stringify!($param)) {
+            $params.$param.hash($hasher);
+        })*
     };
     (@hasher $hasher:expr, REAL, $params:ident, $($param:ident),*) => {
-        $($hasher.write_u64($params.$param.to_bits());)*
+        $(if !LATER_PARAMS.contains(&stringify!($param)) {
+            $hasher.write_u64($params.$param.to_bits());
+        })*
     };
 }
 
+// Parameters added after results.db was first written. They are hashed by
+// hash_later_specifiers only when they change anything, so that the results
+// from before them keep their hashes.
+const LATER_PARAMS: &[&str] = &["early_stop_mode", "early_stop_const", "cvar_alpha"];
+
+fn hash_later_specifiers(params: &Parameters, hasher: &mut DefaultHasher) {
+    use std::hash::Hash;
+    if params.early_stop_mode != EarlyStopMode::Never {
+        params.early_stop_mode.hash(hasher);
+        hasher.write_u64(params.early_stop_const.to_bits());
+    }
+    if params.bound_mode == CostBoundMode::CVaR || params.final_choice_mode == CostBoundMode::CVaR {
+        hasher.write_u64(params.cvar_alpha.to_bits());
+    }
+}
+
 pub fn parse_parameters(params: &mut Parameters, name: &str, val: &str) {
-    let name = name.split('.').last().unwrap();
+    let name = name.rsplit('.').next().unwrap();
     if parse_integer_params(params, name, val)
         || parse_text_params(params, name, val)
         || parse_real_params(params, name, val)
@@ -72,7 +94,13 @@ define_params!(
     most_visited_best_cost_consistency
 );
 
-define_params!(TEXT, bound_mode, final_choice_mode, selection_mode);
+define_params!(
+    TEXT,
+    bound_mode,
+    final_choice_mode,
+    selection_mode,
+    early_stop_mode
+);
 
 define_params!(
     REAL,
@@ -80,7 +108,9 @@ define_params!(
     ucbv_const,
     ucbd_const,
     klucb_max_cost,
-    repeat_const
+    repeat_const,
+    early_stop_const,
+    cvar_alpha
 );
 
 macro_rules! define_result_values {
@@ -102,9 +132,28 @@ define_result_values!(
     true_best_cost,
     regret,
     cost_estimation_error,
-    sum_repeated
+    sum_repeated,
+    trials_saved
 );
 
+// every column of the results table, with its type
+fn columns() -> impl Iterator<Item = (&'static str, &'static str)> {
+    let typed =
+        |params: &'static [&'static str], sql_type| params.iter().map(move |p| (*p, sql_type));
+    typed(INTEGER_PARAMS, "INTEGER")
+        .chain(typed(TEXT_PARAMS, "TEXT"))
+        .chain(typed(REAL_PARAMS, "REAL"))
+        .chain(typed(RESULT_VALUES, "REAL"))
+}
+
+// brings a results table from before some of the columns up to date
+pub fn add_missing_columns_sql(existing_columns: &[String]) -> Vec<String> {
+    columns()
+        .filter(|(column, _)| !existing_columns.iter().any(|c| c == column))
+        .map(|(column, sql_type)| format!("ALTER TABLE results ADD COLUMN {} {}", column, sql_type))
+        .collect()
+}
+
 pub fn create_table_sql() -> String {
     format!(
         "CREATE TABLE results (id INTEGER PRIMARY KEY, specifiers_hash INTEGER, {}, {}, {}, {})",
@@ -163,7 +212,7 @@ pub fn insert_sql() -> String {
         added_any = true;
     }
 
-    sql.push_str(")");
+    sql.push(')');
     sql
 }
 
@@ -180,6 +229,7 @@ pub fn specifiers_hash(params: &Parameters) -> i64 {
     hash_integer_specifiers(params, &mut hasher);
     hash_text_specifiers(params, &mut hasher);
     hash_real_specifiers(params, &mut hasher);
+    hash_later_specifiers(params, &mut hasher);
     hasher.finish() as i64
 }
 
@@ -198,6 +248,59 @@ pub fn make_insert_specifiers(
 
 pub fn specifier_params<'a>(spec: &'a [(&'static str, String)]) -> Vec<(&'a str, &'a dyn ToSql)> {
     spec.iter()
-        .map(|(k, v)| (k.as_ref(), v as &dyn ToSql))
+        .map(|(k, v)| (*k, v as &dyn ToSql))
         .collect_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_params_are_hashed_only_when_used() {
+        let params = Parameters::new();
+        let mut unused = params.clone();
+        unused.early_stop_const = 3.0;
+        assert_eq!(specifiers_hash(&params), specifiers_hash(&unused));
+
+        let mut early_stop = params.clone();
+        early_stop.early_stop_mode = EarlyStopMode::StdDev;
+        assert_ne!(specifiers_hash(&params), specifiers_hash(&early_stop));
+        let mut other_const = early_stop.clone();
+        other_const.early_stop_const = 3.0;
+        assert_ne!(specifiers_hash(&early_stop), specifiers_hash(&other_const));
+
+        let mut unused_alpha = params.clone();
+        unused_alpha.cvar_alpha = 0.5;
+        assert_eq!(specifiers_hash(&params), specifiers_hash(&unused_alpha));
+        for (bound_mode, final_choice_mode) in [
+            (CostBoundMode::CVaR, CostBoundMode::Same),
+            (CostBoundMode::Marginal, CostBoundMode::CVaR),
+        ] {
+            let mut cvar = params.clone();
+            cvar.bound_mode = bound_mode;
+            cvar.final_choice_mode = final_choice_mode;
+            let mut other_alpha = cvar.clone();
+            other_alpha.cvar_alpha = 0.5;
+            assert_ne!(specifiers_hash(&cvar), specifiers_hash(&other_alpha));
+        }
+    }
+
+    #[test]
+    fn adds_only_the_missing_columns() {
+        let existing_columns = columns()
+            .map(|(column, _)| column.to_string())
+            .filter(|column| {
+                !["early_stop_mode", "cvar_alpha", "trials_saved"].contains(&column.as_str())
+            })
+            .collect_vec();
+        assert_eq!(
+            add_missing_columns_sql(&existing_columns),
+            vec![
+                "ALTER TABLE results ADD COLUMN early_stop_mode TEXT",
+                "ALTER TABLE results ADD COLUMN cvar_alpha REAL",
+                "ALTER TABLE results ADD COLUMN trials_saved REAL"
+            ]
+        );
+    }
+}
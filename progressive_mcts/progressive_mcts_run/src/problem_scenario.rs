@@ -1,3 +1,5 @@
+use crate::arg_parameters::Parameters;
+use progressive_mcts::engine;
 use rand::{prelude::StdRng, Rng};
 use rand_distr::{Distribution, Normal, StandardNormal};
 
@@ -59,14 +61,14 @@ impl CostDistribution {
     }
 
     pub fn sample(&self, rng: &mut StdRng) -> f64 {
-        self.from_correlated(
+        self.sample_correlated(
             rng.gen_range(0.0..=1.0),
             StandardNormal.sample(rng),
             StandardNormal.sample(rng),
         )
     }
 
-    pub fn from_correlated(&self, weight_choice: f64, gaussian_z1: f64, gaussian_z2: f64) -> f64 {
+    pub fn sample_correlated(&self, weight_choice: f64, gaussian_z1: f64, gaussian_z2: f64) -> f64 {
         if weight_choice <= self.weight1 {
             self.normal1
                 .from_zscore(gaussian_z1)
@@ -85,7 +87,6 @@ pub struct ProblemScenario {
     pub distribution: Option<CostDistribution>,
     pub children: Vec<ProblemScenario>,
     pub depth: u32,
-    pub max_depth: u32,
 }
 
 impl ProblemScenario {
@@ -104,7 +105,6 @@ impl ProblemScenario {
                 Vec::new()
             },
             depth,
-            max_depth,
         }
     }
 
@@ -147,7 +147,7 @@ impl<'a> Simulator<'a> {
         // .expect("only take search_depth steps");
         let dist = child.distribution.as_ref().expect("not root-level node");
         self.cost += dist.sample(rng)
-            + dist.from_correlated(
+            + dist.sample_correlated(
                 self.particle.weight_choice,
                 self.particle.gaussian_z1,
                 self.particle.gaussian_z2,
@@ -158,6 +158,27 @@ impl<'a> Simulator<'a> {
     }
 }
 
+impl<'a> engine::Simulator for Simulator<'a> {
+    type Params = Parameters;
+    type Action = u32;
+    type Cost = f64;
+    type Particle = Simulator<'a>;
+    type Rng = StdRng;
+
+    fn take_step(&mut self, _params: &Parameters, policy: &u32, rng: &mut StdRng) {
+        // the inherent method, which doesn't need the parameters
+        Simulator::take_step(self, *policy, rng);
+    }
+
+    fn cost(&self) -> f64 {
+        self.cost
+    }
+
+    fn particle_id(&self) -> usize {
+        self.particle.id
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
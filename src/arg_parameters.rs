@@ -2,9 +2,10 @@ use std::{
     collections::BTreeMap,
     fs::{File, OpenOptions},
     io::{BufRead, BufReader, Write},
+    panic::AssertUnwindSafe,
     sync::{
         atomic::{self, AtomicUsize},
-        Mutex,
+        Arc, Mutex,
     },
     time::Instant,
 };
@@ -12,12 +13,20 @@ use std::{
 use atomic::Ordering;
 use itertools::Itertools;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use serde::Deserialize;
-
-use crate::run_with_parameters;
-use progressive_mcts::{ChildSelectionMode, CostBoundMode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cost_term::{CostTermRegistry, STANDARD_COST_TERMS},
+    mcts::PolicyChainMode,
+    planner::PlannerRegistry,
+    recording::replay,
+    reference_path::CenterlineSegment,
+    scenario::{ScenarioKind, ScenarioParameters},
+    simulation::run_with_parameters,
+};
+use progressive_mcts::{ChildSelectionMode, CostBoundMode, EarlyStopMode};
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct EudmParameters {
     pub dt: f64,
     pub layer_t: f64,
@@ -26,7 +35,7 @@ pub struct EudmParameters {
     pub allow_different_root_policy: bool,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct MctsParameters {
     pub dt: f64,
     pub layer_t: f64,
@@ -36,21 +45,51 @@ pub struct MctsParameters {
     pub prefer_same_policy: bool,
     pub ucb_const: f64,
     pub bound_mode: CostBoundMode,
+    // how the expected costs are estimated when choosing the policy after the search,
+    // where "same" keeps the bound_mode
+    pub final_choice_mode: CostBoundMode,
+    // the fraction of the worst trial costs averaged by the cvar bound mode
+    pub cvar_alpha: f64,
     pub selection_mode: ChildSelectionMode,
     pub klucb_max_cost: f64,
+    pub ucbv_const: f64,
+    pub ucbd_const: f64,
     pub repeat_const: f64,
     pub most_visited_best_cost_consistency: bool,
+    // stops a search before samples_n once the best root child's confidence bound,
+    // scaled by early_stop_const, separates it from all the others
+    pub early_stop_mode: EarlyStopMode,
+    pub early_stop_const: f64,
+    // POMCP-style: forward simulations update their own belief after each step,
+    // and resample the obstacle policies from it for the deeper steps
+    pub belief_update: bool,
+    // fraction of replan_dt to spend running trials, instead of a fixed samples_n
+    pub time_budget_frac: Option<f64>,
+    // independent trees searched in parallel, which then vote on the policy
+    pub parallel_trees: usize,
+    // keeps the subtree under the chosen policy for the next replan, with its
+    // statistics thinned out by half every this many seconds
+    pub reuse_half_life: Option<f64>,
+    pub policy_chain: PolicyChainMode,
+    // progressive widening, allowing up to widening_k * n^widening_alpha children
+    // after n visits, with the extras sampled from continuous lane changes
+    pub widening_k: Option<f64>,
+    pub widening_alpha: f64,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct MpdmParameters {
     pub dt: f64,
     pub forward_t: f64,
     pub samples_n: usize,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct CostParameters {
+    // which terms make up the cost function, by their names in the cost term registry,
+    // in the order they are added
+    pub terms: Vec<String>,
+
     pub efficiency_speed_cost: f64,
     pub efficiency_weight: f64,
 
@@ -60,13 +99,34 @@ pub struct CostParameters {
     pub logistic_map_low: f64,
     pub logistic_map_high: f64,
 
+    // time-to-collision and time-headway terms of the safety cost, with the cars ahead of
+    // and behind the ego, penalized as those times fall below their thresholds in seconds
+    pub ttc_weight: Option<f64>,
+    pub ttc_thresh: f64,
+    pub headway_weight: Option<f64>,
+    pub headway_thresh: f64,
+
     pub accel_weight: f64,
     pub steer_weight: f64,
 
+    // for staying in a lane that is about to end
+    pub deadline_weight: f64,
+
+    // for the terms outside of the standard ones
+    pub jerk_weight: f64,
+    pub lane_preference_weight: f64,
+    pub preferred_lane: i32,
+    pub rule_compliance_weight: f64,
+    pub speed_limit: f64,
+
     pub discount_factor: f64,
+
+    // only choose among policies with at most this expected safety cost, if any,
+    // ranking them by their other costs
+    pub safety_constraint: Option<f64>,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct CfbParameters {
     pub key_vehicle_base_dist: f64,
     pub key_vehicle_dist_time: f64,
@@ -76,7 +136,7 @@ pub struct CfbParameters {
     pub horizon_t: f64,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct BeliefParameters {
     pub different_lane_prob: f64,
     pub different_longitudinal_prob: f64,
@@ -88,17 +148,35 @@ pub struct BeliefParameters {
     pub skips_waiting_prob: f64,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RoadParameters {
+    // empty for a straight road
+    pub centerline: Vec<CenterlineSegment>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExportParameters {
+    // svg frames are written here, if given
+    pub dir: Option<String>,
+    // timesteps between exported frames
+    pub interval: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixels_per_meter: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct SpawnParameters {
     pub remove_ahead_beyond: f64,
     pub remove_behind_beyond: f64,
     pub place_ahead_beyond: f64,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Parameters {
     pub max_steps: u32,
     pub n_cars: usize,
+    pub n_lanes: i32,
     pub method: String,
     pub use_cfb: bool,
 
@@ -113,6 +191,12 @@ pub struct Parameters {
     pub run_fast: bool,
     pub load_and_record_results: bool,
     pub is_single_run: bool,
+    pub traffic_file: Option<String>,
+    pub snapshot_at_step: Option<u32>,
+    pub snapshot_file: String,
+    pub resume_from: Option<String>,
+    pub record_file: Option<String>,
+    pub replay_file: Option<String>,
     pub graphics_speedup: f64,
     pub graphics_for_paper: bool,
     pub debug_car_i: Option<usize>,
@@ -132,8 +216,14 @@ pub struct Parameters {
     pub only_crashes_with_ego: bool,
     pub obstacles_only_for_ego: bool,
     pub true_belief_sample_only: bool,
+    // samples obstacle policies from the belief mixed with this fraction of a uniform
+    // distribution, weighting the samples to correct for it
+    pub proposal_mix: Option<f64>,
 
+    pub road: RoadParameters,
+    pub scenario: ScenarioParameters,
     pub spawn: SpawnParameters,
+    pub export: ExportParameters,
     pub belief: BeliefParameters,
     pub cost: CostParameters,
     pub cfb: CfbParameters,
@@ -142,10 +232,14 @@ pub struct Parameters {
     pub mcts: MctsParameters,
 
     pub scenario_name: Option<String>,
+
+    // the cost terms that cost.terms can name, which are code rather than parameters
+    #[serde(skip)]
+    pub cost_term_registry: Arc<CostTermRegistry>,
 }
 
 impl Parameters {
-    fn new() -> Result<Self, config::ConfigError> {
+    pub fn new() -> Result<Self, config::ConfigError> {
         let mut s = config::Config::new();
         s.merge(config::File::with_name("parameters"))?;
         s.try_into()
@@ -155,6 +249,7 @@ impl Parameters {
 fn create_scenarios(
     base_params: &Parameters,
     name_value_pairs: &[(String, Vec<String>)],
+    planners: &PlannerRegistry,
 ) -> Vec<Parameters> {
     if name_value_pairs.is_empty() {
         return vec![base_params.clone()];
@@ -163,12 +258,14 @@ fn create_scenarios(
     let mut scenarios = Vec::new();
     let (name, values) = &name_value_pairs[0];
 
-    if name.starts_with("tree.") && base_params.method != "tree"
-        || name.starts_with("mpdm.") && base_params.method != "mpdm"
-        || name.starts_with("eudm.") && base_params.method != "eudm"
-        || name.starts_with("mcts.") && base_params.method != "mcts"
-    {
-        return create_scenarios(base_params, &name_value_pairs[1..]);
+    // parameters for the other planners don't apply to this scenario
+    let planner_param = name
+        .split_once('.')
+        .filter(|(prefix, _)| planners.find(prefix).is_some());
+    if let Some((prefix, _)) = planner_param {
+        if *prefix != base_params.method {
+            return create_scenarios(base_params, &name_value_pairs[1..], planners);
+        }
     }
 
     for value in values.iter() {
@@ -209,43 +306,70 @@ fn create_scenarios(
             match name.as_str() {
                 "method" => params.method = val.parse().unwrap(),
                 "use_cfb" => params.use_cfb = val.parse().unwrap(),
+                "proposal_mix" => params.proposal_mix = Some(val.parse().unwrap()),
                 "max_steps" => params.max_steps = val.parse().unwrap(),
                 "n_cars" => params.n_cars = val.parse().unwrap(),
+                "n_lanes" => params.n_lanes = val.parse().unwrap(),
+                "traffic_file" => params.traffic_file = Some(val.parse().unwrap()),
+                "snapshot_at_step" => params.snapshot_at_step = Some(val.parse().unwrap()),
+                "snapshot_file" => params.snapshot_file = val.parse().unwrap(),
+                "resume_from" => params.resume_from = Some(val.parse().unwrap()),
+                "record_file" => params.record_file = Some(val.parse().unwrap()),
+                "replay_file" => params.replay_file = Some(val.parse().unwrap()),
+                "export.dir" => params.export.dir = Some(val.parse().unwrap()),
+                "export.interval" => params.export.interval = val.parse().unwrap(),
                 "discount_factor" => params.cost.discount_factor = val.parse().unwrap(),
                 "replan_dt" => params.replan_dt = val.parse().unwrap(),
                 "rng_seed" => params.rng_seed = val.parse().unwrap(),
                 "run_fast" => params.run_fast = val.parse().unwrap(),
                 "load_and_record_results" => params.load_and_record_results = val.parse().unwrap(),
                 "thread_limit" => params.thread_limit = val.parse().unwrap(),
-                "mpdm.samples_n" => params.mpdm.samples_n = val.parse().unwrap(),
-                "eudm.samples_n" => params.eudm.samples_n = val.parse().unwrap(),
-                "mcts.samples_n" => params.mcts.samples_n = val.parse().unwrap(),
-                "mpdm.forward_t" => params.mpdm.forward_t = val.parse().unwrap(),
-                "eudm.search_depth" => params.eudm.search_depth = val.parse().unwrap(),
-                "mcts.search_depth" => params.mcts.search_depth = val.parse().unwrap(),
-                "eudm.layer_t" => params.eudm.layer_t = val.parse().unwrap(),
-                "mcts.layer_t" => params.mcts.layer_t = val.parse().unwrap(),
-                "mcts.total_forward_t" => params.mcts.total_forward_t = Some(val.parse().unwrap()),
                 "safety" => params.cost.safety_weight = val.parse().unwrap(),
                 "safety_margin_low" => params.cost.safety_margin_low = val.parse().unwrap(),
                 "safety_margin_high" => params.cost.safety_margin_high = val.parse().unwrap(),
+                "ttc" => params.cost.ttc_weight = Some(val.parse().unwrap()),
+                "ttc_thresh" => params.cost.ttc_thresh = val.parse().unwrap(),
+                "headway" => params.cost.headway_weight = Some(val.parse().unwrap()),
+                "headway_thresh" => params.cost.headway_thresh = val.parse().unwrap(),
                 "accel" => params.cost.accel_weight = val.parse().unwrap(),
                 "steer" => params.cost.steer_weight = val.parse().unwrap(),
-                "mcts.bound_mode" => params.mcts.bound_mode = val.parse().unwrap(),
-                "mcts.selection_mode" => params.mcts.selection_mode = val.parse().unwrap(),
-                "mcts.ucb_const" => params.mcts.ucb_const = val.parse().unwrap(),
-                "mcts.klucb_max_cost" => params.mcts.klucb_max_cost = val.parse().unwrap(),
-                "mcts.repeat_const" => params.mcts.repeat_const = val.parse().unwrap(),
-                "mcts.most_visited_best_cost_consistency" => {
-                    params.mcts.most_visited_best_cost_consistency = val.parse().unwrap()
+                "deadline" => params.cost.deadline_weight = val.parse().unwrap(),
+                "cost_terms" => {
+                    let registry = &params.cost_term_registry;
+                    params.cost.terms = val
+                        .split('+')
+                        .map(|t| registry.get(t).0.name().to_owned())
+                        .collect()
                 }
-                "eudm.allow_different_root_policy" => {
-                    params.eudm.allow_different_root_policy = val.parse().unwrap()
+                "jerk" => params.cost.jerk_weight = val.parse().unwrap(),
+                "lane_preference" => params.cost.lane_preference_weight = val.parse().unwrap(),
+                "preferred_lane" => params.cost.preferred_lane = val.parse().unwrap(),
+                "rule_compliance" => params.cost.rule_compliance_weight = val.parse().unwrap(),
+                "speed_limit" => params.cost.speed_limit = val.parse().unwrap(),
+                "safety_constraint" => params.cost.safety_constraint = Some(val.parse().unwrap()),
+                "scenario.kind" => params.scenario.kind = val.parse().unwrap(),
+                "scenario.ending_lane" => params.scenario.ending_lane = val.parse().unwrap(),
+                "scenario.merge_start_s" => params.scenario.merge_start_s = val.parse().unwrap(),
+                "scenario.lane_end_s" => params.scenario.lane_end_s = val.parse().unwrap(),
+                _ => {
+                    let planner = planners.get(&params.method);
+                    let is_planner_param = match planner_param {
+                        Some((_, planner_name)) => {
+                            planner.set_param(&mut params, planner_name, &val)
+                        }
+                        None => false,
+                    };
+                    if !is_planner_param {
+                        panic!("{} is not a valid parameter!", name);
+                    }
                 }
-                _ => panic!("{} is not a valid parameter!", name),
             }
             if name_value_pairs.len() > 1 {
-                scenarios.append(&mut create_scenarios(&params, &name_value_pairs[1..]));
+                scenarios.append(&mut create_scenarios(
+                    &params,
+                    &name_value_pairs[1..],
+                    planners,
+                ));
             } else {
                 scenarios.push(params);
             }
@@ -260,79 +384,82 @@ fn create_scenarios(
     }
 
     for s in scenarios.iter_mut() {
-        let samples_n = match s.method.as_str() {
-            "fixed" => "".to_string(),
-            "mpdm" => format_f!(",samples_n={s.mpdm.samples_n}"),
-            "eudm" => format_f!(",samples_n={s.eudm.samples_n}"),
-            "mcts" => format_f!(",samples_n={s.mcts.samples_n}"),
-            _ => panic!("Unknown method {}", s.method),
-        };
-
-        let search_depth = match s.method.as_str() {
-            "fixed" => "".to_string(),
-            "mpdm" => "".to_string(),
-            "eudm" => format_f!(",search_depth={s.eudm.search_depth}"),
-            "mcts" => format_f!(",search_depth={s.mcts.search_depth}"),
-            _ => panic!("Unknown method {}", s.method),
+        let planner = planners.get(&s.method).scenario_name(s);
+
+        let road = if s.road.centerline.is_empty() {
+            "".to_string()
+        } else {
+            let segments = s
+                .road
+                .centerline
+                .iter()
+                .map(|seg| format_f!("{seg.length}/{seg.start_curvature}/{seg.end_curvature}"))
+                .join(";");
+            format_f!(",road={segments}")
         };
 
-        let forward_t = match s.method.as_str() {
-            "fixed" => "".to_string(),
-            "mpdm" => format_f!(",forward_t={s.mpdm.forward_t}"),
-            "eudm" => format_f!(",layer_t={s.eudm.layer_t}"),
-            "mcts" => {
-                if let Some(total_forward_t) = s.mcts.total_forward_t {
-                    format_f!(",total_forward_t={total_forward_t}")
-                } else {
-                    format_f!(",layer_t={s.mcts.layer_t}")
-                }
-            }
-            _ => panic!("Unknown method {}", s.method),
+        let traffic_file = match &s.traffic_file {
+            Some(traffic_file) => format_f!(",traffic_file={traffic_file}"),
+            None => "".to_string(),
         };
 
-        let selection_mode = match s.method.as_str() {
-            "mcts" => format_f!(",selection_mode={s.mcts.selection_mode}"),
-            _ => "".to_string(),
+        // a resumed run only covers part of the scenario
+        let resume_from = match &s.resume_from {
+            Some(resume_from) => format_f!(",resume_from={resume_from}"),
+            None => "".to_string(),
         };
 
-        let bound_mode = match s.method.as_str() {
-            "mcts" => format_f!(",bound_mode={s.mcts.bound_mode}"),
-            _ => "".to_string(),
+        let proposal_mix = match s.proposal_mix {
+            Some(mix) => format_f!(",proposal_mix={mix}"),
+            None => "".to_string(),
         };
 
-        let ucb_const = match s.method.as_str() {
-            "mcts" => {
-                format_f!(",ucb_const={s.mcts.ucb_const}")
-            }
-            _ => "".to_string(),
+        let ttc = match s.cost.ttc_weight {
+            Some(ttc) => format_f!(",ttc={ttc},ttc_thresh={s.cost.ttc_thresh}"),
+            None => "".to_string(),
         };
 
-        let kluct_max_cost = match (s.method.as_str(), s.mcts.selection_mode) {
-            ("mcts", ChildSelectionMode::KLUCB) => {
-                format_f!(",klucb_max_cost={s.mcts.klucb_max_cost}")
-            }
-            _ => "".to_string(),
+        let headway = match s.cost.headway_weight {
+            Some(headway) => format_f!(",headway={headway},headway_thresh={s.cost.headway_thresh}"),
+            None => "".to_string(),
         };
 
-        let repeat_const = match s.method.as_str() {
-            "mcts" => {
-                format_f!(",repeat_const={s.mcts.repeat_const}")
+        // only the parameters of the terms in use
+        let cost_terms = if s.cost.terms == STANDARD_COST_TERMS {
+            "".to_string()
+        } else {
+            let mut cost_terms = format_f!(",cost_terms={}", s.cost.terms.iter().join("+"));
+            for term in s.cost.terms.iter() {
+                cost_terms += &match term.as_str() {
+                    "jerk" => format_f!(",jerk={s.cost.jerk_weight}"),
+                    "lane_preference" => format_f!(
+                        ",lane_preference={s.cost.lane_preference_weight}\
+                         ,preferred_lane={s.cost.preferred_lane}"
+                    ),
+                    "rule_compliance" => format_f!(
+                        ",rule_compliance={s.cost.rule_compliance_weight}\
+                         ,speed_limit={s.cost.speed_limit}"
+                    ),
+                    _ => "".to_string(),
+                };
             }
-            _ => "".to_string(),
+            cost_terms
         };
 
-        let most_visited_best_cost_consistency = match s.method.as_str() {
-            "mcts" => {
-                format_f!(",most_visited_best_cost_consistency={s.mcts.most_visited_best_cost_consistency}")
-            }
-            _ => "".to_string(),
+        let safety_constraint = match s.cost.safety_constraint {
+            Some(max_safety) => format_f!(",safety_constraint={max_safety}"),
+            None => "".to_string(),
         };
 
-        let allow_different_root_policy = match s.method.as_str() {
-            "eudm" => {
-                format_f!(",allow_different_root_policy={s.eudm.allow_different_root_policy}")
-            }
-            _ => "".to_string(),
+        let scenario = match s.scenario.kind {
+            ScenarioKind::Random => "".to_string(),
+            _ => format_f!(
+                ",scenario={s.scenario.kind}\
+                 ,ending_lane={s.scenario.ending_lane}\
+                 ,merge_start_s={s.scenario.merge_start_s}\
+                 ,lane_end_s={s.scenario.lane_end_s}\
+                 ,deadline={s.cost.deadline_weight}"
+            ),
         };
 
         // "smoothness" => params.cost.smoothness_weight = val.parse().unwrap(),
@@ -344,17 +471,24 @@ fn create_scenarios(
         s.scenario_name = Some(format_f!(
             ",method={s.method}\
              ,use_cfb={s.use_cfb}\
-             {samples_n}{search_depth}{forward_t}\
-             {selection_mode}{bound_mode}{ucb_const}{kluct_max_cost}{repeat_const}\
-             {most_visited_best_cost_consistency}\
-             {allow_different_root_policy}\
+             {proposal_mix}\
+             {planner}\
              ,max_steps={s.max_steps}\
              ,n_cars={s.n_cars}\
+             ,n_lanes={s.n_lanes}\
+             {road}\
+             {scenario}\
+             {traffic_file}\
+             {resume_from}\
              ,safety={s.cost.safety_weight}\
              ,safety_margin_low={s.cost.safety_margin_low}\
              ,safety_margin_high={s.cost.safety_margin_high}\
+             {ttc}\
+             {headway}\
+             {cost_terms}\
              ,accel={s.cost.accel_weight}\
              ,steer={s.cost.steer_weight}\
+             {safety_constraint}\
              ,replan_dt={s.replan_dt}\
              ,discount_factor={s.cost.discount_factor}\
              ,rng_seed={s.rng_seed}\
@@ -365,8 +499,9 @@ fn create_scenarios(
     scenarios
 }
 
-pub fn run_parallel_scenarios() {
-    let parameters_default = Parameters::new().unwrap();
+pub fn run_parallel_scenarios(planners: &PlannerRegistry, cost_terms: CostTermRegistry) {
+    let mut parameters_default = Parameters::new().unwrap();
+    parameters_default.cost_term_registry = Arc::new(cost_terms);
 
     // let args = std::env::args().collect_vec();
     let mut name_value_pairs = Vec::<(String, Vec<String>)>::new();
@@ -412,7 +547,7 @@ pub fn run_parallel_scenarios() {
     let mut base_scenario = parameters_default;
     base_scenario.scenario_name = Some("".to_owned());
 
-    let scenarios = create_scenarios(&base_scenario, &name_value_pairs);
+    let scenarios = create_scenarios(&base_scenario, &name_value_pairs, planners);
     // for (i, scenario) in scenarios.iter().enumerate() {
     //     eprintln!("{}: {:?}", i, scenario.file_name);
     // }
@@ -426,7 +561,7 @@ pub fn run_parallel_scenarios() {
     let thread_limit = scenarios[0].thread_limit;
     if thread_limit > 0 {
         rayon::ThreadPoolBuilder::new()
-            .num_threads(thread_limit as usize)
+            .num_threads(thread_limit)
             .build_global()
             .unwrap();
     }
@@ -467,13 +602,21 @@ pub fn run_parallel_scenarios() {
         let mut scenario = scenarios[0].clone();
         scenario.is_single_run = true;
 
+        if let Some(replay_file) = scenario.replay_file.as_ref() {
+            replay(&scenario, replay_file);
+            return;
+        }
+
         let scenario_name = scenario.scenario_name.clone().unwrap();
-        let (cost, reward) = run_with_parameters(scenario);
+        let planner = planners.get(&scenario.method);
+        let (cost, reward) = run_with_parameters(scenario, planner);
         println_f!("{scenario_name}");
         println_f!("{cost:?}, {reward:?}");
     } else {
         scenarios.par_iter().for_each(|scenario| {
-            let result = std::panic::catch_unwind(|| {
+            // a panicking scenario only leaves its own run behind, and the planners and
+            // cost terms it shares with the others aren't changed while running
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
                 let scenario_name = scenario.scenario_name.clone().unwrap();
 
                 if cumulative_results
@@ -486,7 +629,8 @@ pub fn run_parallel_scenarios() {
                 }
 
                 let start_time = Instant::now();
-                let (cost, reward) = run_with_parameters(scenario.clone());
+                let planner = planners.get(&scenario.method);
+                let (cost, reward) = run_with_parameters(scenario.clone(), planner);
                 let seconds = start_time.elapsed().as_secs_f64();
 
                 n_scenarios_completed.fetch_add(1, Ordering::Relaxed);
@@ -506,7 +650,7 @@ pub fn run_parallel_scenarios() {
                 }
 
                 cumulative_results.lock().unwrap().insert(scenario_name, ());
-            });
+            }));
             if result.is_err() {
                 eprintln!(
                     "PANIC for scenario: {:?}",
@@ -1,16 +1,17 @@
 use itertools::Itertools;
-use rand::{
-    distributions::WeightedIndex,
-    prelude::{Distribution, StdRng},
-};
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
 use crate::{lane_change_policy::LongitudinalPolicy, road::Road};
 
 fn predict_lane(road: &Road, car_i: usize) -> i32 {
     let car = &road.cars[car_i];
-    let predicted_y =
-        car.y() + car.vel * (car.theta() + car.steer).sin() * road.params.lane_change_time;
-    Road::get_lane_i(predicted_y).min(1).max(0)
+    let predicted_d =
+        car.d() + car.vel * (car.frenet_theta() + car.steer).sin() * road.params.lane_change_time;
+    Road::get_lane_i(predicted_d)
+        .min(road.params.n_lanes - 1)
+        .max(0)
 }
 
 fn predict_long(road: &Road, car_i: usize) -> LongitudinalPolicy {
@@ -38,10 +39,75 @@ fn predict_long(road: &Road, car_i: usize) -> LongitudinalPolicy {
 fn predict_finished_waiting(road: &Road, car_i: usize) -> bool {
     let car = &road.cars[car_i];
     let lane_y = Road::get_lane_y(car.current_lane());
-    let dy = (lane_y - car.y()).abs();
+    let dy = (lane_y - car.d()).abs();
     dy > road.params.belief.finished_waiting_dy
 }
 
+// how likely each policy makes car_i's observed lane, longitudinal motion and
+// progress on waiting, unnormalized and in the order of the belief states
+pub fn observation_likelihoods(road: &Road, car_i: usize) -> Vec<f64> {
+    let bparams = &road.params.belief;
+    let pred_lane = predict_lane(road, car_i);
+    let pred_long = predict_long(road, car_i);
+    let pred_finished_waiting = predict_finished_waiting(road, car_i);
+
+    if road.super_debug() && road.params.belief_debug && road.params.debug_car_i == Some(car_i) {
+        eprintln_f!("{pred_lane=} {pred_long=:?} {pred_finished_waiting=}");
+    }
+
+    let mut likelihoods = Vec::new();
+    for lane_i in 0..road.params.n_lanes {
+        for long_policy in [LongitudinalPolicy::Maintain, LongitudinalPolicy::Accelerate] {
+            for wait_for_clear in [false, true] {
+                let mut prob = 1.0;
+                if lane_i != pred_lane {
+                    prob *= bparams.different_lane_prob;
+                }
+                if long_policy != pred_long {
+                    prob *= bparams.different_longitudinal_prob;
+                }
+                // wait_for_clear && pred_finished_waiting: already making lane change
+                // !wait_for_clear && pred_finished_waiting: already making lane change
+                // wait_for_clear && !pred_finished_waiting: still need to wait
+                // !wait_for_clear && !pred_finished_waiting: will start lane change
+                let would_lane_change = pred_finished_waiting || !wait_for_clear;
+                let current_lane_i = road.cars[car_i].current_lane();
+                let wants_lane_change = lane_i != current_lane_i;
+                let will_lane_change = would_lane_change && wants_lane_change;
+                // either we can make the lane change, and might as well use wait_for_clear=false
+                // or we still need to wait and so use wait_for_clear=true
+                // the other scenarios are superfluous, or inaccurate
+                if will_lane_change && wait_for_clear {
+                    prob = 0.0;
+                }
+                // waiting... to _not_ change lanes is also pointless
+                if !wants_lane_change && wait_for_clear {
+                    prob = 0.0;
+                }
+                // the chance that the vehicle effectively skips checking for it to be clear before turning
+                // in practice, this would more mean that noise prevented us from telling that they already started turning(?)
+                if wants_lane_change && !pred_finished_waiting && !wait_for_clear {
+                    prob *= bparams.skips_waiting_prob;
+                }
+                likelihoods.push(prob);
+
+                if road.super_debug()
+                    && road.params.belief_debug
+                    && road.params.debug_car_i == Some(car_i)
+                {
+                    eprintln_f!("{road.timesteps}: {car_i=} {lane_i=} {long_policy=:?} {wait_for_clear=}: {prob=:.2}, would: {would_lane_change}, wants: {wants_lane_change}, will: {will_lane_change}");
+                }
+            }
+        }
+    }
+    if LongitudinalPolicy::Decelerate == pred_long {
+        likelihoods.push(bparams.decelerate_prior_prob);
+    } else {
+        likelihoods.push(bparams.decelerate_prior_prob * bparams.different_longitudinal_prob);
+    }
+    likelihoods
+}
+
 fn normalize(belief: &mut [f64]) {
     let sum: f64 = belief.iter().sum();
     for val in belief.iter_mut() {
@@ -49,7 +115,7 @@ fn normalize(belief: &mut [f64]) {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Belief {
     belief: Vec<Vec<f64>>,
 }
@@ -71,70 +137,8 @@ impl Belief {
     }
 
     pub fn update(&mut self, road: &Road) {
-        let bparams = &road.params.belief;
         for (car_i, belief) in self.belief.iter_mut().enumerate().skip(1) {
-            let pred_lane = predict_lane(road, car_i);
-            let pred_long = predict_long(road, car_i);
-            let pred_finished_waiting = predict_finished_waiting(road, car_i);
-
-            if road.super_debug()
-                && road.params.belief_debug
-                && road.params.debug_car_i == Some(car_i)
-            {
-                eprintln_f!("{pred_lane=} {pred_long=:?} {pred_finished_waiting=}");
-            }
-
-            belief.clear();
-            for &lane_i in &[0, 1] {
-                for long_policy in [LongitudinalPolicy::Maintain, LongitudinalPolicy::Accelerate] {
-                    for wait_for_clear in [false, true] {
-                        let mut prob = 1.0;
-                        if lane_i != pred_lane {
-                            prob *= bparams.different_lane_prob;
-                        }
-                        if long_policy != pred_long {
-                            prob *= bparams.different_longitudinal_prob;
-                        }
-                        // wait_for_clear && pred_finished_waiting: already making lane change
-                        // !wait_for_clear && pred_finished_waiting: already making lane change
-                        // wait_for_clear && !pred_finished_waiting: still need to wait
-                        // !wait_for_clear && !pred_finished_waiting: will start lane change
-                        let would_lane_change = pred_finished_waiting || !wait_for_clear;
-                        let current_lane_i = road.cars[car_i].current_lane();
-                        let wants_lane_change = lane_i != current_lane_i;
-                        let will_lane_change = would_lane_change && wants_lane_change;
-                        // either we can make the lane change, and might as well use wait_for_clear=false
-                        // or we still need to wait and so use wait_for_clear=true
-                        // the other scenarios are superfluous, or inaccurate
-                        if will_lane_change && wait_for_clear {
-                            prob = 0.0;
-                        }
-                        // waiting... to _not_ change lanes is also pointless
-                        if !wants_lane_change && wait_for_clear {
-                            prob = 0.0;
-                        }
-                        // the chance that the vehicle effectively skips checking for it to be clear before turning
-                        // in practice, this would more mean that noise prevented us from telling that they already started turning(?)
-                        if wants_lane_change && !pred_finished_waiting && !wait_for_clear {
-                            prob *= bparams.skips_waiting_prob;
-                        }
-                        belief.push(prob);
-
-                        if road.super_debug()
-                            && road.params.belief_debug
-                            && road.params.debug_car_i == Some(car_i)
-                        {
-                            eprintln_f!("{road.timesteps}: {car_i=} {lane_i=} {long_policy=:?} {wait_for_clear=}: {prob=:.2}, would: {would_lane_change}, wants: {wants_lane_change}, will: {will_lane_change}");
-                        }
-                    }
-                }
-            }
-            if LongitudinalPolicy::Decelerate == pred_long {
-                belief.push(bparams.decelerate_prior_prob);
-            } else {
-                belief.push(bparams.decelerate_prior_prob * bparams.different_longitudinal_prob);
-            }
-
+            *belief = observation_likelihoods(road, car_i);
             normalize(belief);
 
             if road.params.belief_debug
@@ -146,13 +150,45 @@ impl Belief {
         }
     }
 
-    pub fn sample(&self, rng: &mut StdRng) -> Vec<usize> {
+    pub fn sample(&self, rng: &mut ChaCha12Rng) -> Vec<usize> {
         self.belief
             .iter()
             .map(|weights| WeightedIndex::new(weights).unwrap().sample(rng))
             .collect_vec()
     }
 
+    // Samples from the belief mixed with uniform_mix of a uniform distribution over the
+    // possible policies, which favors the unlikely (and often risky) ones, returning the
+    // importance weight of the sample as well. The ego car's belief doesn't count towards it.
+    pub fn sample_proposal(&self, rng: &mut ChaCha12Rng, uniform_mix: f64) -> (Vec<usize>, f64) {
+        let mut weight = 1.0;
+        let sample = self
+            .belief
+            .iter()
+            .enumerate()
+            .map(|(car_i, probs)| {
+                let n_possible = probs.iter().filter(|&&p| p > 0.0).count();
+                let uniform_prob = 1.0 / n_possible as f64;
+                let proposal = probs
+                    .iter()
+                    .map(|&p| {
+                        if p > 0.0 {
+                            (1.0 - uniform_mix) * p + uniform_mix * uniform_prob
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect_vec();
+                let policy_i = WeightedIndex::new(&proposal).unwrap().sample(rng);
+                if car_i > 0 {
+                    weight *= probs[policy_i] / proposal[policy_i];
+                }
+                policy_i
+            })
+            .collect_vec();
+        (sample, weight)
+    }
+
     pub fn get(&self, car_i: usize, policy_id: usize) -> f64 {
         assert_ne!(car_i, 0);
         self.belief[car_i][policy_id]
@@ -1,21 +1,24 @@
 use std::f64::consts::PI;
 
-use nalgebra::vector;
+use nalgebra::{point, vector};
 use parry2d_f64::{
     bounding_volume::AABB,
     na::Isometry2,
-    shape::{Cuboid, Shape},
+    shape::{Cuboid, Shape as ParryShape},
 };
-use rand::prelude::{Rng, StdRng};
-use rvx::{Rvx, RvxColor};
+use rand::Rng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     arg_parameters::Parameters,
     forward_control::ForwardControl,
+    graphics::{Canvas, Color, Shape},
     intelligent_driver::IntelligentDriverPolicy,
     mpdm::make_obstacle_vehicle_policy_choices,
     open_loop_policy::{OpenLoopForwardControl, OpenLoopPolicy, OpenLoopSideControl},
     pure_pursuit::PurePursuitPolicy,
+    reference_path::ReferencePath,
     road::{Road, ROAD_LENGTH},
     side_control::{SideControl, SideControlTrait},
     side_policies::{SidePolicy, SidePolicyTrait},
@@ -42,7 +45,7 @@ pub const PREFERRED_ACCEL_HIGH: f64 = 2.0; // 11.2; // model s, 2.4s zero to six
 pub const PREFERRED_ACCEL_DEFAULT: f64 = 2.0; // 16s zero to sixty, just under max accel for a prius (13s)
 pub const BREAKING_ACCEL: f64 = 6.0;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Car {
     pub car_i: usize,
     pub crashed: bool,
@@ -72,14 +75,30 @@ pub struct Car {
     pub side_policy: Option<SidePolicy>,
 
     // cached
+    #[serde(skip, default = "empty_shape")]
     shape: Cuboid,
+    #[serde(skip, default = "Isometry2::identity")]
     pose: Isometry2<f64>,
+    #[serde(skip, default = "AABB::new_invalid")]
     aabb: AABB,
     // norotation_aabb: AABB,
+
+    // cached position along the road's reference path
+    s: f64,
+    d: f64,
+    #[serde(skip)]
+    path_theta: f64,
+    path_seg_i: usize,
+    #[serde(skip, default = "AABB::new_invalid")]
+    frenet_aabb: AABB,
+}
+
+fn empty_shape() -> Cuboid {
+    Cuboid::new(vector!(0.0, 0.0))
 }
 
 impl Car {
-    pub fn new(params: &Parameters, car_i: usize, lane_i: i32) -> Self {
+    pub fn new(params: &Parameters, path: &ReferencePath, car_i: usize, lane_i: i32) -> Self {
         let lane_y = Road::get_lane_y(lane_i);
         let policies = make_obstacle_vehicle_policy_choices(params);
         let width = PRIUS_WIDTH;
@@ -112,27 +131,39 @@ impl Car {
             side_control: Some(SideControl::PurePursuitPolicy(PurePursuitPolicy::new(
                 AHEAD_TIME_DEFAULT,
             ))),
-            side_policy: Some(if lane_i == 0 {
-                policies[1].clone()
-            } else {
-                policies[3].clone()
-            }),
+            // the "accelerate" policy for the starting lane
+            side_policy: Some(policies[2 * lane_i as usize + 1].clone()),
 
             shape: Cuboid::new(vector!(length / 2.0, width / 2.0)),
             pose: Isometry2::identity(),
             aabb: AABB::new_invalid(),
+
+            s: 0.0,
+            d: lane_y,
+            path_theta: 0.0,
+            path_seg_i: 0,
+            frenet_aabb: AABB::new_invalid(),
         };
 
-        car.update_geometry_cache();
+        car.set_frenet(path, 0.0, lane_y);
         car
     }
 
-    pub fn random_new(params: &Parameters, car_i: usize, rng: &mut StdRng) -> Self {
-        let lane_i = rng.gen_range(0..=1);
-        let mut car = Self::new(params, car_i, lane_i);
+    pub fn random_new(
+        params: &Parameters,
+        path: &ReferencePath,
+        car_i: usize,
+        rng: &mut ChaCha12Rng,
+    ) -> Self {
+        let lane_i = rng.gen_range(0..params.n_lanes);
+        let mut car = Self::new(params, path, car_i, lane_i);
         car.preferred_vel = rng.gen_range(SPEED_LOW..SPEED_HIGH);
         car.vel = car.preferred_vel;
-        car.set_x(rng.gen_range(0.0..ROAD_LENGTH) - ROAD_LENGTH / 2.0);
+        car.set_frenet(
+            path,
+            rng.gen_range(0.0..ROAD_LENGTH) - ROAD_LENGTH / 2.0,
+            car.d,
+        );
         car.preferred_accel = rng.gen_range(PREFERRED_ACCEL_LOW..PREFERRED_ACCEL_HIGH);
         car.preferred_follow_time = rng.gen_range(FOLLOW_TIME_LOW..FOLLOW_TIME_HIGH);
 
@@ -193,7 +224,30 @@ impl Car {
         self.aabb = self.shape().compute_aabb(&self.pose());
     }
 
-    pub fn update(&mut self, dt: f64) {
+    fn update_frenet_cache(&mut self, path: &ReferencePath) {
+        self.path_theta = path.heading(self.s);
+
+        // like the cartesian aabb, but aligned with the road instead
+        let theta = self.frenet_theta();
+        let (sin, cos) = theta.sin_cos();
+        let center_s = self.s - self.length / 2.0 * cos;
+        let center_d = self.d - self.length / 2.0 * sin;
+        let half_s = self.length / 2.0 * cos.abs() + self.width / 2.0 * sin.abs();
+        let half_d = self.length / 2.0 * sin.abs() + self.width / 2.0 * cos.abs();
+        self.frenet_aabb = AABB::new(
+            point!(center_s - half_s, center_d - half_d),
+            point!(center_s + half_s, center_d + half_d),
+        );
+    }
+
+    // after deserializing, which skips the cached geometry
+    pub fn restore_caches(&mut self, path: &ReferencePath) {
+        self.shape = Cuboid::new(vector!(self.length / 2.0, self.width / 2.0));
+        self.update_geometry_cache();
+        self.update_frenet_cache(path);
+    }
+
+    pub fn update(&mut self, dt: f64, path: &ReferencePath) {
         if !self.crashed {
             let theta = self.theta + self.steer;
             self.x += theta.cos() * self.vel * dt;
@@ -201,55 +255,61 @@ impl Car {
             self.theta += self.vel * self.steer.sin() / self.length * dt;
 
             self.update_geometry_cache();
+
+            let (s, d, seg_i) = path.to_frenet_near(point!(self.x, self.y), self.path_seg_i);
+            self.s = s;
+            self.d = d;
+            self.path_seg_i = seg_i;
+            self.update_frenet_cache(path);
         }
     }
 
-    pub fn draw(&self, params: &Parameters, r: &mut Rvx, color: RvxColor) {
+    pub fn draw(&self, params: &Parameters, r: &mut Canvas, color: Color) {
         // front dot
         r.draw(
-            Rvx::circle()
+            Shape::circle()
                 .scale(0.5)
                 .translate(&[self.x, self.y])
-                .color(RvxColor::WHITE.set_a(0.5)),
+                .color(Color::WHITE.set_a(0.5)),
         );
 
         // back dot
         r.draw(
-            Rvx::circle()
+            Shape::circle()
                 .scale(0.5)
                 .translate(&[
                     self.x - self.length * self.theta.cos(),
                     self.y - self.length * self.theta.sin(),
                 ])
-                .color(RvxColor::YELLOW.set_a(0.5)),
+                .color(Color::YELLOW.set_a(0.5)),
         );
 
         // front wheel
         r.draw(
-            Rvx::square()
+            Shape::square()
                 .scale_xy(&[1.0, 0.5])
                 .rot(self.theta + self.steer)
                 .translate(&[self.x, self.y])
-                .color(RvxColor::BLACK.set_a(0.9)),
+                .color(Color::BLACK.set_a(0.9)),
         );
 
         // back wheel
         r.draw(
-            Rvx::square()
+            Shape::square()
                 .scale_xy(&[1.0, 0.5])
                 .rot(self.theta)
                 .translate(&[
                     self.x - self.length * self.theta.cos(),
                     self.y - self.length * self.theta.sin(),
                 ])
-                .color(RvxColor::BLACK.set_a(0.9)),
+                .color(Color::BLACK.set_a(0.9)),
         );
 
         let center_x = self.x - self.length / 2.0 * self.theta.cos();
         let center_y = self.y - self.length / 2.0 * self.theta.sin();
 
         r.draw(
-            Rvx::square()
+            Shape::square()
                 .scale_xy(&[self.length, self.width])
                 .rot(self.theta)
                 .translate(&[center_x, center_y])
@@ -258,16 +318,16 @@ impl Car {
 
         // if !params.graphics_for_paper {
         r.draw(
-            Rvx::text(&format!("{:.1}", self.car_i,), "Arial", 60.0)
+            Shape::text(&format!("{:.1}", self.car_i,), "Arial", 60.0)
                 .rot(-PI / 2.0)
                 .translate(&[self.x - self.length / 2.0, self.y + self.width / 2.0])
-                .color(RvxColor::BLACK),
+                .color(Color::BLACK),
         );
         // }
 
         if false {
             r.draw(
-            Rvx::text(
+            Shape::text(
                 &format!(
                     "MPH: {:.1}\nPref MPH: {:.1}\nLane: {}, y: {:.2}\nFollow time: {:.1}\nPref accel: {:.1}\nPref follow time: {:.1}\nPref follow: {:.1}",
                     self.vel * MPS_TO_MPH,
@@ -287,7 +347,7 @@ impl Car {
         );
         } else if !params.graphics_for_paper {
             r.draw(
-                Rvx::text(
+                Shape::text(
                     &format!(
                         "MPH: {:.1}\nPref MPH: {:.1}\nFollow time: {:.1}\nx: {:.1}\n{}",
                         self.vel * MPS_TO_MPH,
@@ -314,10 +374,10 @@ impl Car {
     }
 
     pub fn current_lane(&self) -> i32 {
-        Road::get_lane_i(self.y)
+        Road::get_lane_i(self.d)
     }
 
-    pub fn shape(&self) -> impl Shape {
+    pub fn shape(&self) -> impl ParryShape {
         self.shape
     }
 
@@ -330,6 +390,7 @@ impl Car {
         self.pose
     }
 
+    #[allow(unused)]
     pub fn aabb(&self) -> AABB {
         // let aabb = self.shape().compute_aabb(&self.pose());
         // assert_eq!(aabb, self.aabb);
@@ -337,6 +398,11 @@ impl Car {
         self.aabb
     }
 
+    // aabb in (s, d) coordinates along the road
+    pub fn frenet_aabb(&self) -> AABB {
+        self.frenet_aabb
+    }
+
     pub fn x(&self) -> f64 {
         self.x
     }
@@ -349,43 +415,122 @@ impl Car {
         self.theta
     }
 
-    pub fn set_x(&mut self, x: f64) {
-        self.x = x;
-        self.update_geometry_cache();
+    pub fn s(&self) -> f64 {
+        self.s
     }
 
-    #[allow(unused)]
-    pub fn set_y(&mut self, y: f64) {
-        self.y = y;
+    pub fn d(&self) -> f64 {
+        self.d
+    }
+
+    // heading relative to the road
+    pub fn frenet_theta(&self) -> f64 {
+        let theta = self.theta - self.path_theta;
+        if theta > PI {
+            theta - 2.0 * PI
+        } else if theta < -PI {
+            theta + 2.0 * PI
+        } else {
+            theta
+        }
+    }
+
+    // places the car at the given road position, aligned with the road
+    pub fn set_frenet(&mut self, path: &ReferencePath, s: f64, d: f64) {
+        let p = path.to_cartesian(s, d);
+        self.x = p.x;
+        self.y = p.y;
+        self.theta = path.heading(s);
         self.update_geometry_cache();
+
+        self.s = s;
+        self.d = d;
+        // so the local search from here on starts from the right place, not the old one
+        self.path_seg_i = path.segment_at(s);
+        self.update_frenet_cache(path);
     }
 
-    #[allow(unused)]
-    pub fn set_theta(&mut self, theta: f64) {
+    // places the car at the given pose, as when replaying a recorded run
+    pub fn set_pose(&mut self, path: &ReferencePath, x: f64, y: f64, theta: f64) {
+        self.x = x;
+        self.y = y;
         self.theta = theta;
         self.update_geometry_cache();
+
+        let (s, d, seg_i) = path.to_frenet_near(point!(x, y), self.path_seg_i);
+        self.s = s;
+        self.d = d;
+        self.path_seg_i = seg_i;
+        self.update_frenet_cache(path);
     }
 
-    pub fn spatial_x(&self) -> i32 {
+    pub fn spatial_s(&self) -> i32 {
         self.spatial_offset(0.0)
     }
 
-    pub fn spatial_offset(&self, dx: f64) -> i32 {
-        ((self.x + dx) * 1000.0) as i32
+    pub fn spatial_offset(&self, ds: f64) -> i32 {
+        ((self.s + ds) * 1000.0) as i32
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct SpatialCar {
-    pub x: i32,
+    pub s: i32,
     pub car_i: u32,
 }
 
 impl From<&Car> for SpatialCar {
     fn from(item: &Car) -> Self {
         Self {
-            x: item.spatial_x(),
+            s: item.spatial_s(),
             car_i: item.car_i as u32,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::reference_path::CenterlineSegment;
+
+    #[test]
+    fn respawn_on_curved_path_tracks_from_new_position() {
+        // out and back around a half circle, so the far leg passes close to the near one
+        let radius = 20.0;
+        let path = ReferencePath::from_segments(&[
+            CenterlineSegment {
+                length: 100.0,
+                start_curvature: 0.0,
+                end_curvature: 0.0,
+            },
+            CenterlineSegment {
+                length: radius * PI,
+                start_curvature: 1.0 / radius,
+                end_curvature: 1.0 / radius,
+            },
+            CenterlineSegment {
+                length: 100.0,
+                start_curvature: 0.0,
+                end_curvature: 0.0,
+            },
+        ]);
+        let params = Parameters::new().unwrap();
+        let mut car = Car::new(&params, &path, 1, 0);
+
+        // as when a car is respawned far from where it was
+        let s = 100.0 + radius * PI + 50.0;
+        car.set_frenet(&path, s, 0.0);
+        car.vel = 10.0;
+        car.update(0.1, &path);
+
+        assert!(
+            (car.s() - (s + 1.0)).abs() < 1e-3,
+            "{} != {}",
+            car.s(),
+            s + 1.0
+        );
+        assert!(car.d().abs() < 1e-3);
+    }
+}
@@ -19,7 +19,7 @@ fn key_vehicles(params: &Parameters, road: &Road) -> Vec<(usize, f64)> {
             continue;
         }
 
-        let dx = (ego.x() - c.x()).abs();
+        let dx = (ego.s() - c.s()).abs();
         // if params.cfb_debug && road.super_debug() {
         //     eprintln_f!("ego to {c.car_i}: {dx=:.2}, {dx_thresh=:.2}");
         // }
@@ -31,14 +31,16 @@ fn key_vehicles(params: &Parameters, road: &Road) -> Vec<(usize, f64)> {
     car_ids
 }
 
+// kept in a heap by the scenario's probability, least probable first, with each car's policy
+type RankedScenario = (std::cmp::Reverse<NotNan<f64>>, Vec<(usize, usize)>);
+
 fn most_probable_cartesian_product_scenarios(
     car_is: &[usize],
     belief: &Belief,
     n_policies: usize,
     n_scenarios: usize,
 ) -> Vec<(f64, Vec<(usize, usize)>)> {
-    let mut top_n_scenarios: BinaryHeap<(std::cmp::Reverse<NotNan<f64>>, Vec<(usize, usize)>)> =
-        BinaryHeap::new();
+    let mut top_n_scenarios: BinaryHeap<RankedScenario> = BinaryHeap::new();
     let mut current_scenario = car_is.iter().map(|a| (*a, 0)).collect_vec();
     'outer: loop {
         let probability: f64 = current_scenario
@@ -190,24 +192,28 @@ pub fn conditional_focused_branching(
     // sort descending and choose just the most probable
     // ranked_scenarios.sort_unstable_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
     // ranked_scenarios.truncate(n);
-    let mut roads = top_n_scenarios
+    // the scenarios are weighted by their probabilities
+    let (mut roads, mut weights): (Vec<_>, Vec<_>) = top_n_scenarios
         .into_iter()
         .map(|(prob, scenario)| {
             let mut sim_road = sim_road.clone();
             for (car_i, policy_i) in scenario.iter() {
                 sim_road.cars[*car_i].side_policy = Some(policies[*policy_i].clone());
             }
-
-            sim_road.cost.weight = prob;
-            sim_road
+            (sim_road, prob)
         })
-        .collect_vec();
+        .filter(|(_, prob)| *prob > 0.0)
+        .unzip();
 
     if roads.is_empty() {
         roads.push(sim_road);
+        weights.push(1.0);
     }
 
-    (RoadSet::new(roads), selected_important_car_ids)
+    (
+        RoadSet::new_weighted(roads, weights),
+        selected_important_car_ids,
+    )
 }
 
 #[cfg(test)]
@@ -219,10 +225,10 @@ mod tests {
     #[test]
     fn most_probable_cartesian_product() {
         let n_cars = 5;
-        let policies = vec![0, 1, 2];
+        let policies = [0, 1, 2];
         let risky_car_is = vec![2, 3, 4];
 
-        let beliefs = vec![
+        let beliefs = [
             Belief::for_all_cars(n_cars, &[0.1, 0.2, 0.3]),
             Belief::for_all_cars(n_cars, &[0.3, 0.4, 0.1]),
             Belief::for_all_cars(n_cars, &[0.3, 0.2, 0.1]),
@@ -294,10 +300,9 @@ mod tests {
         }
         ranked_scenarios.sort_unstable_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
         ranked_scenarios.truncate(n_scenarios);
-        let ranked_scenarios = ranked_scenarios
+        ranked_scenarios
             .into_iter()
             .map(|(p, scenario)| (p, scenario.clone()))
-            .collect_vec();
-        ranked_scenarios
+            .collect_vec()
     }
 }
@@ -1,9 +1,32 @@
-#[derive(Clone, Copy, PartialEq)]
+use std::collections::BTreeMap;
+
+use progressive_mcts::{cost_set::CostSet, engine::TrialCost};
+use serde::{Deserialize, Serialize};
+
+// room for the original five components and any added by the cost terms
+pub const MAX_COST_COMPONENTS: usize = 16;
+
+// A component of a cost, accumulated by the cost terms that feed into it. The original
+// five always come first, in the order that results.cache lists them, and the cost term
+// registry allocates any others and keeps their names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CostComponent(pub(crate) usize);
+
+impl CostComponent {
+    pub const EFFICIENCY: Self = Self(0);
+    pub const SAFETY: Self = Self(1);
+    pub const ACCEL: Self = Self(2);
+    pub const STEER: Self = Self(3);
+    pub const DEADLINE: Self = Self(4);
+
+    pub const STANDARD_NAMES: [&'static str; 5] =
+        ["efficiency", "safety", "accel", "steer", "deadline"];
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Cost {
-    pub efficiency: f64,
-    pub safety: f64,
-    pub accel: f64,
-    pub steer: f64,
+    // indexed by CostComponent, with zeros for the components no term has added yet
+    components: [f64; MAX_COST_COMPONENTS],
 
     pub discount: f64,
     pub discount_factor: f64,
@@ -11,23 +34,59 @@ pub struct Cost {
     pub weight: f64,
 }
 
+// A cost with its components by name, as recordings store it, so that they don't depend
+// on the order in which a registry allocated the components. The cost term registry
+// converts costs to and from this. Older recordings have the original five as named fields.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamedCost {
+    #[serde(flatten)]
+    pub components: BTreeMap<String, f64>,
+    pub discount: f64,
+    pub discount_factor: f64,
+    pub weight: f64,
+}
+
+// Only the original five components, which plot.py reads from results.cache by position.
 impl std::fmt::Display for Cost {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = self.normalize();
-        write_f!(
+        let s = self.normalize().components;
+        write!(
             f,
-            "{s.efficiency:8.2} {s.safety:8.2} {s.accel:8.2} {s.steer:8.2}"
+            "{:8.2} {:8.2} {:8.2} {:8.2} {:8.2}",
+            s[0], s[1], s[2], s[3], s[4]
         )
     }
 }
 
 impl std::fmt::Debug for Cost {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = self;
-        write_f!(
+        let s = &self.components;
+        write!(
             f,
-            "eff: {s.efficiency:.2}, safe: {s.safety:.2}, accel: {s.accel:.2}, steer: {s.steer:.2}"
-        )
+            "eff: {:.2}, safe: {:.2}, accel: {:.2}, steer: {:.2}, deadline: {:.2}",
+            s[0], s[1], s[2], s[3], s[4]
+        )?;
+        // the other components only when some cost term is using them
+        for (i, c) in s.iter().enumerate().skip(5) {
+            if *c != 0.0 {
+                write!(f, ", component {}: {:.2}", i, c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::ops::Index<CostComponent> for Cost {
+    type Output = f64;
+
+    fn index(&self, component: CostComponent) -> &f64 {
+        &self.components[component.0]
+    }
+}
+
+impl std::ops::IndexMut<CostComponent> for Cost {
+    fn index_mut(&mut self, component: CostComponent) -> &mut f64 {
+        &mut self.components[component.0]
     }
 }
 
@@ -36,10 +95,7 @@ impl Cost {
 
     pub const fn new(discount_factor: f64, weight: f64) -> Self {
         Self {
-            efficiency: 0.0,
-            safety: 0.0,
-            accel: 0.0,
-            steer: 0.0,
+            components: [0.0; MAX_COST_COMPONENTS],
             discount: 1.0,
             discount_factor,
             weight,
@@ -47,37 +103,58 @@ impl Cost {
     }
 
     pub fn max_value() -> Self {
-        Self {
-            efficiency: f64::MAX,
-            safety: 0.0,
-            accel: 0.0,
-            steer: 0.0,
-            discount: 1.0,
-            discount_factor: 1.0,
-            weight: 1.0,
+        let mut cost = Self::new(1.0, 1.0);
+        cost[CostComponent::EFFICIENCY] = f64::MAX;
+        cost
+    }
+
+    // applies f to every component, keeping the discount and weight
+    fn map(mut self, f: impl Fn(f64) -> f64) -> Self {
+        for c in self.components.iter_mut() {
+            *c = f(*c);
         }
+        self
     }
 
     pub fn normalize(&self) -> Self {
         Self {
-            efficiency: self.efficiency * self.weight,
-            safety: self.safety * self.weight,
-            accel: self.accel * self.weight,
-            steer: self.steer * self.weight,
             discount: 1.0,
             discount_factor: 1.0,
             weight: 1.0,
+            ..self.map(|c| c * self.weight)
         }
     }
 
     fn unweighted_total(&self) -> f64 {
-        self.efficiency + self.safety + self.accel + self.steer
+        self.components.iter().sum()
     }
 
     pub fn total(&self) -> f64 {
         self.weight * self.unweighted_total()
     }
 
+    // How policies are ranked when choosing between them. Without a safety constraint,
+    // that's by total cost. With one, the policies whose safety cost is within it come
+    // first, ranked by their other costs, and then the rest by their safety cost alone.
+    pub fn choice_key(&self, safety_constraint: Option<f64>) -> (bool, f64) {
+        match safety_constraint {
+            None => (false, self.total()),
+            Some(max_safety) => {
+                let safety = self.weight * self[CostComponent::SAFETY];
+                if safety <= max_safety {
+                    let others = self.unweighted_total() - self[CostComponent::SAFETY];
+                    (false, self.weight * others)
+                } else {
+                    (true, safety)
+                }
+            }
+        }
+    }
+
+    pub fn is_better_choice(&self, other: &Self, safety_constraint: Option<f64>) -> bool {
+        self.choice_key(safety_constraint) < other.choice_key(safety_constraint)
+    }
+
     pub fn update_discount(&mut self, dt: f64) {
         self.discount *= self.discount_factor.powf(dt);
     }
@@ -91,6 +168,27 @@ impl Cost {
     }
 }
 
+// costs are kept in cost sets by their totals, but averaged by component
+impl TrialCost for Cost {
+    const ZERO: Self = Cost::ZERO;
+
+    fn total(&self) -> f64 {
+        Cost::total(self)
+    }
+
+    fn mean<T: Clone>(costs: &CostSet<f64, T>, cost: impl Fn(&T) -> Self) -> Self {
+        if costs.is_weighted() {
+            costs
+                .iter_weighted()
+                .map(|((_, c), weight)| cost(c) * weight)
+                .sum::<Cost>()
+                / costs.sum_weights()
+        } else {
+            costs.iter().map(|(_, c)| cost(c)).sum::<Cost>() / costs.len() as f64
+        }
+    }
+}
+
 impl Default for Cost {
     fn default() -> Self {
         Self::new(1.0, 1.0)
@@ -117,15 +215,7 @@ impl std::ops::Mul<f64> for Cost {
     type Output = Cost;
 
     fn mul(self, rhs: f64) -> Self::Output {
-        Self {
-            efficiency: self.efficiency * rhs,
-            safety: self.safety * rhs,
-            accel: self.accel * rhs,
-            steer: self.steer * rhs,
-            discount: self.discount,
-            discount_factor: self.discount_factor,
-            weight: self.weight,
-        }
+        self.map(|c| c * rhs)
     }
 }
 
@@ -133,24 +223,13 @@ impl std::ops::Div<f64> for Cost {
     type Output = Cost;
 
     fn div(self, rhs: f64) -> Self::Output {
-        Self {
-            efficiency: self.efficiency / rhs,
-            safety: self.safety / rhs,
-            accel: self.accel / rhs,
-            steer: self.steer / rhs,
-            discount: self.discount,
-            discount_factor: self.discount_factor,
-            weight: self.weight,
-        }
+        self.map(|c| c / rhs)
     }
 }
 
 impl std::ops::DivAssign<f64> for Cost {
     fn div_assign(&mut self, rhs: f64) {
-        self.efficiency /= rhs;
-        self.safety /= rhs;
-        self.accel /= rhs;
-        self.steer /= rhs;
+        *self = *self / rhs;
     }
 }
 
@@ -158,16 +237,15 @@ impl std::ops::Add for Cost {
     type Output = Cost;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let a = self.normalize();
+        let mut sum = self.normalize();
         let b = rhs.normalize();
+        for (c, b) in sum.components.iter_mut().zip(b.components.iter()) {
+            *c += b;
+        }
         Self {
-            efficiency: a.efficiency + b.efficiency,
-            safety: a.safety + b.safety,
-            accel: a.accel + b.accel,
-            steer: a.steer + b.steer,
             discount: self.discount,
             discount_factor: self.discount_factor,
-            weight: 1.0,
+            ..sum
         }
     }
 }
@@ -176,16 +254,15 @@ impl std::ops::Sub for Cost {
     type Output = Cost;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        let a = self.normalize();
+        let mut diff = self.normalize();
         let b = rhs.normalize();
+        for (c, b) in diff.components.iter_mut().zip(b.components.iter()) {
+            *c -= b;
+        }
         Self {
-            efficiency: a.efficiency - b.efficiency,
-            safety: a.safety - b.safety,
-            accel: a.accel - b.accel,
-            steer: a.steer - b.steer,
             discount: self.discount,
             discount_factor: self.discount_factor,
-            weight: 1.0,
+            ..diff
         }
     }
 }
@@ -195,3 +272,27 @@ impl std::ops::AddAssign for Cost {
         *self = *self + rhs;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cost(efficiency: f64, safety: f64) -> Cost {
+        let mut cost = Cost::ZERO;
+        cost[CostComponent::EFFICIENCY] = efficiency;
+        cost[CostComponent::SAFETY] = safety;
+        cost
+    }
+
+    #[test]
+    fn safety_constraint_ranks_lexicographically() {
+        let fast_unsafe = cost(1.0, 3.0);
+        let slow_safe = cost(4.0, 1.0);
+        assert!(fast_unsafe.is_better_choice(&slow_safe, None));
+        assert!(slow_safe.is_better_choice(&fast_unsafe, Some(2.0)));
+        // within the constraint, safety doesn't matter
+        assert!(fast_unsafe.is_better_choice(&slow_safe, Some(3.0)));
+        // outside of it, only safety does
+        assert!(slow_safe.is_better_choice(&fast_unsafe, Some(0.5)));
+    }
+}
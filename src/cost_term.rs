@@ -0,0 +1,417 @@
+use crate::{
+    cost::{Cost, CostComponent, NamedCost, MAX_COST_COMPONENTS},
+    road::{change_range, logistic, Road},
+};
+
+// One part of the ego car's cost function, which cost.terms lists by name. Each step, the
+// road adds the term's cost rate, discounted and times dt, into the component it names.
+pub trait CostTerm: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // terms with the same component are summed into it
+    fn component(&self) -> &'static str;
+
+    // the weighted cost per second at the road's current state, after a step of dt
+    fn cost_rate(&self, road: &Road, dt: f64) -> f64;
+}
+
+// the cost function as it was before terms could be chosen
+pub const STANDARD_COST_TERMS: [&str; 6] = [
+    "efficiency",
+    "safety",
+    "time_gap",
+    "deadline",
+    "accel",
+    "steer",
+];
+
+struct RegisteredCostTerm {
+    term: Box<dyn CostTerm>,
+    component: CostComponent,
+}
+
+// The cost terms that cost.terms can use. The default registry has the built-in terms,
+// and a new term only needs to be registered, along with any parameters it reads.
+// Each registry allocates its own cost components, beyond the original five.
+pub struct CostTermRegistry {
+    terms: Vec<RegisteredCostTerm>,
+    // indexed by CostComponent
+    component_names: Vec<&'static str>,
+}
+
+impl CostTermRegistry {
+    pub fn empty() -> Self {
+        Self {
+            terms: Vec::new(),
+            component_names: CostComponent::STANDARD_NAMES.to_vec(),
+        }
+    }
+
+    pub fn register(&mut self, term: impl CostTerm + 'static) {
+        assert!(
+            self.find(term.name()).is_none(),
+            "cost term {} is already registered",
+            term.name()
+        );
+        let component = self.allocate_component(term.component());
+        self.terms.push(RegisteredCostTerm {
+            term: Box::new(term),
+            component,
+        });
+    }
+
+    pub fn find(&self, name: &str) -> Option<(&dyn CostTerm, CostComponent)> {
+        self.terms
+            .iter()
+            .find(|t| t.term.name() == name)
+            .map(|t| (t.term.as_ref(), t.component))
+    }
+
+    pub fn get(&self, name: &str) -> (&dyn CostTerm, CostComponent) {
+        self.find(name)
+            .unwrap_or_else(|| panic!("invalid cost term '{}'", name))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.terms.iter().map(|t| t.term.name())
+    }
+
+    // the component with this name, which is added if there isn't one yet
+    fn allocate_component(&mut self, name: &'static str) -> CostComponent {
+        if let Some(component) = self.component(name) {
+            return component;
+        }
+        assert!(
+            self.component_names.len() < MAX_COST_COMPONENTS,
+            "no room for cost component {}, as there are already {}",
+            name,
+            MAX_COST_COMPONENTS
+        );
+        self.component_names.push(name);
+        CostComponent(self.component_names.len() - 1)
+    }
+
+    pub fn component(&self, name: &str) -> Option<CostComponent> {
+        self.component_names
+            .iter()
+            .position(|n| *n == name)
+            .map(CostComponent)
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = (CostComponent, &'static str)> + '_ {
+        self.component_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (CostComponent(i), *name))
+    }
+
+    pub fn named_cost(&self, cost: &Cost) -> NamedCost {
+        NamedCost {
+            components: self
+                .components()
+                .map(|(component, name)| (name.to_owned(), cost[component]))
+                .collect(),
+            discount: cost.discount,
+            discount_factor: cost.discount_factor,
+            weight: cost.weight,
+        }
+    }
+
+    // panics on a component that none of this registry's terms use
+    pub fn cost_from_named(&self, named: &NamedCost) -> Cost {
+        let mut cost = Cost::new(named.discount_factor, named.weight);
+        cost.discount = named.discount;
+        for (name, value) in named.components.iter() {
+            let component = self
+                .component(name)
+                .unwrap_or_else(|| panic!("invalid cost component '{}'", name));
+            cost[component] = *value;
+        }
+        cost
+    }
+}
+
+impl Default for CostTermRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(EfficiencyTerm);
+        registry.register(SafetyTerm);
+        registry.register(TimeGapTerm);
+        registry.register(DeadlineTerm);
+        registry.register(AccelTerm);
+        registry.register(SteerTerm);
+        registry.register(JerkTerm);
+        registry.register(LanePreferenceTerm);
+        registry.register(RuleComplianceTerm);
+        registry
+    }
+}
+
+// so that Parameters, which carries the registry, can still be printed and compared
+impl std::fmt::Debug for CostTermRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl PartialEq for CostTermRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        self.names().eq(other.names())
+    }
+}
+
+// for not going at the preferred speed
+pub struct EfficiencyTerm;
+
+impl CostTerm for EfficiencyTerm {
+    fn name(&self) -> &'static str {
+        "efficiency"
+    }
+
+    fn component(&self) -> &'static str {
+        "efficiency"
+    }
+
+    fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+        let cparams = &road.params.cost;
+        let car = &road.cars[0];
+        cparams.efficiency_weight
+            * cparams.efficiency_speed_cost
+            * (car.preferred_vel - car.vel).abs()
+    }
+}
+
+// for getting close to other cars, through a logistic function of the distance
+pub struct SafetyTerm;
+
+impl CostTerm for SafetyTerm {
+    fn name(&self) -> &'static str {
+        "safety"
+    }
+
+    fn component(&self) -> &'static str {
+        "safety"
+    }
+
+    fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+        let cparams = &road.params.cost;
+        let min_dist = match road.min_unsafe_dist(0) {
+            Some(min_dist) => min_dist,
+            None => return 0.0,
+        };
+        let penalty = cparams.safety_weight
+            * logistic(change_range(
+                min_dist,
+                cparams.safety_margin_low,
+                cparams.safety_margin_high,
+                cparams.logistic_map_low,
+                cparams.logistic_map_high,
+            ));
+        if road.debug && penalty > 10.0 {
+            eprintln!(
+                "{}: safety distance: {:.2} -> penalty {:.2}",
+                road.timesteps, min_dist, penalty
+            );
+        }
+        penalty
+    }
+}
+
+// for time-to-collision and time headway with the cars ahead and behind,
+// when ttc_weight or headway_weight are set
+pub struct TimeGapTerm;
+
+impl CostTerm for TimeGapTerm {
+    fn name(&self) -> &'static str {
+        "time_gap"
+    }
+
+    fn component(&self) -> &'static str {
+        "safety"
+    }
+
+    fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+        let cparams = &road.params.cost;
+        if cparams.ttc_weight.is_none() && cparams.headway_weight.is_none() {
+            return 0.0;
+        }
+        let penalty = road.time_gap_cost();
+        if road.debug && penalty > 10.0 {
+            eprintln!(
+                "{}: time-to-collision/headway penalty {:.2}",
+                road.timesteps, penalty
+            );
+        }
+        penalty
+    }
+}
+
+// for staying in a lane that is about to end
+pub struct DeadlineTerm;
+
+impl CostTerm for DeadlineTerm {
+    fn name(&self) -> &'static str {
+        "deadline"
+    }
+
+    fn component(&self) -> &'static str {
+        "deadline"
+    }
+
+    fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+        let car = &road.cars[0];
+        match road.dist_to_lane_end(0, car.current_lane()) {
+            Some(end_dist) => {
+                // the penalty ramps up over the merge zone, so there is time to plan for it
+                let scenario = &road.params.scenario;
+                let merge_zone_length = scenario.lane_end_s - scenario.merge_start_s;
+                let urgency = (1.0 - end_dist / merge_zone_length).clamp(0.0, 1.0);
+                road.params.cost.deadline_weight * urgency
+            }
+            None => 0.0,
+        }
+    }
+}
+
+// squared longitudinal acceleration
+pub struct AccelTerm;
+
+impl CostTerm for AccelTerm {
+    fn name(&self) -> &'static str {
+        "accel"
+    }
+
+    fn component(&self) -> &'static str {
+        "accel"
+    }
+
+    fn cost_rate(&self, road: &Road, dt: f64) -> f64 {
+        road.params.cost.accel_weight * road.ego_accel(dt).powi(2)
+    }
+}
+
+// squared angular acceleration, which is how "steer" has always been measured
+pub struct SteerTerm;
+
+impl CostTerm for SteerTerm {
+    fn name(&self) -> &'static str {
+        "steer"
+    }
+
+    fn component(&self) -> &'static str {
+        "steer"
+    }
+
+    fn cost_rate(&self, road: &Road, dt: f64) -> f64 {
+        let theta_accel = (road.cars[0].theta() - road.last_ego.theta()) / dt;
+        road.params.cost.steer_weight * theta_accel.powi(2)
+    }
+}
+
+// squared longitudinal jerk, for comfort
+pub struct JerkTerm;
+
+impl CostTerm for JerkTerm {
+    fn name(&self) -> &'static str {
+        "jerk"
+    }
+
+    fn component(&self) -> &'static str {
+        "jerk"
+    }
+
+    fn cost_rate(&self, road: &Road, dt: f64) -> f64 {
+        let jerk = (road.ego_accel(dt) - road.last_ego_accel) / dt;
+        road.params.cost.jerk_weight * jerk.powi(2)
+    }
+}
+
+// for each lane away from preferred_lane
+pub struct LanePreferenceTerm;
+
+impl CostTerm for LanePreferenceTerm {
+    fn name(&self) -> &'static str {
+        "lane_preference"
+    }
+
+    fn component(&self) -> &'static str {
+        "lane_preference"
+    }
+
+    fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+        let cparams = &road.params.cost;
+        let lanes_away = (road.cars[0].current_lane() - cparams.preferred_lane).abs();
+        cparams.lane_preference_weight * lanes_away as f64
+    }
+}
+
+// for breaking the rules of the road, which for now is the speed limit
+pub struct RuleComplianceTerm;
+
+impl CostTerm for RuleComplianceTerm {
+    fn name(&self) -> &'static str {
+        "rule_compliance"
+    }
+
+    fn component(&self) -> &'static str {
+        "rule_compliance"
+    }
+
+    fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+        let cparams = &road.params.cost;
+        let over_limit = (road.cars[0].vel - cparams.speed_limit).max(0.0);
+        cparams.rule_compliance_weight * over_limit.powi(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameters_use_the_standard_terms() {
+        let params = crate::arg_parameters::Parameters::new().unwrap();
+        assert_eq!(params.cost.terms, STANDARD_COST_TERMS);
+        for name in STANDARD_COST_TERMS.iter() {
+            assert_eq!(params.cost_term_registry.get(name).0.name(), *name);
+        }
+    }
+
+    struct ComfortTerm;
+
+    impl CostTerm for ComfortTerm {
+        fn name(&self) -> &'static str {
+            "comfort"
+        }
+
+        fn component(&self) -> &'static str {
+            "comfort"
+        }
+
+        fn cost_rate(&self, road: &Road, _dt: f64) -> f64 {
+            road.cars[0].vel
+        }
+    }
+
+    #[test]
+    fn registered_terms_get_their_own_component() {
+        let mut registry = CostTermRegistry::default();
+        registry.register(ComfortTerm);
+        let (term, component) = registry.get("comfort");
+        assert_eq!(term.name(), "comfort");
+        assert_eq!(registry.component("comfort"), Some(component));
+        // the built-in terms share the original components
+        assert_eq!(registry.get("time_gap").1, CostComponent::SAFETY);
+        // and other registries don't see it
+        assert_eq!(CostTermRegistry::default().component("comfort"), None);
+
+        let mut cost = Cost::ZERO;
+        cost[component] = 2.0;
+        let named = registry.named_cost(&cost);
+        assert_eq!(named.components["comfort"], 2.0);
+        let json = serde_json::to_string(&named).unwrap();
+        let restored = registry.cost_from_named(&serde_json::from_str(&json).unwrap());
+        assert!(restored == cost);
+        assert_eq!(restored.total(), 2.0);
+    }
+}
@@ -0,0 +1,88 @@
+use progressive_mcts::cost_set::effective_sample_size;
+
+// 95% confidence
+const Z: f64 = 1.96;
+
+// The (importance-weighted) fraction of forward simulations in which the ego car crashed,
+// as an estimate of the collision probability of a policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CrashProbability {
+    crashed_weight: f64,
+    sum_weights: f64,
+    sum_sq_weights: f64,
+}
+
+impl CrashProbability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, crashed: bool, weight: f64) {
+        if crashed {
+            self.crashed_weight += weight;
+        }
+        self.sum_weights += weight;
+        self.sum_sq_weights += weight * weight;
+    }
+
+    pub fn probability(&self) -> f64 {
+        if self.sum_weights > 0.0 {
+            self.crashed_weight / self.sum_weights
+        } else {
+            0.0
+        }
+    }
+
+    // The Wilson score interval, which stays sensible with few or no crashes,
+    // over the effective sample size.
+    pub fn interval(&self) -> (f64, f64) {
+        let n = effective_sample_size(self.sum_weights, self.sum_sq_weights);
+        if n == 0.0 {
+            return (0.0, 1.0);
+        }
+        let p = self.probability();
+        let z2 = Z * Z;
+        let denom = 1.0 + z2 / n;
+        let center = (p + z2 / (2.0 * n)) / denom;
+        let half_width = Z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / denom;
+        (
+            (center - half_width).max(0.0),
+            (center + half_width).min(1.0),
+        )
+    }
+}
+
+impl std::fmt::Display for CrashProbability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (low, high) = self.interval();
+        write!(f, "{:.3} [{:.3}, {:.3}]", self.probability(), low, high)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_contains_the_estimate() {
+        let mut crash = CrashProbability::new();
+        assert_eq!(crash.interval(), (0.0, 1.0));
+
+        for i in 0..20 {
+            crash.push(i % 4 == 0, 1.0);
+        }
+        assert!((crash.probability() - 0.25).abs() < 1e-12);
+        let (low, high) = crash.interval();
+        assert!(low < 0.25 && 0.25 < high);
+        assert!(low > 0.0 && high < 0.5);
+
+        // no crashes still leaves some doubt
+        let mut no_crash = CrashProbability::new();
+        for _ in 0..20 {
+            no_crash.push(false, 1.0);
+        }
+        let (low, high) = no_crash.interval();
+        assert!(low < 1e-9);
+        assert!(high > 0.0 && high < 0.2);
+    }
+}
@@ -1,11 +1,12 @@
 use parry2d_f64::na::Point2;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     road::Road,
     side_policies::{SidePolicy, SidePolicyTrait},
 };
 
-#[derive(Clone, PartialEq, PartialOrd)]
+#[derive(Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct DelayedPolicy {
     policy_a: Box<SidePolicy>,
     policy_b: Box<SidePolicy>,
@@ -40,6 +41,15 @@ impl DelayedPolicy {
         }
     }
 
+    // Chains the policies into one that follows each for delay_time, until the last.
+    pub fn chain(mut policies: Vec<SidePolicy>, delay_time: f64) -> SidePolicy {
+        let mut chained = policies.pop().expect("cannot chain zero policies");
+        while let Some(policy) = policies.pop() {
+            chained = SidePolicy::DelayedPolicy(Self::new(policy, chained, delay_time));
+        }
+        chained
+    }
+
     fn check_for_switch(&mut self, road: &Road, dt: f64) {
         let start_time = *self.start_time.get_or_insert(road.t);
         self.time_until_switch = (start_time + self.delay_time - road.t).max(0.0);
@@ -52,6 +62,10 @@ impl DelayedPolicy {
 impl SidePolicyTrait for DelayedPolicy {
     fn precheck(&mut self, road: &Road, dt: f64) {
         self.check_for_switch(road, dt);
+        // a chained policy_b only starts its own delay once it takes over
+        if self.has_switched {
+            self.policy_b.precheck(road, dt);
+        }
     }
 
     fn choose_target_lane(&mut self, road: &Road, car_i: usize) -> i32 {
@@ -116,3 +130,31 @@ impl SidePolicyTrait for DelayedPolicy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{arg_parameters::Parameters, mpdm::make_policy_choices};
+
+    #[test]
+    fn chain_follows_each_policy_in_turn() {
+        let mut params = Parameters::new().unwrap();
+        params.run_fast = true;
+        let params = Arc::new(params);
+        let choices = make_policy_choices(&params);
+
+        let mut road = Road::new(params.clone());
+        let sequence = vec![choices[0].clone(), choices[2].clone(), choices[4].clone()];
+        road.set_ego_policy(DelayedPolicy::chain(sequence, 1.0));
+
+        let mut operating_ids = Vec::new();
+        for _ in 0..3 {
+            road.take_update_steps(0.5, params.physics_dt);
+            operating_ids.push(road.ego_policy().operating_policy().policy_id());
+            road.take_update_steps(0.5, params.physics_dt);
+        }
+        assert_eq!(operating_ids, vec![0, 2, 4]);
+    }
+}
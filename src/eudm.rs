@@ -1,14 +1,15 @@
-use rand::prelude::StdRng;
+use rand_chacha::ChaCha12Rng;
 
 use crate::{
     arg_parameters::Parameters,
-    cost::Cost,
     delayed_policy::DelayedPolicy,
     mpdm::make_policy_choices,
+    planner::{Plan, Planner},
     road::Road,
     road_set::RoadSet,
     road_set_for_scenario,
     side_policies::{SidePolicy, SidePolicyTrait},
+    trace::Trace,
 };
 
 fn dcp_tree_search(
@@ -16,7 +17,7 @@ fn dcp_tree_search(
     policy_choices: &[SidePolicy],
     roads: RoadSet,
     debug: bool,
-) -> (Option<SidePolicy>, Vec<rvx::Shape>) {
+) -> (Option<SidePolicy>, Vec<Trace>) {
     let mut traces = Vec::new();
 
     let unchanged_policy = roads.ego_policy();
@@ -33,13 +34,13 @@ fn dcp_tree_search(
 
     let max_car_traces_depth = 3;
 
+    let safety_constraint = params.cost.safety_constraint;
     let mut best_sub_policy = None;
     let mut best_switch_depth = 0;
-    let mut best_cost = Cost::max_value();
 
     // Let's first consider the ongoing policy, which may be mid-way through a transition
     // unlike everything else we will consider, which won't transition policies for at least some period
-    {
+    let mut best_cost = {
         let mut ongoing_roads = roads.clone();
         for depth_level in 0..eudm.search_depth {
             if depth_level < max_car_traces_depth {
@@ -54,15 +55,13 @@ fn dcp_tree_search(
         if debug {
             let unchanged_policy_id = unchanged_policy.policy_id();
             eprintln_f!(
-                "Unchanged: {unchanged_policy_id}: {cost:7.2?} = {:7.2}, {unchanged_policy:?}",
-                cost.total()
+                "Unchanged: {unchanged_policy_id}: {cost:7.2?} = {:7.2}, crash: {}, {unchanged_policy:?}",
+                cost.total(),
+                ongoing_roads.crash_probability()
             );
         }
-        if cost < best_cost {
-            best_cost = cost;
-            best_sub_policy = None;
-        }
-    }
+        cost
+    };
 
     // this copy of the roads will be advanced by layer_t each time through the loop
     // to avoid doing duplicate work.
@@ -90,15 +89,16 @@ fn dcp_tree_search(
         if switch_depth == eudm.search_depth {
             if debug {
                 eprintln_f!(
-                    "switch time: {}, {operating_policy:?}: {:7.2?} = {:7.2}",
+                    "switch time: {}, {operating_policy:?}: {:7.2?} = {:7.2}, crash: {}",
                     switch_depth as f64 * eudm.layer_t,
                     init_policy_roads.cost(),
-                    init_policy_roads.cost().total()
+                    init_policy_roads.cost().total(),
+                    init_policy_roads.crash_probability()
                 );
             }
 
             let cost = init_policy_roads.cost();
-            if cost < best_cost {
+            if cost.is_better_choice(&best_cost, safety_constraint) {
                 best_cost = cost;
                 best_switch_depth = switch_depth;
                 best_sub_policy = Some(&operating_policy);
@@ -123,15 +123,16 @@ fn dcp_tree_search(
 
                 if debug {
                     eprintln_f!(
-                        "switch time: {}, to {i}: {sub_policy:?}: {:7.2?} = {:7.2}",
+                        "switch time: {}, to {i}: {sub_policy:?}: {:7.2?} = {:7.2}, crash: {}",
                         switch_depth as f64 * eudm.layer_t,
                         roads.cost(),
-                        roads.cost().total()
+                        roads.cost().total(),
+                        roads.crash_probability()
                     );
                 }
 
                 let cost = roads.cost();
-                if cost < best_cost {
+                if cost.is_better_choice(&best_cost, safety_constraint) {
                     best_cost = cost;
                     best_switch_depth = switch_depth;
                     best_sub_policy = Some(sub_policy);
@@ -167,8 +168,8 @@ fn dcp_tree_search(
 pub fn dcp_tree_choose_policy(
     params: &Parameters,
     true_road: &Road,
-    rng: &mut StdRng,
-) -> (Option<SidePolicy>, Vec<rvx::Shape>) {
+    rng: &mut ChaCha12Rng,
+) -> (Option<SidePolicy>, Vec<Trace>) {
     let roads = road_set_for_scenario(params, true_road, rng, params.eudm.samples_n);
     let debug = params.policy_report_debug
         && true_road.debug
@@ -176,3 +177,41 @@ pub fn dcp_tree_choose_policy(
     let policy_choices = make_policy_choices(params);
     dcp_tree_search(params, &policy_choices, roads, debug)
 }
+
+pub struct EudmPlanner;
+
+impl Planner for EudmPlanner {
+    fn name(&self) -> &'static str {
+        "eudm"
+    }
+
+    fn new_run(&self) -> Box<dyn Planner> {
+        Box::new(EudmPlanner)
+    }
+
+    fn choose_policy(&mut self, params: &Parameters, road: &Road, rng: &mut ChaCha12Rng) -> Plan {
+        dcp_tree_choose_policy(params, road, rng).into()
+    }
+
+    fn scenario_name(&self, params: &Parameters) -> String {
+        let p = &params.eudm;
+        format_f!(
+            ",samples_n={p.samples_n}\
+             ,search_depth={p.search_depth}\
+             ,layer_t={p.layer_t}\
+             ,allow_different_root_policy={p.allow_different_root_policy}"
+        )
+    }
+
+    fn set_param(&self, params: &mut Parameters, name: &str, val: &str) -> bool {
+        let p = &mut params.eudm;
+        match name {
+            "samples_n" => p.samples_n = val.parse().unwrap(),
+            "search_depth" => p.search_depth = val.parse().unwrap(),
+            "layer_t" => p.layer_t = val.parse().unwrap(),
+            "allow_different_root_policy" => p.allow_different_root_policy = val.parse().unwrap(),
+            _ => return false,
+        }
+        true
+    }
+}
@@ -1,9 +1,11 @@
+use serde::{Deserialize, Serialize};
+
 use crate::intelligent_driver::IntelligentDriverPolicy;
 use crate::open_loop_policy::OpenLoopForwardControl;
 use crate::Road;
 
 #[enum_dispatch]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ForwardControl {
     IntelligentDriverPolicy,
     OpenLoopForwardControl,
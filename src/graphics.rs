@@ -0,0 +1,338 @@
+use std::time::Duration;
+
+use nalgebra::Matrix3;
+use rvx::{Rvx, RvxColor};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BaseColor {
+    White,
+    Black,
+    Gray,
+    DarkGray,
+    LightGray,
+    Red,
+    Green,
+    Blue,
+    Pink,
+    Orange,
+    Yellow,
+}
+
+// Mirrors RvxColor, but with its components available to other backends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    base: BaseColor,
+    rgb_scale: f64,
+    a: Option<f64>,
+}
+
+impl Color {
+    pub const WHITE: Self = Self::new(BaseColor::White);
+    pub const BLACK: Self = Self::new(BaseColor::Black);
+    pub const GRAY: Self = Self::new(BaseColor::Gray);
+    pub const DARK_GRAY: Self = Self::new(BaseColor::DarkGray);
+    pub const LIGHT_GRAY: Self = Self::new(BaseColor::LightGray);
+    pub const RED: Self = Self::new(BaseColor::Red);
+    pub const GREEN: Self = Self::new(BaseColor::Green);
+    pub const BLUE: Self = Self::new(BaseColor::Blue);
+    pub const PINK: Self = Self::new(BaseColor::Pink);
+    pub const ORANGE: Self = Self::new(BaseColor::Orange);
+    pub const YELLOW: Self = Self::new(BaseColor::Yellow);
+
+    const fn new(base: BaseColor) -> Self {
+        Self {
+            base,
+            rgb_scale: 1.0,
+            a: None,
+        }
+    }
+
+    pub fn set_a(mut self, a: f64) -> Self {
+        self.a = Some(a);
+        self
+    }
+
+    pub fn scale_rgb(mut self, scale: f64) -> Self {
+        self.rgb_scale *= scale;
+        self
+    }
+
+    pub fn rgba(&self) -> [f64; 4] {
+        let [r, g, b] = match self.base {
+            BaseColor::White => [1.0, 1.0, 1.0],
+            BaseColor::Black => [0.0, 0.0, 0.0],
+            BaseColor::Gray => [0.5, 0.5, 0.5],
+            BaseColor::DarkGray => [0.25, 0.25, 0.25],
+            BaseColor::LightGray => [0.75, 0.75, 0.75],
+            BaseColor::Red => [1.0, 0.0, 0.0],
+            BaseColor::Green => [0.0, 1.0, 0.0],
+            BaseColor::Blue => [0.0, 0.0, 1.0],
+            BaseColor::Pink => [1.0, 0.75, 0.8],
+            BaseColor::Orange => [1.0, 0.65, 0.0],
+            BaseColor::Yellow => [1.0, 1.0, 0.0],
+        };
+        let scale = self.rgb_scale;
+        [r * scale, g * scale, b * scale, self.a.unwrap_or(1.0)]
+    }
+
+    fn to_rvx(self) -> RvxColor {
+        let mut color = match self.base {
+            BaseColor::White => RvxColor::WHITE,
+            BaseColor::Black => RvxColor::BLACK,
+            BaseColor::Gray => RvxColor::GRAY,
+            BaseColor::DarkGray => RvxColor::DARK_GRAY,
+            BaseColor::LightGray => RvxColor::LIGHT_GRAY,
+            BaseColor::Red => RvxColor::RED,
+            BaseColor::Green => RvxColor::GREEN,
+            BaseColor::Blue => RvxColor::BLUE,
+            BaseColor::Pink => RvxColor::PINK,
+            BaseColor::Orange => RvxColor::ORANGE,
+            BaseColor::Yellow => RvxColor::YELLOW,
+        };
+        if self.rgb_scale != 1.0 {
+            color = color.scale_rgb(self.rgb_scale);
+        }
+        if let Some(a) = self.a {
+            color = color.set_a(a);
+        }
+        color
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ShapeKind {
+    // unit square and unit-diameter circle, centered on the origin
+    Square,
+    Circle,
+    Text {
+        text: String,
+        font: String,
+        size: f64,
+    },
+    // x, y pairs, with the width in pixels
+    Lines {
+        points: Vec<f64>,
+        width: f64,
+    },
+    // a copy of the shape at each of the x, y pairs
+    Array {
+        shape: Box<Shape>,
+        points: Vec<f64>,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Transform {
+    Scale(f64, f64),
+    Rot(f64),
+    Translate(f64, f64),
+}
+
+impl Transform {
+    fn matrix(&self) -> Matrix3<f64> {
+        match *self {
+            Transform::Scale(x, y) => Matrix3::new(x, 0.0, 0.0, 0.0, y, 0.0, 0.0, 0.0, 1.0),
+            Transform::Rot(theta) => {
+                let (sin, cos) = theta.sin_cos();
+                Matrix3::new(cos, -sin, 0.0, sin, cos, 0.0, 0.0, 0.0, 1.0)
+            }
+            Transform::Translate(x, y) => Matrix3::new(1.0, 0.0, x, 0.0, 1.0, y, 0.0, 0.0, 1.0),
+        }
+    }
+}
+
+// A backend-independent version of rvx::Shape, built up the same way,
+// with transforms applied in the order they are added.
+#[derive(Clone, Debug)]
+pub struct Shape {
+    pub kind: ShapeKind,
+    pub transforms: Vec<Transform>,
+    pub color: Option<Color>,
+}
+
+impl Shape {
+    fn new(kind: ShapeKind) -> Self {
+        Self {
+            kind,
+            transforms: Vec::new(),
+            color: None,
+        }
+    }
+
+    pub fn square() -> Self {
+        Self::new(ShapeKind::Square)
+    }
+
+    pub fn circle() -> Self {
+        Self::new(ShapeKind::Circle)
+    }
+
+    pub fn text(text: &str, font: &str, size: f64) -> Self {
+        Self::new(ShapeKind::Text {
+            text: text.to_owned(),
+            font: font.to_owned(),
+            size,
+        })
+    }
+
+    pub fn lines(points: &[f64], width: f64) -> Self {
+        Self::new(ShapeKind::Lines {
+            points: points.to_vec(),
+            width,
+        })
+    }
+
+    pub fn line(points: [f64; 4], width: f64) -> Self {
+        Self::lines(&points, width)
+    }
+
+    pub fn array(shape: Shape, points: &[f64]) -> Self {
+        Self::new(ShapeKind::Array {
+            shape: Box::new(shape),
+            points: points.to_vec(),
+        })
+    }
+
+    pub fn scale(self, scale: f64) -> Self {
+        self.scale_xy(&[scale, scale])
+    }
+
+    pub fn scale_xy(mut self, scale: &[f64]) -> Self {
+        self.transforms.push(Transform::Scale(scale[0], scale[1]));
+        self
+    }
+
+    pub fn rot(mut self, theta: f64) -> Self {
+        self.transforms.push(Transform::Rot(theta));
+        self
+    }
+
+    pub fn translate(mut self, offset: &[f64]) -> Self {
+        self.transforms
+            .push(Transform::Translate(offset[0], offset[1]));
+        self
+    }
+
+    pub fn color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn matrix(&self) -> Matrix3<f64> {
+        self.transforms
+            .iter()
+            .fold(Matrix3::identity(), |m, t| t.matrix() * m)
+    }
+
+    fn to_rvx(&self) -> rvx::Shape {
+        let mut shape = match &self.kind {
+            ShapeKind::Square => Rvx::square(),
+            ShapeKind::Circle => Rvx::circle(),
+            ShapeKind::Text { text, font, size } => Rvx::text(text, font, *size),
+            ShapeKind::Lines { points, width } => Rvx::lines(points, *width),
+            ShapeKind::Array { shape, points } => Rvx::array(shape.to_rvx(), points),
+        };
+        for t in self.transforms.iter() {
+            shape = match *t {
+                Transform::Scale(x, y) => shape.scale_xy(&[x, y]),
+                Transform::Rot(theta) => shape.rot(theta),
+                Transform::Translate(x, y) => shape.translate(&[x, y]),
+            };
+        }
+        if let Some(color) = self.color {
+            shape = shape.color(color.to_rvx());
+        }
+        shape
+    }
+}
+
+// Everything drawn for one frame, which a backend then displays or saves.
+#[derive(Clone, Debug, Default)]
+pub struct Canvas {
+    shapes: Vec<Shape>,
+    translate_modifier: (f64, f64),
+    global_rot: f64,
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // shapes drawn from here on are moved by the translate modifier
+    pub fn draw(&mut self, shape: Shape) {
+        let (x, y) = self.translate_modifier;
+        if x != 0.0 || y != 0.0 {
+            self.shapes.push(shape.translate(&[x, y]));
+        } else {
+            self.shapes.push(shape);
+        }
+    }
+
+    pub fn draw_all(&mut self, shapes: impl IntoIterator<Item = Shape>) {
+        for shape in shapes {
+            self.draw(shape);
+        }
+    }
+
+    pub fn set_translate_modifier(&mut self, x: f64, y: f64) {
+        self.translate_modifier = (x, y);
+    }
+
+    // rotates the whole view
+    pub fn set_global_rot(&mut self, rot: f64) {
+        self.global_rot = rot;
+    }
+
+    pub fn global_rot(&self) -> f64 {
+        self.global_rot
+    }
+
+    pub fn shapes(&self) -> &[Shape] {
+        &self.shapes
+    }
+}
+
+// a live window showing canvases as they are drawn
+pub struct RvxWindow {
+    r: Rvx,
+}
+
+impl RvxWindow {
+    pub fn new() -> Self {
+        let mut r = Rvx::new("Self-Driving!", [0, 0, 0, 0], 8000);
+        // r.set_user_zoom(Some(0.4)); // 0.22
+        std::thread::sleep(Duration::from_millis(500));
+        r.set_user_zoom(None);
+        Self { r }
+    }
+
+    pub fn show(&mut self, canvas: &Canvas) {
+        self.r.clear();
+        self.r.draw_all(canvas.shapes().iter().map(|s| s.to_rvx()));
+        self.r.set_global_rot(canvas.global_rot());
+        self.r.commit_changes();
+    }
+}
+
+impl Default for RvxWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_apply_in_order() {
+        let shape = Shape::square()
+            .scale_xy(&[4.0, 2.0])
+            .rot(std::f64::consts::PI / 2.0)
+            .translate(&[10.0, 0.0]);
+        let corner = shape.matrix() * nalgebra::Vector3::new(0.5, 0.5, 1.0);
+        assert!((corner.x - 9.0).abs() < 1e-9);
+        assert!((corner.y - 2.0).abs() < 1e-9);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::{car::BREAKING_ACCEL, forward_control::ForwardControlTrait, Road};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+use crate::{
+    car::{Car, BREAKING_ACCEL},
+    forward_control::ForwardControlTrait,
+    Road,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct IntelligentDriverPolicy;
 
 impl IntelligentDriverPolicy {
@@ -9,6 +15,11 @@ impl IntelligentDriverPolicy {
     }
 }
 
+fn spacing_term(car: &Car, approaching_rate: f64) -> f64 {
+    car.follow_dist()
+        + car.vel * approaching_rate / (2.0 * (car.preferred_accel * BREAKING_ACCEL).sqrt())
+}
+
 // https://en.wikipedia.org/wiki/Intelligent_driver_model
 impl ForwardControlTrait for IntelligentDriverPolicy {
     fn choose_accel(&mut self, road: &Road, car_i: usize) -> f64 {
@@ -43,9 +54,7 @@ impl ForwardControlTrait for IntelligentDriverPolicy {
             let approaching_rate = car.vel - road.cars[c_i].vel;
 
             let follow_dist = car.follow_dist();
-            let spacing_term = follow_dist
-                + car.vel * approaching_rate
-                    / (2.0 * (car.preferred_accel * BREAKING_ACCEL).sqrt());
+            let spacing_term = spacing_term(car, approaching_rate);
             let accel_interaction = car.preferred_accel * (-(spacing_term / forward_dist).powi(2));
 
             accel = accel_free_road + accel_interaction;
@@ -67,6 +76,14 @@ impl ForwardControlTrait for IntelligentDriverPolicy {
             }
         }
 
+        // the end of the lane acts like a stopped car
+        if let Some(end_dist) = road.dist_to_lane_end(car_i, car.target_lane_i) {
+            let spacing_term = spacing_term(car, car.vel);
+            let end_accel =
+                accel_free_road + car.preferred_accel * (-(spacing_term / end_dist).powi(2));
+            return accel.min(end_accel);
+        }
+
         accel
     }
 }
@@ -1,5 +1,6 @@
 use nalgebra::point;
 use parry2d_f64::na::Point2;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     car::{PREFERRED_VEL_ESTIMATE_MIN, PRIUS_LENGTH},
@@ -11,14 +12,17 @@ use crate::{
 const TRANSITION_DIST_MIN: f64 = 1.0 * PRIUS_LENGTH;
 const TRANSITION_DIST_MAX: f64 = 100.0 * PRIUS_LENGTH;
 
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum LongitudinalPolicy {
     Maintain,
     Accelerate,
     Decelerate,
+    // settle at the starting velocity plus this change
+    VelChange(f64),
 }
 
-#[derive(Clone, PartialEq, PartialOrd)]
+#[derive(Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct LaneChangePolicy {
     policy_id: u32,
     target_lane_i: Option<i32>,
@@ -56,58 +60,78 @@ impl LaneChangePolicy {
         }
     }
 
+    pub fn target_lane_i(&self) -> Option<i32> {
+        self.target_lane_i
+    }
+
+    pub fn long_policy(&self) -> LongitudinalPolicy {
+        self.long_policy
+    }
+
     fn lane_change_trajectory(&mut self, road: &Road, car_i: usize, traj: &mut Vec<Point2<f64>>) {
         let car = &road.cars[car_i];
 
-        let total_transition_dist = (self.transition_time * car.vel)
-            .max(TRANSITION_DIST_MIN)
-            .min(TRANSITION_DIST_MAX);
+        let total_transition_dist =
+            (self.transition_time * car.vel).clamp(TRANSITION_DIST_MIN, TRANSITION_DIST_MAX);
 
-        let target_y = Road::get_lane_y(self.target_lane_i.unwrap_or_else(|| car.current_lane()));
+        let target_d = Road::get_lane_y(self.target_lane_i.unwrap_or_else(|| car.current_lane()));
 
-        let transition_left = (car.y() - target_y).abs() / LANE_WIDTH;
+        let transition_left = (car.d() - target_d).abs() / LANE_WIDTH;
         let transition_dist = total_transition_dist * transition_left;
 
-        let target_x = car.x() + transition_dist;
+        let target_s = car.s() + transition_dist;
         // let progress = (road.t - start_time) / self.transition_time;
 
+        // laid out in road coordinates, so the points follow any curves
         traj.clear();
-        traj.extend_from_slice(&[
-            point!(car.x(), car.y()),
-            point!(target_x, target_y),
-            point!(target_x + 100.0, target_y), // then continue straight
-        ]);
+        traj.push(point!(car.x(), car.y()));
+        let path = &road.path;
+        path.append_frenet_line(traj, (car.s(), car.d()), (target_s, target_d));
+        // then continue along the lane
+        path.append_frenet_line(traj, (target_s, target_d), (target_s + 100.0, target_d));
     }
 
     fn lane_keep_trajectory(&mut self, road: &Road, car_i: usize, traj: &mut Vec<Point2<f64>>) {
         let car = &road.cars[car_i];
         let lane_i = car.current_lane();
 
-        let transition_dist = (self.transition_time * car.vel)
-            .max(TRANSITION_DIST_MIN)
-            .min(TRANSITION_DIST_MAX);
+        let transition_dist =
+            (self.transition_time * car.vel).clamp(TRANSITION_DIST_MIN, TRANSITION_DIST_MAX);
+
+        let lane_d = Road::get_lane_y(lane_i);
 
         traj.clear();
-        traj.extend_from_slice(&[
-            point!(car.x(), car.y()),
-            point!(car.x() + transition_dist, Road::get_lane_y(lane_i)),
-            point!(car.x() + 100.0, Road::get_lane_y(lane_i)),
-        ]);
+        traj.push(point!(car.x(), car.y()));
+        let path = &road.path;
+        path.append_frenet_line(
+            traj,
+            (car.s(), car.d()),
+            (car.s() + transition_dist, lane_d),
+        );
+        path.append_frenet_line(
+            traj,
+            (car.s() + transition_dist, lane_d),
+            (car.s() + 100.0, lane_d),
+        );
     }
 }
 
 impl SidePolicyTrait for LaneChangePolicy {
     fn choose_target_lane(&mut self, road: &Road, car_i: usize) -> i32 {
+        let current_lane = road.cars[car_i].current_lane();
         if self.wait_for_clear && !self.waiting_done {
-            return road.cars[car_i].current_lane();
+            return current_lane;
         }
-        self.target_lane_i
-            .unwrap_or_else(|| road.cars[car_i].current_lane())
+        let target_lane_i = self.target_lane_i.unwrap_or(current_lane);
+        if !road.lane_change_allowed(car_i, target_lane_i) {
+            return current_lane;
+        }
+        target_lane_i
     }
 
     fn choose_follow_time(&mut self, _road: &Road, _car_i: usize) -> f64 {
         match self.long_policy {
-            LongitudinalPolicy::Maintain => 0.6,
+            LongitudinalPolicy::Maintain | LongitudinalPolicy::VelChange(_) => 0.6,
             LongitudinalPolicy::Accelerate => 0.2,
             LongitudinalPolicy::Decelerate => 1.0,
         }
@@ -122,19 +146,27 @@ impl SidePolicyTrait for LaneChangePolicy {
                 .max(PREFERRED_VEL_ESTIMATE_MIN),
             LongitudinalPolicy::Accelerate => (car.vel + 10.0).max(PREFERRED_VEL_ESTIMATE_MIN),
             LongitudinalPolicy::Decelerate => (car.vel - 10.0).max(0.0),
+            LongitudinalPolicy::VelChange(change) => {
+                (*self.start_vel.get_or_insert(car.vel) + change).max(0.0)
+            }
         };
 
         target_vel
     }
 
     fn choose_trajectory(&mut self, road: &Road, car_i: usize, traj: &mut Vec<Point2<f64>>) {
+        let car = &road.cars[car_i];
+        // nothing to wait for while the target lane can't be reached at all
+        let target_lane_i = self.target_lane_i.unwrap_or_else(|| car.current_lane());
+        if !road.lane_change_allowed(car_i, target_lane_i) {
+            return self.lane_keep_trajectory(road, car_i, traj);
+        }
         if self.wait_for_clear && !self.waiting_done {
-            let car = &road.cars[car_i];
             self.waiting_done = road.lane_definitely_clear_between(
                 car_i,
                 self.target_lane_i.unwrap_or_else(|| car.current_lane()),
-                car.x() - 0.5 * car.length - car.length,
-                car.x() + 0.5 * car.length,
+                car.s() - 0.5 * car.length - car.length,
+                car.s() + 0.5 * car.length,
             );
         }
         if self.waiting_done || !self.wait_for_clear {
@@ -0,0 +1,62 @@
+// `x % n == 0` over is_multiple_of(), which would need Rust 1.87
+#![allow(unknown_lints, clippy::manual_is_multiple_of)]
+
+use arg_parameters::Parameters;
+use cfb::conditional_focused_branching;
+use rand_chacha::ChaCha12Rng;
+use road::Road;
+use road_set::RoadSet;
+
+#[macro_use]
+extern crate fstrings;
+
+pub mod arg_parameters;
+pub mod belief;
+pub mod car;
+pub mod cfb;
+pub mod cost;
+pub mod cost_term;
+pub mod crash_probability;
+pub mod delayed_policy;
+pub mod eudm;
+pub mod forward_control;
+pub mod graphics;
+pub mod intelligent_driver;
+pub mod lane_change_policy;
+pub mod mcts;
+pub mod mpdm;
+pub mod open_loop_policy;
+pub mod planner;
+pub mod pure_pursuit;
+pub mod rate_timer;
+pub mod recording;
+pub mod reference_path;
+pub mod reward;
+pub mod road;
+pub mod road_set;
+pub mod scenario;
+pub mod side_control;
+pub mod side_policies;
+pub mod simulation;
+pub mod svg;
+pub mod trace;
+pub mod traffic;
+
+#[macro_use]
+extern crate enum_dispatch;
+
+const AHEAD_TIME_DEFAULT: f64 = 0.6;
+
+pub fn road_set_for_scenario(
+    params: &Parameters,
+    true_road: &Road,
+    rng: &mut ChaCha12Rng,
+    n: usize,
+) -> RoadSet {
+    if params.use_cfb {
+        let (base_set, _selected_ids) = conditional_focused_branching(params, true_road, n);
+        base_set
+    } else {
+        RoadSet::new_samples(true_road, rng, n)
+    }
+}
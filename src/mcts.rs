@@ -1,226 +1,534 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
 use itertools::Itertools;
 use progressive_mcts::{
-    cost_set::CostSet, klucb::klucb_bernoulli, ChildSelectionMode, CostBoundMode,
+    cost_set::{effective_sample_size, CostSet},
+    engine::{self, MctsParams, Simulator},
+    ChildSelectionMode, CostBoundMode, EarlyStopMode,
+};
+use rand::{
+    distributions::WeightedIndex,
+    prelude::{Distribution, SliceRandom},
+    Rng, SeedableRng,
 };
-use rand::prelude::{SliceRandom, StdRng};
+use rand_chacha::ChaCha12Rng;
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    arg_parameters::{MctsParameters, Parameters},
+    arg_parameters::Parameters,
     cost::Cost,
+    crash_probability::CrashProbability,
+    delayed_policy::DelayedPolicy,
+    lane_change_policy::{LaneChangePolicy, LongitudinalPolicy},
     mpdm::make_policy_choices,
+    planner::{Plan, Planner},
     road::{Particle, Road},
     road_set_for_scenario,
     side_policies::{SidePolicy, SidePolicyTrait},
+    trace::Trace,
 };
 
-fn compute_selection_index(
-    mctsp: &MctsParameters,
-    total_n: f64,
-    ln_total_n: f64,
-    n_trials: usize,
-    cost: f64,
-    mode: ChildSelectionMode,
-) -> Option<f64> {
-    if n_trials == 0 {
-        return None;
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyChainMode {
+    // only the first policy of the best sequence is used
+    FirstOnly,
+    // the whole best sequence is chained together, but still replaced every replan
+    Receding,
+    // the chained sequence is followed to its end before replanning
+    Committed,
+}
+
+impl std::fmt::Display for PolicyChainMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FirstOnly => write!(f, "first_only"),
+            Self::Receding => write!(f, "receding"),
+            Self::Committed => write!(f, "committed"),
+        }
     }
+}
+
+impl std::str::FromStr for PolicyChainMode {
+    type Err = String;
 
-    let mean_cost = cost;
-    let n = n_trials as f64;
-    let ln_t_over_n = ln_total_n / n;
-    let index = match mode {
-        ChildSelectionMode::UCB => {
-            let upper_margin = mctsp.ucb_const * ln_t_over_n.sqrt();
-            assert!(upper_margin.is_finite(), "{}", n);
-            mean_cost + upper_margin
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "first_only" => Ok(Self::FirstOnly),
+            "receding" => Ok(Self::Receding),
+            "committed" => Ok(Self::Committed),
+            _ => Err(format!("Invalid PolicyChainMode '{}'", s)),
         }
-        ChildSelectionMode::KLUCB => {
-            let scaled_mean = (1.0 - mean_cost / mctsp.klucb_max_cost).min(1.0).max(0.0);
-            -klucb_bernoulli(scaled_mean, mctsp.ucb_const.abs() * ln_t_over_n)
+    }
+}
+
+impl MctsParams for Parameters {
+    fn selection_mode(&self) -> ChildSelectionMode {
+        self.mcts.selection_mode
+    }
+
+    fn bound_mode(&self) -> CostBoundMode {
+        self.mcts.bound_mode
+    }
+
+    fn ucb_const(&self) -> f64 {
+        self.mcts.ucb_const
+    }
+
+    fn ucbv_const(&self) -> f64 {
+        self.mcts.ucbv_const
+    }
+
+    fn ucbd_const(&self) -> f64 {
+        self.mcts.ucbd_const
+    }
+
+    fn klucb_max_cost(&self) -> f64 {
+        self.mcts.klucb_max_cost
+    }
+
+    fn cvar_alpha(&self) -> f64 {
+        self.mcts.cvar_alpha
+    }
+}
+
+// each step of a trial follows one ego policy for layer_t
+impl Simulator for Road {
+    type Params = Parameters;
+    type Action = SidePolicy;
+    type Cost = Cost;
+    type Particle = Particle;
+    type Rng = ChaCha12Rng;
+
+    fn take_step(&mut self, params: &Parameters, policy: &SidePolicy, _rng: &mut ChaCha12Rng) {
+        self.set_ego_policy(policy.clone());
+        let mcts = &params.mcts;
+        if mcts.reuse_half_life.is_none() || self.replan_costs.is_some() {
+            self.take_update_steps(mcts.layer_t, mcts.dt);
+            return;
         }
-        ChildSelectionMode::KLUCBP => {
-            let scaled_mean = (1.0 - mean_cost / mctsp.klucb_max_cost).min(1.0).max(0.0);
-            -klucb_bernoulli(scaled_mean, mctsp.ucb_const.abs() * (total_n / n).ln() / n)
+
+        // The first step goes a replan_dt at a time, keeping the cost at each,
+        // so that the next searches can reuse the trial from where they start.
+        let mut replan_costs = Vec::new();
+        let mut t = 0.0;
+        while t + params.replan_dt <= mcts.layer_t {
+            self.take_update_steps(params.replan_dt, mcts.dt);
+            replan_costs.push(self.cost);
+            t += params.replan_dt;
         }
-        ChildSelectionMode::Uniform => n,
-        _ => unimplemented!(),
-    };
-    Some(index)
-}
+        self.take_update_steps(mcts.layer_t - t, mcts.dt);
+        self.replan_costs = Some(replan_costs);
+    }
 
-#[derive(Clone)]
-struct MctsNode<'a> {
-    params: &'a Parameters,
-    policy_choices: &'a [SidePolicy],
-    policy: Option<SidePolicy>,
-    traces: Vec<rvx::Shape>,
+    fn cost(&self) -> Cost {
+        self.cost
+    }
 
-    depth: u32,
-    n_trials: usize,
-    expected_cost: Option<Cost>,
+    fn choice_key(params: &Parameters, cost: &Cost) -> (bool, f64) {
+        cost.choice_key(params.cost.safety_constraint)
+    }
 
-    costs: Vec<(Cost, Particle)>,
-    intermediate_costs: Vec<Cost>,
-    marginal_costs: CostSet<f64, Cost>,
+    fn particle_id(&self) -> usize {
+        self.particle.as_ref().unwrap().id
+    }
 
-    n_particles_repeated: usize,
+    fn weight(&self) -> f64 {
+        self.particle.as_ref().unwrap().weight
+    }
+}
 
-    sub_nodes: Option<Vec<MctsNode<'a>>>,
+// what each node keeps of the trials that went through it
+#[derive(Default)]
+struct NodeData {
+    traces: Vec<Trace>,
+    // With belief updates, the particle set filtered by what the ego would have seen:
+    // the obstacle policies of this node's step, weighted by how much more likely
+    // the belief updated from that step makes them. Deeper steps continue from it.
+    particles: Vec<(Particle, f64)>,
+    // how often trials through this node ended with the ego car crashed
+    crash: CrashProbability,
+    // with tree reuse, the replan costs of each trial through a root child, in order
+    replan_costs: Vec<Vec<Cost>>,
 }
 
-impl<'a> std::fmt::Debug for MctsNode<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = self;
-        write_f!(
-            f,
-            "MctsNode ({s.depth=}, {s.n_trials=}, {s.expected_cost=:?}, "
-        )?;
-        write_f!(
-            f,
-            "#intermediate costs = {}, #marginal costs = {}, ",
-            s.intermediate_costs.len(),
-            s.marginal_costs.len()
-        )
+type MctsNode<'a> = engine::MctsNode<'a, Road, NodeData>;
+
+// Progressive widening: beyond the discrete policy choices, adds a child with
+// a sampled policy whenever the visit count allows for more children.
+fn widen(node: &mut MctsNode, rng: &mut ChaCha12Rng) {
+    let params = node.params;
+    let widening_k = match params.mcts.widening_k {
+        Some(widening_k) => widening_k,
+        None => return,
+    };
+    let max_children = (widening_k * (node.n_trials as f64 + 1.0).powf(params.mcts.widening_alpha))
+        .ceil() as usize;
+
+    let policy_choices = node.policy_choices;
+    let depth = node.depth + 1;
+    let sub_nodes = node.get_or_expand_sub_nodes_mut();
+    if sub_nodes.len() < max_children {
+        // Sampled ids come after the discrete choices, interleaved by depth,
+        // so that they are never the same as the parent's however many there are.
+        let depth_stride = params.mcts.search_depth + 1;
+        let policy_id = sub_nodes[policy_choices.len()..]
+            .iter()
+            .filter_map(policy_id)
+            .max()
+            .map_or(policy_choices.len() as u32 + depth, |id| id + depth_stride);
+        let policy = sample_policy(params, policy_id, rng);
+        sub_nodes.push(MctsNode::new(params, policy_choices, Some(policy), depth));
     }
 }
 
-impl<'a> MctsNode<'a> {
-    fn new(
-        params: &'a Parameters,
-        policy_choices: &'a [SidePolicy],
-        policy: Option<SidePolicy>,
-        depth: u32,
-    ) -> Self {
-        Self {
-            params,
-            policy_choices,
-            policy,
-            traces: Vec::new(),
-            depth,
-            n_trials: 0,
-            expected_cost: None,
-            costs: Vec::new(),
-            intermediate_costs: Vec::new(),
-            marginal_costs: CostSet::new(),
-            n_particles_repeated: 0,
-            sub_nodes: None,
+fn policy_id(node: &MctsNode) -> Option<u32> {
+    node.policy.as_ref().map(|p| p.policy_id())
+}
+
+// the child for the given policy, adding it if it isn't there yet
+fn sub_node_for_policy_mut<'b, 'a>(
+    node: &'b mut MctsNode<'a>,
+    policy: &SidePolicy,
+) -> &'b mut MctsNode<'a> {
+    let params = node.params;
+    let policy_choices = node.policy_choices;
+    let depth = node.depth + 1;
+    let sub_nodes = node.get_or_expand_sub_nodes_mut();
+    let id = Some(policy.policy_id());
+    match sub_nodes.iter().position(|n| policy_id(n) == id) {
+        Some(sub_node_i) => &mut sub_nodes[sub_node_i],
+        None => {
+            sub_nodes.push(MctsNode::new(
+                params,
+                policy_choices,
+                Some(policy.clone()),
+                depth,
+            ));
+            sub_nodes.last_mut().unwrap()
         }
     }
+}
 
-    fn min_child_expected_cost(&self) -> Option<Cost> {
-        self.sub_nodes.as_ref().and_then(|sub_nodes| {
-            sub_nodes
-                .iter()
-                .filter_map(|n| n.expected_cost)
-                .min_by(|a, b| a.partial_cmp(b).unwrap())
+// the best policy at each depth below this node, merged over the trees, as far as
+// the search went
+fn best_policy_sequence(nodes: &[&MctsNode]) -> Vec<SidePolicy> {
+    let mut policies = Vec::new();
+    let mut nodes = nodes.to_vec();
+    while let Some(best_nodes) = best_merged_child(&nodes) {
+        policies.push(best_nodes[0].policy.clone().unwrap());
+        nodes = best_nodes;
+    }
+    policies
+}
+
+// The subtree kept for the next search, merged over the trees, with the trials
+// that reached the next replan. Each tree numbered its particles from zero,
+// so they are renumbered to stay distinct.
+fn merged_retain_stats(nodes: &[&MctsNode]) -> Option<NodeStats> {
+    let n_trees = nodes.len();
+    let stats = nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(tree_i, node)| {
+            let mut stats = retain_stats(node)?;
+            stats.renumber_particles(n_trees, tree_i);
+            Some(stats)
         })
+        .collect_vec();
+    if stats.is_empty() {
+        return None;
     }
+    Some(NodeStats::merge(stats, nodes[0].policy_choices.len()))
+}
+
+// The subtree kept for the next search, with the trials that reached the next replan.
+fn retain_stats(node: &MctsNode) -> Option<NodeStats> {
+    // what each trial cost up to the next replan is committed to by then
+    let prefixes = node
+        .costs
+        .iter()
+        .zip(node.data.replan_costs.iter())
+        .filter_map(|((_, (_, particle)), replan_costs)| {
+            Some((particle.id, *replan_costs.first()?))
+        })
+        .collect::<HashMap<_, _>>();
+    rebased_stats(node, &prefixes)
+}
 
-    fn mean_cost(&self) -> Cost {
-        self.costs.iter().map(|(c, _)| *c).sum::<Cost>() / self.costs.len() as f64
+// The node's trials with their costs from the next replan on, discounted from then.
+fn rebased_stats(node: &MctsNode, prefixes: &HashMap<usize, Cost>) -> Option<NodeStats> {
+    let first_step = node.depth == 1;
+    // the trial, intermediate and marginal costs are all pushed once per trial, in order
+    assert_eq!(node.costs.len(), node.intermediate_costs.len());
+    assert_eq!(node.costs.len(), node.marginal_costs.len());
+    let trials = node
+        .costs
+        .iter_weighted()
+        .zip(node.intermediate_costs.iter())
+        .zip(node.marginal_costs.iter())
+        .enumerate()
+        .filter_map(
+            |(i, (((cost, weight), intermediate_cost), marginal_cost))| {
+                let (_, (cost, particle)) = cost;
+                let prefix = prefixes.get(&particle.id)?;
+                let rebase = |c: &Cost| {
+                    let mut rebased = (*c - *prefix) / prefix.discount;
+                    rebased.discount = c.discount / prefix.discount;
+                    rebased
+                };
+                // only the first step's marginal cost includes the prefix
+                let marginal_cost = if first_step {
+                    rebase(&marginal_cost.1)
+                } else {
+                    marginal_cost.1 / prefix.discount
+                };
+                let replan_costs = if first_step {
+                    node.data.replan_costs[i][1..].iter().map(rebase).collect()
+                } else {
+                    Vec::new()
+                };
+                Some(RetainedTrial {
+                    particle: particle.clone(),
+                    weight,
+                    cost: rebase(cost),
+                    intermediate_cost: rebase(&intermediate_cost.1),
+                    marginal_cost,
+                    replan_costs,
+                })
+            },
+        )
+        .collect::<Vec<_>>();
+    if trials.is_empty() {
+        return None;
     }
+    Some(NodeStats {
+        policy: node.policy.clone(),
+        trials,
+        sub_nodes: node
+            .sub_nodes
+            .iter()
+            .flatten()
+            .map(|n| rebased_stats(n, prefixes))
+            .collect(),
+    })
+}
 
-    fn intermediate_cost(&self) -> Cost {
-        if self.intermediate_costs.is_empty() {
-            Cost::ZERO
-        } else {
-            self.intermediate_costs.iter().copied().sum::<Cost>()
-                / self.intermediate_costs.len() as f64
+// The most recent keep_frac of the retained particles, split between the trees so
+// that each is counted once, and numbered from zero for the tree that keeps them.
+fn kept_particle_ids(
+    stats: &NodeStats,
+    keep_frac: f64,
+    tree_i: usize,
+    n_trees: usize,
+) -> HashMap<usize, usize> {
+    let n_keep = (stats.trials.len() as f64 * keep_frac).round() as usize;
+    stats.trials[stats.trials.len() - n_keep..]
+        .iter()
+        .skip(tree_i)
+        .step_by(n_trees)
+        .enumerate()
+        .map(|(new_id, trial)| (trial.particle.id, new_id))
+        .collect()
+}
+
+// Starts the node off with the retained trials of the particles in new_ids,
+// under their new ids.
+fn graft(node: &mut MctsNode, stats: &NodeStats, new_ids: &HashMap<usize, usize>) {
+    node.clear_costs();
+    node.data.replan_costs.clear();
+    for trial in stats.trials.iter() {
+        let new_id = match new_ids.get(&trial.particle.id) {
+            Some(&new_id) => new_id,
+            None => continue,
+        };
+        let particle = Particle {
+            id: new_id,
+            ..trial.particle.clone()
+        };
+        node.intermediate_costs.push_weighted(
+            (trial.intermediate_cost.total(), trial.intermediate_cost),
+            trial.weight,
+        );
+        node.marginal_costs.push_weighted(
+            (trial.marginal_cost.total(), trial.marginal_cost),
+            trial.weight,
+        );
+        node.record_weighted_trial(new_id, trial.cost, particle, trial.weight);
+        if node.depth == 1 {
+            node.data.replan_costs.push(trial.replan_costs.clone());
         }
     }
+    node.n_retained_trials = node.costs.len();
+    if node.costs.is_empty() {
+        return;
+    }
 
-    fn marginal_cost(&self) -> Cost {
-        if self.marginal_costs.is_empty() {
-            Cost::ZERO
-        } else {
-            self.marginal_costs.iter().map(|(_, c)| *c).sum::<Cost>()
-                / self.marginal_costs.len() as f64
+    if !stats.sub_nodes.is_empty() {
+        node.get_or_expand_sub_nodes();
+        for sub_stats in stats.sub_nodes.iter().flatten() {
+            if sub_stats
+                .trials
+                .iter()
+                .any(|t| new_ids.contains_key(&t.particle.id))
+            {
+                let policy = sub_stats.policy.as_ref().unwrap();
+                graft(sub_node_for_policy_mut(node, policy), sub_stats, new_ids);
+            }
         }
     }
 
-    fn update_expected_cost(&mut self) {
-        let mcts = &self.params.mcts;
-
-        let expected_cost = match mcts.bound_mode {
-            CostBoundMode::Classic => self.mean_cost(),
-            CostBoundMode::Expectimax => self
-                .min_child_expected_cost()
-                .unwrap_or_else(|| self.mean_cost()),
-            CostBoundMode::LowerBound => self
-                .min_child_expected_cost()
-                .unwrap_or(Cost::ZERO)
-                .max(&self.intermediate_cost()),
-            CostBoundMode::Marginal => {
-                self.min_child_expected_cost().unwrap_or(Cost::ZERO) + self.marginal_cost()
-            }
-            CostBoundMode::Same => unimplemented!(),
-        };
+    node.update_expected_cost(node.params.mcts.bound_mode);
+}
 
-        self.expected_cost = Some(expected_cost);
+// Starts a new root off with the retained trials, under the policy they were kept for.
+// The root counts them too, so that its children's selection sees them in its total.
+fn graft_root(root: &mut MctsNode, stats: &NodeStats, new_ids: &HashMap<usize, usize>) {
+    let policy = stats.policy.as_ref().unwrap();
+    graft(sub_node_for_policy_mut(root, policy), stats, new_ids);
+    for trial in stats.trials.iter() {
+        if let Some(&new_id) = new_ids.get(&trial.particle.id) {
+            let particle = Particle {
+                id: new_id,
+                ..trial.particle.clone()
+            };
+            root.record_weighted_trial(new_id, trial.cost, particle, trial.weight);
+        }
     }
+    root.n_retained_trials = root.costs.len();
+}
+
+// A trial through a node, as kept between replans.
+#[derive(Clone)]
+struct RetainedTrial {
+    particle: Particle,
+    weight: f64,
+    cost: Cost,
+    intermediate_cost: Cost,
+    marginal_cost: Cost,
+    // the costs at the replans after the next one, for the first step only
+    replan_costs: Vec<Cost>,
+}
 
-    fn get_or_expand_sub_nodes_mut(&mut self) -> &mut Vec<MctsNode<'a>> {
-        let params = self.params;
+// The parts of an MctsNode kept between replans, without its references.
+#[derive(Clone)]
+struct NodeStats {
+    policy: Option<SidePolicy>,
+    trials: Vec<RetainedTrial>,
+    // empty if the node was never expanded
+    sub_nodes: Vec<Option<NodeStats>>,
+}
 
-        if self.sub_nodes.is_none() {
-            let policy_choices = self.policy_choices;
+impl NodeStats {
+    fn renumber_particles(&mut self, n_trees: usize, tree_i: usize) {
+        for trial in self.trials.iter_mut() {
+            trial.particle.id = trial.particle.id * n_trees + tree_i;
+        }
+        for sub_stats in self.sub_nodes.iter_mut().flatten() {
+            sub_stats.renumber_particles(n_trees, tree_i);
+        }
+    }
 
-            self.sub_nodes = Some(
-                policy_choices
+    // The same node's stats from each tree, with the trials interleaved so that the
+    // most recent of each stay at the end. Only the first n_shared children, the discrete
+    // policy choices, are the same in every tree. Children from progressive widening
+    // are kept apart, except that of those that ended up with the same policy id,
+    // only the first is kept.
+    fn merge(stats: Vec<NodeStats>, n_shared: usize) -> NodeStats {
+        if stats.len() == 1 {
+            return stats.into_iter().next().unwrap();
+        }
+        let policy = stats[0].policy.clone();
+        let expanded = stats.iter().any(|s| !s.sub_nodes.is_empty());
+        let mut trial_iters = Vec::new();
+        let mut shared = (0..n_shared).map(|_| Vec::new()).collect_vec();
+        let mut widened = Vec::<NodeStats>::new();
+        for node_stats in stats {
+            trial_iters.push(node_stats.trials.into_iter());
+            for (i, sub_stats) in node_stats.sub_nodes.into_iter().enumerate() {
+                let sub_stats = match sub_stats {
+                    Some(sub_stats) => sub_stats,
+                    None => continue,
+                };
+                if i < n_shared {
+                    shared[i].push(sub_stats);
+                } else if !widened
                     .iter()
-                    .map(|p| Self::new(params, policy_choices, Some(p.clone()), self.depth + 1))
-                    .collect(),
-            );
+                    .any(|w| w.policy_id() == sub_stats.policy_id())
+                {
+                    widened.push(sub_stats);
+                }
+            }
         }
 
-        self.sub_nodes.as_mut().unwrap()
-    }
+        let mut trials = Vec::new();
+        loop {
+            let n_trials = trials.len();
+            trials.extend(trial_iters.iter_mut().filter_map(|t| t.next()));
+            if trials.len() == n_trials {
+                break;
+            }
+        }
 
-    fn get_or_expand_sub_nodes(&mut self) -> &Vec<MctsNode<'a>> {
-        self.get_or_expand_sub_nodes_mut()
+        let sub_nodes = if expanded {
+            shared
+                .into_iter()
+                .map(|s| (!s.is_empty()).then(|| NodeStats::merge(s, n_shared)))
+                .chain(widened.into_iter().map(Some))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        NodeStats {
+            policy,
+            trials,
+            sub_nodes,
+        }
     }
 
-    fn compute_expected_cost_index(&self, total_n: f64, ln_total_n: f64) -> Option<f64> {
-        compute_selection_index(
-            &self.params.mcts,
-            total_n,
-            ln_total_n,
-            self.costs.len(),
-            self.expected_cost.unwrap().total(),
-            self.params.mcts.selection_mode,
-        )
+    fn policy_id(&self) -> Option<u32> {
+        self.policy.as_ref().map(|p| p.policy_id())
     }
+}
 
-    fn get_best_policy_by_cost(&self) -> Option<&SidePolicy> {
-        let chosen_policy = self
-            .sub_nodes
-            .as_ref()
-            .unwrap()
-            .iter()
-            .min_by(|a, b| {
-                let cost_a = a.expected_cost.map_or(f64::MAX, |c| c.total());
-                let cost_b = b.expected_cost.map_or(f64::MAX, |c| c.total());
-                cost_a.partial_cmp(&cost_b).unwrap()
-            })?
-            .policy
-            .as_ref();
-        chosen_policy
-    }
-
-    fn get_best_policy_by_visits(&self) -> Option<&SidePolicy> {
-        let chosen_policy = self
-            .sub_nodes
-            .as_ref()
-            .unwrap()
-            .iter()
-            .max_by(|a, b| a.costs.len().cmp(&b.costs.len()))?
-            .policy
-            .as_ref();
-        chosen_policy
-    }
+// the subtree under the policy chosen by the last replan
+pub struct RetainedTree {
+    t: f64,
+    stats: NodeStats,
 }
 
-fn possibly_modify_particle(costs: &mut [(Cost, Particle)], node: &mut MctsNode, road: &mut Road) {
+const WIDENING_MAX_VEL_CHANGE: f64 = 10.0;
+const WIDENING_TRANSITION_SCALE_MIN: f64 = 0.5;
+const WIDENING_TRANSITION_SCALE_MAX: f64 = 2.0;
+
+// a lane change with a continuous target velocity and duration, to any lane that doesn't end
+fn sample_policy(params: &Parameters, policy_id: u32, rng: &mut ChaCha12Rng) -> SidePolicy {
+    let scenario = &params.scenario;
+    let lanes = (0..params.n_lanes)
+        .filter(|&lane_i| !scenario.has_lane_end() || lane_i != scenario.ending_lane)
+        .collect_vec();
+    let target_lane_i = *lanes.choose(rng).unwrap();
+    let transition_time = params.lane_change_time
+        * rng.gen_range(WIDENING_TRANSITION_SCALE_MIN..WIDENING_TRANSITION_SCALE_MAX);
+    let vel_change = rng.gen_range(-WIDENING_MAX_VEL_CHANGE..WIDENING_MAX_VEL_CHANGE);
+    SidePolicy::LaneChangePolicy(LaneChangePolicy::new(
+        policy_id,
+        Some(target_lane_i),
+        transition_time,
+        false,
+        LongitudinalPolicy::VelChange(vel_change),
+    ))
+}
+
+fn possibly_modify_particle(
+    costs: &mut CostSet<f64, (Cost, Particle)>,
+    node: &mut MctsNode,
+    road: &mut Road,
+) {
     if node.depth > 1 {
         return;
     }
@@ -235,137 +543,145 @@ fn possibly_modify_particle(costs: &mut [(Cost, Particle)], node: &mut MctsNode,
     }
 
     // sort descending by cost, then particle
-    costs.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    costs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
 
-    // at least samples_n possible particles
-    let mut node_seen_particles = vec![false; mctsp.samples_n];
-    for (_, particle) in node.costs.iter() {
-        if particle.id >= node_seen_particles.len() {
-            node_seen_particles.resize(particle.id + 1, false);
-        }
-        node_seen_particles[particle.id] = true;
-    }
-
-    for (_c, particle) in costs.iter() {
-        if particle.id >= node_seen_particles.len() || !node_seen_particles[particle.id] {
+    for (_, (_c, particle)) in costs.iter() {
+        if !node.has_seen_particle(particle.id) {
             for (car, policy) in road.cars.iter_mut().zip(&particle.policies).skip(1) {
                 car.side_policy = Some(policy.clone());
             }
             road.sample_id = Some(particle.id);
-            road.save_particle();
+            road.save_weighted_particle(particle.weight);
             node.n_particles_repeated += 1;
             return;
         }
     }
 }
 
-fn run_step<'a>(node: &mut MctsNode<'a>, road: &mut Road) -> Option<Cost> {
-    let mcts = &node.params.mcts;
-
-    if let Some(ref policy) = node.policy {
-        road.set_ego_policy(policy.clone());
-        // road.reset_car_traces();
+fn run_step(node: &mut MctsNode, road: &mut Road, rng: &mut ChaCha12Rng) -> Option<Cost> {
+    if node.policy.is_some() {
         if node.depth < 4 {
             road.reset_car_traces();
         } else {
             road.disable_car_traces();
         }
-        let prev_cost = road.cost;
-        road.take_update_steps(mcts.layer_t, mcts.dt);
-        node.intermediate_costs.push(road.cost);
-        let marginal_cost = road.cost - prev_cost;
-        node.marginal_costs
-            .push((marginal_cost.total(), marginal_cost));
-        node.traces
+    }
+    let cost = node.run_step(road, rng);
+    if cost.is_some() {
+        node.data
+            .traces
             .append(&mut road.make_traces(node.depth - 1, false));
 
-        return Some(road.cost);
+        if node.params.mcts.belief_update {
+            let particle = road.current_particle();
+            node.data
+                .particles
+                .append(&mut weigh_particles(road, vec![particle]));
+            road.update_sim_belief();
+        }
     }
-    None
+    cost
+}
+
+// Weights each particle by how likely its obstacle policies make the motion they
+// produced in the trial's step, on top of its importance weight.
+fn weigh_particles(road: &Road, particles: Vec<Particle>) -> Vec<(Particle, f64)> {
+    particles
+        .into_iter()
+        .map(|particle| {
+            let weight = particle.weight * road.observation_likelihood(&particle);
+            (particle, weight)
+        })
+        .collect()
+}
+
+// a particle from the weighted set, if any has weight
+fn sample_continuation<'a>(
+    particles: &'a [(Particle, f64)],
+    rng: &mut ChaCha12Rng,
+) -> Option<&'a Particle> {
+    let dist = WeightedIndex::new(particles.iter().map(|(_, w)| w)).ok()?;
+    Some(&particles[dist.sample(rng)].0)
+}
+
+// the effective number of particles in the node's weighted particle set
+fn effective_particles_n(node: &MctsNode) -> f64 {
+    let sum_weights: f64 = node.data.particles.iter().map(|(_, w)| w).sum();
+    let sum_sq_weights: f64 = node.data.particles.iter().map(|(_, w)| w * w).sum();
+    effective_sample_size(sum_weights, sum_sq_weights)
 }
 
-fn find_and_run_trial(node: &mut MctsNode, road: &mut Road, rng: &mut StdRng) -> Cost {
+fn find_and_run_trial(node: &mut MctsNode, road: &mut Road, rng: &mut ChaCha12Rng) -> Cost {
     let params = node.params;
     let mcts = &params.mcts;
 
-    run_step(node, road);
+    run_step(node, road, rng);
 
-    let mut trial_final_cost = None;
-    if node.depth + 1 > mcts.search_depth {
-        trial_final_cost = Some(road.cost);
+    let trial_final_cost = if node.depth + 1 > mcts.search_depth {
+        road.cost
     } else {
         node.get_or_expand_sub_nodes();
-        let sub_nodes = node.sub_nodes.as_mut().unwrap();
+        widen(node, rng);
 
         // choose a node to recurse down into! First, try keeping the policy the same
-        let mut has_run_trial = false;
-        if mcts.prefer_same_policy {
-            if let Some(ref policy) = node.policy {
-                let policy_id = policy.policy_id();
-                if sub_nodes[policy_id as usize].n_trials == 0 {
-                    possibly_modify_particle(
-                        &mut node.costs,
-                        &mut sub_nodes[policy_id as usize],
-                        road,
-                    );
-                    trial_final_cost = Some(find_and_run_trial(
-                        &mut sub_nodes[policy_id as usize],
-                        road,
-                        rng,
-                    ));
-                    has_run_trial = true;
-                }
-            }
+        let mut same_i = None;
+        if mcts.prefer_same_policy && node.policy.is_some() {
+            let sub_nodes = node.sub_nodes.as_ref().unwrap();
+            same_i = sub_nodes
+                .iter()
+                .position(|n| policy_id(n) == policy_id(node))
+                .filter(|&i| sub_nodes[i].n_trials == 0);
         }
+        let chosen_i = match same_i {
+            Some(same_i) => same_i,
+            None => node.choose_sub_node(rng),
+        };
 
-        // then choose any unexplored branch
-        if !has_run_trial {
-            let unexplored = sub_nodes
-                .iter()
-                .enumerate()
-                .filter(|(_, n)| n.n_trials == 0)
-                .map(|(i, _)| i)
-                .collect_vec();
-            if !unexplored.is_empty() {
-                let sub_node_i = *unexplored.choose(rng).unwrap();
-                possibly_modify_particle(&mut node.costs, &mut sub_nodes[sub_node_i], road);
-                trial_final_cost = Some(find_and_run_trial(&mut sub_nodes[sub_node_i], road, rng));
-                has_run_trial = true;
+        // POMCP-style: the rest of the trial follows a particle from this node's set,
+        // filtered by what the ego would believe by now
+        let mut stepped_particle = None;
+        if mcts.belief_update && node.policy.is_some() {
+            let particle = road.particle.clone().unwrap();
+            match sample_continuation(&node.data.particles, rng) {
+                Some(continuation) => {
+                    let policies = continuation.policies.iter().skip(1);
+                    for (car, policy) in road.cars.iter_mut().skip(1).zip(policies) {
+                        car.side_policy = Some(policy.clone());
+                    }
+                }
+                None => road.resample_obstacle_policies(rng),
             }
+            // still under the trial's id and importance weight
+            road.save_weighted_particle(particle.weight);
+            stepped_particle = Some(particle);
         }
 
-        // Everything has been explored at least once: UCB time!
-        if !has_run_trial {
-            let total_n = node.n_trials as f64;
-            let ln_total_n = (total_n).ln();
-            let (_best_ucb, chosen_i) = sub_nodes
-                .iter()
-                .enumerate()
-                .map(|(i, node)| {
-                    let index = node.compute_expected_cost_index(total_n, ln_total_n);
-                    (index, i)
-                })
-                .min_by(|a, b| a.partial_cmp(b).unwrap())
-                .unwrap();
-
-            possibly_modify_particle(&mut node.costs, &mut sub_nodes[chosen_i], road);
-            trial_final_cost = Some(find_and_run_trial(&mut sub_nodes[chosen_i], road, rng));
+        let sub_nodes = node.sub_nodes.as_mut().unwrap();
+        possibly_modify_particle(&mut node.costs, &mut sub_nodes[chosen_i], road);
+        let cost = find_and_run_trial(&mut sub_nodes[chosen_i], road, rng);
+        // this node records the particle its own step followed
+        if let Some(particle) = stepped_particle {
+            road.particle = Some(particle);
         }
-    }
-
-    let trial_final_cost = trial_final_cost.unwrap();
-
-    node.costs
-        .push((trial_final_cost, road.particle.clone().unwrap()));
-    node.n_trials = node.costs.len();
+        cost
+    };
 
-    node.update_expected_cost();
+    let particle = road.particle.clone().unwrap();
+    let weight = particle.weight;
+    node.data.crash.push(road.ego_crashed(), weight);
+    node.record_weighted_trial(particle.id, trial_final_cost, particle, weight);
+    if node.depth == 1 && mcts.reuse_half_life.is_some() {
+        node.data
+            .replan_costs
+            .push(road.replan_costs.clone().unwrap());
+    }
+    node.update_expected_cost(mcts.bound_mode);
 
     trial_final_cost
 }
 
-fn collect_traces(node: &mut MctsNode, traces: &mut Vec<rvx::Shape>) {
-    traces.append(&mut node.traces);
+fn collect_traces(node: &mut MctsNode, traces: &mut Vec<Trace>) {
+    traces.append(&mut node.data.traces);
 
     if let Some(sub_nodes) = node.sub_nodes.as_mut() {
         for sub_node in sub_nodes.iter_mut() {
@@ -383,8 +699,18 @@ fn print_report(node: &MctsNode) {
         let expected_score = node.expected_cost.unwrap();
         let score = expected_score.total();
         eprintln_f!(
-            "n_trials: {node.n_trials}, policy: {policy_id:?}, score: {score:.2}, cost: {expected_score=:.2?}"
+            "n_trials: {node.n_trials}, policy: {policy_id:?}, score: {score:.2}, cost: {expected_score=:.2?}, crash: {node.data.crash}"
         );
+        if node.params.mcts.belief_update && !node.data.particles.is_empty() {
+            for _ in 0..node.depth {
+                eprint!("    ");
+            }
+            let effective_n = effective_particles_n(node);
+            eprintln_f!(
+                "particles: {}, effective: {effective_n:.1}",
+                node.data.particles.len()
+            );
+        }
     }
 
     if let Some(sub_nodes) = &node.sub_nodes {
@@ -394,50 +720,53 @@ fn print_report(node: &MctsNode) {
     }
 }
 
-pub fn mcts_choose_policy(
-    params: &Parameters,
+// Runs trials on a single tree until samples_n are done or the deadline passes,
+// returning the number of trials run. Their particles are numbered from first_id,
+// after any retained ones.
+fn run_trials(
+    node: &mut MctsNode,
     true_road: &Road,
-    rng: &mut StdRng,
-) -> (Option<SidePolicy>, Vec<rvx::Shape>) {
-    let mut params = params.clone();
-    if let Some(total_forward_t) = params.mcts.total_forward_t {
-        params.mcts.layer_t = total_forward_t / params.mcts.search_depth as f64;
-    }
-    let params = &params;
+    rng: &mut ChaCha12Rng,
+    samples_n: usize,
+    deadline: Option<Instant>,
+    first_id: usize,
+) -> usize {
+    let params = node.params;
 
     let mut roads = road_set_for_scenario(
         params,
         true_road,
         rng,
-        (params.mcts.samples_n as f64 * 1.2).ceil() as usize,
+        (samples_n as f64 * 1.2).ceil() as usize,
     );
 
-    let policy_choices = make_policy_choices(params);
-    let debug = true_road.debug
-        && true_road.timesteps + params.debug_steps_before >= params.max_steps as usize;
-
-    let mut node = MctsNode::new(params, &policy_choices, None, 0);
-    node.get_or_expand_sub_nodes();
-
     let mut i = 0;
     loop {
-        let mut road = roads.pop();
-        road.sample_id = Some(i);
-        road.save_particle();
-        find_and_run_trial(&mut node, &mut road, rng);
+        if roads.is_empty() {
+            roads = road_set_for_scenario(params, true_road, rng, samples_n);
+        }
+        let (mut road, weight) = roads.pop();
+        road.sample_id = Some(first_id + i);
+        road.save_weighted_particle(weight);
+        find_and_run_trial(node, &mut road, rng);
 
         i += 1;
-        if i >= params.mcts.samples_n {
-            if params.mcts.most_visited_best_cost_consistency
-                && i <= params.mcts.samples_n * 12 / 10
-            {
+        // by the same expected costs and ranking as the final choice
+        if node.best_child_is_separated(
+            params.mcts.early_stop_mode,
+            params.mcts.early_stop_const,
+            params.mcts.final_choice_mode,
+        ) {
+            break;
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                break;
+            }
+        } else if i >= samples_n {
+            if params.mcts.most_visited_best_cost_consistency && i <= samples_n * 12 / 10 {
                 // if we have this best policy inconsistency, do more trials to try to resolve it!
-                let best_visits = node.get_best_policy_by_visits().map(|p| p.policy_id());
-                let best_cost = node.get_best_policy_by_cost().map(|p| p.policy_id());
-                if best_visits != best_cost {
-                    // if params.is_single_run {
-                    //     eprintln_f!("{best_visits:?} != {best_cost:?}");
-                    // }
+                if !node.best_children_agree() {
                     continue;
                 }
             }
@@ -445,14 +774,646 @@ pub fn mcts_choose_policy(
         }
     }
 
-    let best_policy = node.get_best_policy_by_cost().cloned();
+    i
+}
+
+// The expected cost of the same node over the trees, with each tree's estimate
+// weighted by the total importance weight of its trials.
+fn merged_expected_cost(nodes: &[&MctsNode]) -> Option<Cost> {
+    let mut sum_weights = 0.0;
+    let mut weighted_cost = Cost::ZERO;
+    for node in nodes.iter() {
+        if let Some(expected_cost) = node.expected_cost {
+            let weight = node.costs.sum_weights();
+            sum_weights += weight;
+            weighted_cost += expected_cost * weight;
+        }
+    }
+    if sum_weights > 0.0 {
+        Some(weighted_cost / sum_weights)
+    } else {
+        None
+    }
+}
+
+// Root parallelization: the independent trees vote on each policy, by the merged
+// expected cost of its child in every tree. Children from progressive widening
+// only exist in one tree, so stand alone. The best child is given as its node
+// in each tree that has it.
+fn best_merged_child<'b, 'a>(nodes: &[&'b MctsNode<'a>]) -> Option<Vec<&'b MctsNode<'a>>> {
+    let n_shared = nodes[0].policy_choices.len();
+    let shared = (0..n_shared).map(|child_i| {
+        nodes
+            .iter()
+            .filter_map(|node| node.sub_nodes.as_ref().map(|s| &s[child_i]))
+            .collect_vec()
+    });
+    let widened = nodes
+        .iter()
+        .flat_map(|node| node.sub_nodes.iter().flatten().skip(n_shared))
+        .map(|child| vec![child]);
+    shared
+        .chain(widened)
+        .filter_map(|children| {
+            let cost = merged_expected_cost(&children)?;
+            Some((nodes[0].choice_key(&cost), children))
+        })
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+        .map(|(_, children)| children)
+}
+
+pub fn mcts_choose_policy(
+    params: &Parameters,
+    true_road: &Road,
+    rng: &mut ChaCha12Rng,
+    retained: &mut Option<RetainedTree>,
+) -> Plan {
+    let start_time = Instant::now();
+
+    let mut params = params.clone();
+    if let Some(total_forward_t) = params.mcts.total_forward_t {
+        params.mcts.layer_t = total_forward_t / params.mcts.search_depth as f64;
+    }
+    let params = &params;
+
+    let policy_choices = make_policy_choices(params);
+    let debug = true_road.debug
+        && true_road.timesteps + params.debug_steps_before >= params.max_steps as usize;
+
+    // in anytime mode, trials run until the time budget is used up
+    let deadline = params
+        .mcts
+        .time_budget_frac
+        .map(|frac| start_time + Duration::from_secs_f64(params.replan_dt * frac));
+
+    // the statistics kept from the last replan, aged by the time since then
+    let reused = match (params.mcts.reuse_half_life, retained.take()) {
+        (Some(half_life), Some(retained)) => {
+            let keep_frac = 0.5f64.powf((true_road.t - retained.t) / half_life);
+            Some((retained, keep_frac))
+        }
+        _ => None,
+    };
+    let n_trees = params.mcts.parallel_trees.max(1);
+    let policy_choices = &policy_choices;
+    // along with the number of retained particles it starts with
+    let new_root = |tree_i: usize| {
+        let mut node = MctsNode::new(params, policy_choices, None, 0);
+        node.get_or_expand_sub_nodes();
+        let mut n_retained = 0;
+        if let Some((retained, keep_frac)) = reused.as_ref() {
+            let new_ids = kept_particle_ids(&retained.stats, *keep_frac, tree_i, n_trees);
+            graft_root(&mut node, &retained.stats, &new_ids);
+            n_retained = new_ids.len();
+        }
+        (node, n_retained)
+    };
+
+    // each tree gets its own share of the samples
+    let samples_n = (params.mcts.samples_n as f64 / n_trees as f64).ceil() as usize;
+    let (mut trees, tree_trials): (Vec<_>, Vec<usize>) = if n_trees == 1 {
+        let (mut node, n_retained) = new_root(0);
+        let n_trials = run_trials(&mut node, true_road, rng, samples_n, deadline, n_retained);
+        (vec![node], vec![n_trials])
+    } else {
+        // and its own rng
+        let seeds = (0..n_trees).map(|_| rng.gen()).collect_vec();
+        seeds
+            .into_par_iter()
+            .enumerate()
+            .map(|(tree_i, seed): (usize, u64)| {
+                let mut rng = ChaCha12Rng::seed_from_u64(seed);
+                let (mut node, n_retained) = new_root(tree_i);
+                let n_trials = run_trials(
+                    &mut node, true_road, &mut rng, samples_n, deadline, n_retained,
+                );
+                (node, n_trials)
+            })
+            .unzip()
+    };
+    let n_trials = tree_trials.iter().sum();
+    // with a deadline, there's no fixed number of trials to have saved
+    let n_trials_saved = match (params.mcts.early_stop_mode, deadline) {
+        (EarlyStopMode::Never, _) | (_, Some(_)) => None,
+        _ => Some(
+            tree_trials
+                .iter()
+                .map(|n| samples_n.saturating_sub(*n))
+                .sum(),
+        ),
+    };
+
+    // the final choice may estimate costs differently than the search did
+    for tree in trees.iter_mut() {
+        tree.set_final_choice_expected_values(params.mcts.final_choice_mode);
+    }
+
+    // the chosen child, as its node in each tree
+    let chosen_nodes = if trees.len() == 1 {
+        trees[0].get_best_child_by_cost().map(|node| vec![node])
+    } else {
+        best_merged_child(&trees.iter().collect_vec())
+    };
+    let best_policy = chosen_nodes.as_ref().and_then(|n| n[0].policy.clone());
+
+    // the deeper choices under the chosen policy continue the plan
+    let mut commit_t = None;
+    let policy = match (params.mcts.policy_chain, best_policy) {
+        (PolicyChainMode::FirstOnly, best_policy) | (_, best_policy @ None) => best_policy,
+        (mode, Some(best_policy)) => {
+            let mut sequence = vec![best_policy];
+            sequence.extend(best_policy_sequence(chosen_nodes.as_ref().unwrap()));
+            if mode == PolicyChainMode::Committed {
+                commit_t = Some(sequence.len() as f64 * params.mcts.layer_t);
+            }
+            Some(DelayedPolicy::chain(sequence, params.mcts.layer_t))
+        }
+    };
+
+    // The retained trials are re-based to the next replan, so a committed plan,
+    // which replans later than that, starts the next search fresh.
+    // With several trees, the chosen child's trials from all of them are kept.
+    if params.mcts.reuse_half_life.is_some() && commit_t.is_none() {
+        *retained = chosen_nodes
+            .and_then(|nodes| merged_retain_stats(&nodes))
+            .map(|stats| RetainedTree {
+                t: true_road.t,
+                stats,
+            });
+    }
 
     let mut traces = Vec::new();
-    collect_traces(&mut node, &mut traces);
+    for tree in trees.iter_mut() {
+        collect_traces(tree, &mut traces);
+    }
 
     if debug && params.policy_report_debug {
-        print_report(&node);
+        for tree in trees.iter() {
+            print_report(tree);
+        }
+    }
+
+    Plan {
+        policy,
+        traces,
+        n_trials: Some(n_trials),
+        n_trials_saved,
+        commit_t,
+    }
+}
+
+#[derive(Default)]
+pub struct MctsPlanner {
+    retained: Option<RetainedTree>,
+}
+
+impl Planner for MctsPlanner {
+    fn name(&self) -> &'static str {
+        "mcts"
+    }
+
+    fn new_run(&self) -> Box<dyn Planner> {
+        Box::new(Self::default())
+    }
+
+    fn choose_policy(&mut self, params: &Parameters, road: &Road, rng: &mut ChaCha12Rng) -> Plan {
+        mcts_choose_policy(params, road, rng, &mut self.retained)
+    }
+
+    fn scenario_name(&self, params: &Parameters) -> String {
+        let p = &params.mcts;
+        let forward_t = if let Some(total_forward_t) = p.total_forward_t {
+            format_f!(",total_forward_t={total_forward_t}")
+        } else {
+            format_f!(",layer_t={p.layer_t}")
+        };
+        let final_choice_mode = match p.final_choice_mode {
+            CostBoundMode::Same => "".to_string(),
+            mode => format_f!(",final_choice_mode={mode}"),
+        };
+        let cvar_alpha =
+            if p.bound_mode == CostBoundMode::CVaR || p.final_choice_mode == CostBoundMode::CVaR {
+                format_f!(",cvar_alpha={p.cvar_alpha}")
+            } else {
+                "".to_string()
+            };
+        let selection_const = match p.selection_mode {
+            ChildSelectionMode::KLUCB => format_f!(",klucb_max_cost={p.klucb_max_cost}"),
+            ChildSelectionMode::UCBV => format_f!(",ucbv_const={p.ucbv_const}"),
+            ChildSelectionMode::UCBd => format_f!(",ucbd_const={p.ucbd_const}"),
+            _ => "".to_string(),
+        };
+        let parallel_trees = if p.parallel_trees > 1 {
+            format_f!(",parallel_trees={p.parallel_trees}")
+        } else {
+            "".to_string()
+        };
+        let widening = match p.widening_k {
+            Some(k) => format_f!(",widening_k={k},widening_alpha={p.widening_alpha}"),
+            None => "".to_string(),
+        };
+        let policy_chain = match p.policy_chain {
+            PolicyChainMode::FirstOnly => "".to_string(),
+            mode => format_f!(",policy_chain={mode}"),
+        };
+        let reuse_half_life = match p.reuse_half_life {
+            Some(half_life) => format_f!(",reuse_half_life={half_life}"),
+            None => "".to_string(),
+        };
+        let belief_update = if p.belief_update {
+            ",belief_update=true"
+        } else {
+            ""
+        };
+        let early_stop = match p.early_stop_mode {
+            EarlyStopMode::Never => "".to_string(),
+            mode => format_f!(",early_stop_mode={mode},early_stop_const={p.early_stop_const}"),
+        };
+        let time_budget_frac = match p.time_budget_frac {
+            Some(frac) => format_f!(",time_budget_frac={frac}"),
+            None => "".to_string(),
+        };
+        format_f!(
+            ",samples_n={p.samples_n}\
+             ,search_depth={p.search_depth}\
+             {forward_t}\
+             ,selection_mode={p.selection_mode}\
+             ,bound_mode={p.bound_mode}\
+             {final_choice_mode}\
+             {cvar_alpha}\
+             ,ucb_const={p.ucb_const}\
+             {selection_const}\
+             ,repeat_const={p.repeat_const}\
+             ,most_visited_best_cost_consistency={p.most_visited_best_cost_consistency}\
+             {early_stop}\
+             {belief_update}\
+             {time_budget_frac}\
+             {parallel_trees}\
+             {reuse_half_life}\
+             {policy_chain}\
+             {widening}"
+        )
+    }
+
+    fn set_param(&self, params: &mut Parameters, name: &str, val: &str) -> bool {
+        let p = &mut params.mcts;
+        match name {
+            "samples_n" => p.samples_n = val.parse().unwrap(),
+            "search_depth" => p.search_depth = val.parse().unwrap(),
+            "layer_t" => p.layer_t = val.parse().unwrap(),
+            "total_forward_t" => p.total_forward_t = Some(val.parse().unwrap()),
+            "bound_mode" => p.bound_mode = val.parse().unwrap(),
+            "final_choice_mode" => p.final_choice_mode = val.parse().unwrap(),
+            "cvar_alpha" => p.cvar_alpha = val.parse().unwrap(),
+            "selection_mode" => p.selection_mode = val.parse().unwrap(),
+            "ucb_const" => p.ucb_const = val.parse().unwrap(),
+            "klucb_max_cost" => p.klucb_max_cost = val.parse().unwrap(),
+            "ucbv_const" => p.ucbv_const = val.parse().unwrap(),
+            "ucbd_const" => p.ucbd_const = val.parse().unwrap(),
+            "repeat_const" => p.repeat_const = val.parse().unwrap(),
+            "most_visited_best_cost_consistency" => {
+                p.most_visited_best_cost_consistency = val.parse().unwrap()
+            }
+            "belief_update" => p.belief_update = val.parse().unwrap(),
+            "early_stop_mode" => p.early_stop_mode = val.parse().unwrap(),
+            "early_stop_const" => p.early_stop_const = val.parse().unwrap(),
+            "time_budget_frac" => p.time_budget_frac = Some(val.parse().unwrap()),
+            "parallel_trees" => p.parallel_trees = val.parse().unwrap(),
+            "reuse_half_life" => p.reuse_half_life = Some(val.parse().unwrap()),
+            "policy_chain" => p.policy_chain = val.parse().unwrap(),
+            "widening_k" => p.widening_k = Some(val.parse().unwrap()),
+            "widening_alpha" => p.widening_alpha = val.parse().unwrap(),
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        belief, cost::CostComponent, mpdm::make_obstacle_vehicle_policy_belief_states,
+        scenario::ScenarioKind,
+    };
+    use approx::assert_abs_diff_eq;
+    use std::{collections::HashSet, sync::Arc};
+
+    fn test_road(params: &Parameters) -> Road {
+        let params = Arc::new(params.clone());
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+        let mut road = Road::new(params.clone());
+        while road.cars.len() < params.n_cars + 1 {
+            road.add_random_car(&mut rng);
+        }
+        road.init_belief();
+        road
+    }
+
+    fn weighted_ids(node: &MctsNode) -> Vec<(usize, f64)> {
+        node.costs
+            .iter_weighted()
+            .map(|((_, (_, particle)), weight)| (particle.id, weight))
+            .collect()
     }
 
-    (best_policy, traces)
+    // each node has the retained trials of exactly the kept particles, in order
+    fn assert_grafted(node: &MctsNode, stats: &NodeStats, new_ids: &HashMap<usize, usize>) {
+        let expected = stats
+            .trials
+            .iter()
+            .filter_map(|t| Some((*new_ids.get(&t.particle.id)?, t.weight)))
+            .collect_vec();
+        assert_eq!(weighted_ids(node), expected);
+
+        for sub_stats in stats.sub_nodes.iter().flatten() {
+            let id = sub_stats.policy.as_ref().map(|p| p.policy_id());
+            let sub_node = node.sub_nodes.iter().flatten().find(|n| policy_id(n) == id);
+            match sub_node {
+                Some(sub_node) => assert_grafted(sub_node, sub_stats, new_ids),
+                None => assert!(sub_stats
+                    .trials
+                    .iter()
+                    .all(|t| !new_ids.contains_key(&t.particle.id))),
+            }
+        }
+    }
+
+    #[test]
+    fn node_particles_shift_toward_the_observed_policy() {
+        let mut params = Parameters::new().unwrap();
+        params.run_fast = true;
+        // a single obstacle car, so only its policy sets the weights
+        params.n_cars = 1;
+        let mut road = test_road(&params);
+        road.update_belief();
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+
+        // car 1 is seen changing lanes, where the alternative is staying in its lane
+        let policies = make_obstacle_vehicle_policy_belief_states(&params);
+        let mut sim_road = road.sample_belief(&mut rng);
+        let lane_i = sim_road.cars[1].current_lane();
+        let other_lane_i = (lane_i + 1) % params.n_lanes;
+        sim_road.cars[1].side_policy = Some(policies[other_lane_i as usize * 4].clone());
+        sim_road.sample_id = Some(0);
+        let observed = sim_road.current_particle();
+        let mut unobserved = observed.clone();
+        unobserved.id = 1;
+        unobserved.policies[1] = policies[lane_i as usize * 4].clone();
+
+        for _ in 0..(1.0 / params.physics_dt) as usize {
+            sim_road.update(params.physics_dt);
+        }
+        assert_eq!(
+            sim_road.observation_likelihood(&observed),
+            belief::observation_likelihoods(&sim_road, 1)[other_lane_i as usize * 4]
+        );
+
+        // the two start out even, but the node's set leans to the lane change it saw
+        let policy_choices = make_policy_choices(&params);
+        let mut node = MctsNode::new(&params, &policy_choices, None, 0);
+        node.data
+            .particles
+            .append(&mut weigh_particles(&sim_road, vec![observed, unobserved]));
+        let particles = &node.data.particles;
+        assert_eq!(particles[0].0.weight, particles[1].0.weight);
+        // only the lane differs from what was seen for the one that stayed put
+        assert!(particles[0].1 > 0.0);
+        assert_abs_diff_eq!(
+            particles[1].1,
+            particles[0].1 * params.belief.different_lane_prob,
+            epsilon = 1e-12
+        );
+
+        let n_observed = (0..1000)
+            .filter(|_| sample_continuation(particles, &mut rng).unwrap().id == 0)
+            .count();
+        assert!(n_observed > 750);
+
+        // and with no weight on any particle, there is nothing to continue with
+        let unweighted = particles
+            .iter()
+            .map(|(p, _)| (p.clone(), 0.0))
+            .collect_vec();
+        assert!(sample_continuation(&unweighted, &mut rng).is_none());
+    }
+
+    #[test]
+    fn widening_adds_children_on_schedule_with_their_own_ids() {
+        let mut params = Parameters::new().unwrap();
+        params.scenario.kind = ScenarioKind::LaneDrop;
+        params.mcts.widening_k = Some(2.0);
+        params.mcts.widening_alpha = 0.5;
+        let policy_choices = make_policy_choices(&params);
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+
+        let mut node = MctsNode::new(&params, &policy_choices, None, 0);
+        for n_trials in 0..400 {
+            node.n_trials = n_trials;
+            widen(&mut node, &mut rng);
+            let max_children = (2.0 * (n_trials as f64 + 1.0).sqrt()).ceil() as usize;
+            assert_eq!(
+                node.sub_nodes.as_ref().unwrap().len(),
+                max_children.max(policy_choices.len())
+            );
+        }
+
+        // well past a thousand children, each depth's ids stay apart from the next's
+        params.mcts.widening_k = Some(1.0);
+        params.mcts.widening_alpha = 1.0;
+        let mut ids = HashSet::new();
+        let mut node = MctsNode::new(&params, &policy_choices, None, 0);
+        let sub_node = &mut node.get_or_expand_sub_nodes_mut()[0];
+        for n_trials in 0..1500 {
+            sub_node.n_trials = n_trials;
+            widen(sub_node, &mut rng);
+        }
+        for n_trials in 0..1500 {
+            node.n_trials = n_trials;
+            widen(&mut node, &mut rng);
+        }
+        let sub_nodes = node.sub_nodes.as_ref().unwrap();
+        for n in sub_nodes
+            .iter()
+            .chain(sub_nodes[0].sub_nodes.iter().flatten())
+        {
+            let id = policy_id(n).unwrap();
+            if (id as usize) < policy_choices.len() {
+                continue;
+            }
+            assert!(ids.insert(id));
+            if let Some(SidePolicy::LaneChangePolicy(policy)) = n.policy.as_ref() {
+                assert_ne!(policy.target_lane_i(), Some(params.scenario.ending_lane));
+            }
+        }
+        assert_eq!(ids.len(), 2 * (1500 - policy_choices.len()));
+    }
+
+    #[test]
+    fn grafted_trees_replay_only_the_kept_particles() {
+        let mut params = Parameters::new().unwrap();
+        params.run_fast = true;
+        params.proposal_mix = Some(0.5);
+        params.mcts.samples_n = 24;
+        params.mcts.search_depth = 2;
+        params.mcts.reuse_half_life = Some(1.0);
+        let road = test_road(&params);
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+
+        let mut retained = None;
+        mcts_choose_policy(&params, &road, &mut rng, &mut retained);
+        let stats = retained.unwrap().stats;
+        assert!(stats.trials.len() >= params.mcts.samples_n / 5);
+
+        // two trees split the most recent half of the retained trials
+        let policy_choices = make_policy_choices(&params);
+        let mut kept = HashSet::new();
+        for tree_i in 0..2 {
+            let new_ids = kept_particle_ids(&stats, 0.5, tree_i, 2);
+            let n_retained = new_ids.len();
+            assert_eq!(
+                new_ids.values().copied().sorted().collect_vec(),
+                (0..n_retained).collect_vec()
+            );
+            for old_id in new_ids.keys() {
+                assert!(kept.insert(*old_id));
+            }
+
+            let mut root = MctsNode::new(&params, &policy_choices, None, 0);
+            root.get_or_expand_sub_nodes();
+            graft_root(&mut root, &stats, &new_ids);
+            assert_eq!(root.n_trials, n_retained);
+            let policy = stats.policy.as_ref().unwrap();
+            let node = sub_node_for_policy_mut(&mut root, policy);
+            assert_grafted(node, &stats, &new_ids);
+            assert!(!node.has_seen_particle(n_retained));
+
+            // New particles come after the retained ones, so the grafted node has only
+            // seen those that it ran, and the others can still be replayed through it.
+            run_trials(&mut root, &road, &mut rng, 40, None, n_retained);
+            // the root counts every trial through its children
+            let children_n = root.sub_nodes.iter().flatten().map(|n| n.n_trials);
+            assert_eq!(root.n_trials, children_n.sum::<usize>());
+            let new_particle_ids = weighted_ids(&root)
+                .into_iter()
+                .map(|(id, _)| id)
+                .filter(|&id| id >= n_retained);
+            let node = sub_node_for_policy_mut(&mut root, policy);
+            let ids = weighted_ids(node)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect_vec();
+            assert_eq!(ids.iter().unique().count(), ids.len());
+            assert!(ids.len() > n_retained);
+            for id in new_particle_ids {
+                assert_eq!(node.has_seen_particle(id), ids[n_retained..].contains(&id));
+            }
+        }
+        let n_keep = (stats.trials.len() as f64 * 0.5).round() as usize;
+        assert_eq!(kept.len(), n_keep);
+        let most_recent = stats.trials[stats.trials.len() - n_keep..]
+            .iter()
+            .map(|t| t.particle.id)
+            .collect::<HashSet<_>>();
+        assert_eq!(kept, most_recent);
+    }
+
+    // every retained trial of the merged node, and each of its children, is one of the
+    // node's own trials in one of the trees
+    fn assert_merged(merged: &NodeStats, stats: &[Option<NodeStats>], n_trees: usize) {
+        let mut expected = stats
+            .iter()
+            .enumerate()
+            .flat_map(|(tree_i, s)| {
+                s.iter().flat_map(move |s| {
+                    s.trials
+                        .iter()
+                        .map(move |t| t.particle.id * n_trees + tree_i)
+                })
+            })
+            .collect_vec();
+        let mut ids = merged.trials.iter().map(|t| t.particle.id).collect_vec();
+        expected.sort_unstable();
+        ids.sort_unstable();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn parallel_trees_retain_the_chosen_child_from_each_tree() {
+        let mut params = Parameters::new().unwrap();
+        params.run_fast = true;
+        params.mcts.samples_n = 24;
+        params.mcts.search_depth = 2;
+        params.mcts.reuse_half_life = Some(1.0);
+        let road = test_road(&params);
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+
+        let policy_choices = make_policy_choices(&params);
+        let trees = (0..2)
+            .map(|_| {
+                let mut root = MctsNode::new(&params, &policy_choices, None, 0);
+                run_trials(&mut root, &road, &mut rng, 24, None, 0);
+                root.set_final_choice_expected_values(params.mcts.final_choice_mode);
+                root
+            })
+            .collect_vec();
+        let roots = trees.iter().collect_vec();
+
+        let chosen = best_merged_child(&roots).unwrap();
+        assert_eq!(chosen.len(), 2);
+        let chosen_id = policy_id(chosen[0]);
+        assert_eq!(policy_id(chosen[1]), chosen_id);
+        let merged = merged_retain_stats(&chosen).unwrap();
+        let stats = chosen.iter().map(|n| retain_stats(n)).collect_vec();
+        assert_merged(&merged, &stats, 2);
+        for (i, sub_stats) in merged
+            .sub_nodes
+            .iter()
+            .enumerate()
+            .take(policy_choices.len())
+        {
+            if let Some(sub_stats) = sub_stats {
+                let tree_sub_stats = stats
+                    .iter()
+                    .map(|s| {
+                        s.as_ref()
+                            .and_then(|s| s.sub_nodes.get(i).cloned().flatten())
+                    })
+                    .collect_vec();
+                assert_merged(sub_stats, &tree_sub_stats, 2);
+            }
+        }
+
+        // the next replan splits all of them between the trees again
+        let n_kept = (0..2)
+            .map(|tree_i| kept_particle_ids(&merged, 1.0, tree_i, 2).len())
+            .sum::<usize>();
+        assert_eq!(n_kept, merged.trials.len());
+    }
+
+    #[test]
+    fn merged_costs_are_weighted_by_importance() {
+        let params = Parameters::new().unwrap();
+        let policy_choices = make_policy_choices(&params);
+        let node_with = |weights: &[f64], efficiency: f64| {
+            let mut node = MctsNode::new(&params, &policy_choices, None, 0);
+            let mut cost = Cost::ZERO;
+            cost[CostComponent::EFFICIENCY] = efficiency;
+            for (id, weight) in weights.iter().enumerate() {
+                let particle = Particle {
+                    id,
+                    policies: Vec::new(),
+                    weight: *weight,
+                };
+                node.record_weighted_trial(id, cost, particle, *weight);
+            }
+            node.expected_cost = Some(cost);
+            node
+        };
+        // the second tree has more trials, but less weight
+        let a = node_with(&[1.5, 1.5], 1.0);
+        let b = node_with(&[0.25, 0.25, 0.25, 0.25], 4.0);
+        let cost = merged_expected_cost(&[&a, &b]).unwrap();
+        assert_abs_diff_eq!(cost[CostComponent::EFFICIENCY], 7.0 / 4.0, epsilon = 1e-9);
+    }
 }
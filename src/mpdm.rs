@@ -1,19 +1,22 @@
-use rand::prelude::StdRng;
+use rand_chacha::ChaCha12Rng;
 
 use crate::{
     arg_parameters::Parameters,
     cost::Cost,
+    crash_probability::CrashProbability,
     lane_change_policy::{LaneChangePolicy, LongitudinalPolicy},
+    planner::{Plan, Planner},
     road::Road,
     road_set::RoadSet,
     road_set_for_scenario,
     side_policies::{SidePolicy, SidePolicyTrait},
+    trace::Trace,
 };
 
 pub fn make_obstacle_vehicle_policy_choices(params: &Parameters) -> Vec<SidePolicy> {
     let mut policy_choices = Vec::new();
 
-    for lane_i in [0, 1] {
+    for lane_i in 0..params.n_lanes {
         for long_policy in [LongitudinalPolicy::Maintain, LongitudinalPolicy::Accelerate] {
             policy_choices.push(SidePolicy::LaneChangePolicy(LaneChangePolicy::new(
                 policy_choices.len() as u32,
@@ -39,7 +42,7 @@ pub fn make_obstacle_vehicle_policy_choices(params: &Parameters) -> Vec<SidePoli
 pub fn make_obstacle_vehicle_policy_belief_states(params: &Parameters) -> Vec<SidePolicy> {
     let mut policy_choices = Vec::new();
 
-    for lane_i in [0, 1] {
+    for lane_i in 0..params.n_lanes {
         for long_policy in [LongitudinalPolicy::Maintain, LongitudinalPolicy::Accelerate] {
             for wait_for_clear in [false, true] {
                 policy_choices.push(SidePolicy::LaneChangePolicy(LaneChangePolicy::new(
@@ -67,9 +70,9 @@ pub fn make_obstacle_vehicle_policy_belief_states(params: &Parameters) -> Vec<Si
 pub fn make_policy_choices(params: &Parameters) -> Vec<SidePolicy> {
     let mut policy_choices = Vec::new();
 
-    let long_policies = vec![LongitudinalPolicy::Maintain, LongitudinalPolicy::Accelerate];
+    let long_policies = [LongitudinalPolicy::Maintain, LongitudinalPolicy::Accelerate];
 
-    for &lane_i in &[0, 1] {
+    for lane_i in 0..params.n_lanes {
         for &long_policy in long_policies.iter() {
             policy_choices.push(SidePolicy::LaneChangePolicy(LaneChangePolicy::new(
                 policy_choices.len() as u32,
@@ -96,7 +99,7 @@ fn evaluate_policy(
     params: &Parameters,
     roads: &RoadSet,
     policy: &SidePolicy,
-) -> (Cost, Vec<rvx::Shape>) {
+) -> (Cost, CrashProbability, Vec<Trace>) {
     let mut roads = roads.clone();
     roads.set_ego_policy(policy);
 
@@ -104,14 +107,18 @@ fn evaluate_policy(
     roads.reset_car_traces();
     roads.take_update_steps(mpdm.forward_t, mpdm.dt);
 
-    (roads.cost(), roads.make_traces(0, false))
+    (
+        roads.cost(),
+        roads.crash_probability(),
+        roads.make_traces(0, false),
+    )
 }
 
 pub fn mpdm_choose_policy(
     params: &Parameters,
     true_road: &Road,
-    rng: &mut StdRng,
-) -> (Option<SidePolicy>, Vec<rvx::Shape>) {
+    rng: &mut ChaCha12Rng,
+) -> (Option<SidePolicy>, Vec<Trace>) {
     let mut traces = Vec::new();
     let roads = road_set_for_scenario(params, true_road, rng, params.mpdm.samples_n);
     let debug = params.policy_report_debug
@@ -131,6 +138,7 @@ pub fn mpdm_choose_policy(
     }
 
     let policy_choices = make_policy_choices(params);
+    let safety_constraint = params.cost.safety_constraint;
     let mut best_cost = Cost::max_value();
     let mut best_policy = None;
 
@@ -142,15 +150,19 @@ pub fn mpdm_choose_policy(
         //     continue;
         // }
 
-        let (cost, mut new_traces) = evaluate_policy(params, &roads, &policy);
+        let (cost, crash, mut new_traces) = evaluate_policy(params, &roads, &policy);
         traces.append(&mut new_traces);
         // eprint!("{:.2} ", cost);
         // eprintln!("{:?}: {:.2} ", policy, cost);
         if debug {
-            eprintln_f!("{i}: {policy:?}: {:7.2?} = {:7.2}", cost, cost.total());
+            eprintln_f!(
+                "{i}: {policy:?}: {:7.2?} = {:7.2}, crash: {crash}",
+                cost,
+                cost.total()
+            );
         }
 
-        if cost < best_cost {
+        if best_policy.is_none() || cost.is_better_choice(&best_cost, safety_constraint) {
             best_cost = cost;
             best_policy = Some(policy);
         }
@@ -159,3 +171,34 @@ pub fn mpdm_choose_policy(
 
     (best_policy, traces)
 }
+
+pub struct MpdmPlanner;
+
+impl Planner for MpdmPlanner {
+    fn name(&self) -> &'static str {
+        "mpdm"
+    }
+
+    fn new_run(&self) -> Box<dyn Planner> {
+        Box::new(MpdmPlanner)
+    }
+
+    fn choose_policy(&mut self, params: &Parameters, road: &Road, rng: &mut ChaCha12Rng) -> Plan {
+        mpdm_choose_policy(params, road, rng).into()
+    }
+
+    fn scenario_name(&self, params: &Parameters) -> String {
+        let p = &params.mpdm;
+        format_f!(",samples_n={p.samples_n},forward_t={p.forward_t}")
+    }
+
+    fn set_param(&self, params: &mut Parameters, name: &str, val: &str) -> bool {
+        let p = &mut params.mpdm;
+        match name {
+            "samples_n" => p.samples_n = val.parse().unwrap(),
+            "forward_t" => p.forward_t = val.parse().unwrap(),
+            _ => return false,
+        }
+        true
+    }
+}
@@ -1,10 +1,12 @@
+use serde::{Deserialize, Serialize};
+
 use crate::{
     forward_control::ForwardControlTrait,
     side_control::SideControlTrait,
     side_policies::{SidePolicy, SidePolicyTrait},
 };
 
-#[derive(Clone, Debug, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct OpenLoopPolicy;
 
 impl SidePolicyTrait for OpenLoopPolicy {
@@ -25,7 +27,7 @@ impl SidePolicyTrait for OpenLoopPolicy {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OpenLoopSideControl;
 
 impl SideControlTrait for OpenLoopSideControl {
@@ -39,7 +41,7 @@ impl SideControlTrait for OpenLoopSideControl {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OpenLoopForwardControl;
 
 impl ForwardControlTrait for OpenLoopForwardControl {
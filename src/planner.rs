@@ -0,0 +1,120 @@
+use rand_chacha::ChaCha12Rng;
+
+use crate::{
+    arg_parameters::Parameters, eudm::EudmPlanner, mcts::MctsPlanner, mpdm::MpdmPlanner,
+    road::Road, side_policies::SidePolicy, trace::Trace,
+};
+
+// what a planner decided, along with how it got there
+pub struct Plan {
+    // None keeps the current ego policy
+    pub policy: Option<SidePolicy>,
+    pub traces: Vec<Trace>,
+    // for planners that run a variable number of trials
+    pub n_trials: Option<usize>,
+    // how many fewer trials than planned it needed, if it can stop early
+    pub n_trials_saved: Option<usize>,
+    // how long to follow the policy before replanning, if longer than replan_dt
+    pub commit_t: Option<f64>,
+}
+
+impl From<(Option<SidePolicy>, Vec<Trace>)> for Plan {
+    fn from((policy, traces): (Option<SidePolicy>, Vec<Trace>)) -> Self {
+        Self {
+            policy,
+            traces,
+            n_trials: None,
+            n_trials_saved: None,
+            commit_t: None,
+        }
+    }
+}
+
+// A method for choosing the ego policy, selected by name with the `method` parameter.
+// Scenarios run in parallel and catch panics, hence the bounds.
+pub trait Planner: Sync {
+    // also the prefix of the planner's own parameters, as in "mcts.samples_n"
+    fn name(&self) -> &'static str;
+
+    // a planner for a new run, without any state kept from earlier runs
+    fn new_run(&self) -> Box<dyn Planner>;
+
+    fn choose_policy(&mut self, params: &Parameters, road: &Road, rng: &mut ChaCha12Rng) -> Plan;
+
+    // the planner's parameters, as they should appear in the scenario name
+    fn scenario_name(&self, _params: &Parameters) -> String {
+        String::new()
+    }
+
+    // Sets one of the planner's own parameters, named without the prefix,
+    // returning false if there is no such parameter.
+    fn set_param(&self, _params: &mut Parameters, _name: &str, _val: &str) -> bool {
+        false
+    }
+}
+
+// keeps whatever policy the ego car starts with
+pub struct FixedPlanner;
+
+impl Planner for FixedPlanner {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn new_run(&self) -> Box<dyn Planner> {
+        Box::new(FixedPlanner)
+    }
+
+    fn choose_policy(
+        &mut self,
+        _params: &Parameters,
+        _road: &Road,
+        _rng: &mut ChaCha12Rng,
+    ) -> Plan {
+        (None, Vec::new()).into()
+    }
+}
+
+pub struct PlannerRegistry {
+    planners: Vec<Box<dyn Planner>>,
+}
+
+impl PlannerRegistry {
+    pub fn empty() -> Self {
+        Self {
+            planners: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, planner: impl Planner + 'static) {
+        assert!(
+            self.find(planner.name()).is_none(),
+            "planner {} is already registered",
+            planner.name()
+        );
+        self.planners.push(Box::new(planner));
+    }
+
+    pub fn find(&self, name: &str) -> Option<&dyn Planner> {
+        self.planners
+            .iter()
+            .find(|p| p.name() == name)
+            .map(|p| p.as_ref())
+    }
+
+    pub fn get(&self, name: &str) -> &dyn Planner {
+        self.find(name)
+            .unwrap_or_else(|| panic!("invalid method '{}'", name))
+    }
+}
+
+impl Default for PlannerRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register(FixedPlanner);
+        registry.register(MpdmPlanner);
+        registry.register(EudmPlanner);
+        registry.register(MctsPlanner::default());
+        registry
+    }
+}
@@ -1,8 +1,14 @@
 use nalgebra::point;
 use parry2d_f64::{math::Isometry, na::Point2, shape::Ball};
-use rvx::{Rvx, RvxColor};
-
-use crate::{car::PRIUS_LENGTH, road::LANE_WIDTH, side_control::SideControlTrait, Road};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    car::PRIUS_LENGTH,
+    graphics::{Canvas, Color, Shape},
+    road::LANE_WIDTH,
+    side_control::SideControlTrait,
+    Road,
+};
 use itertools::Itertools;
 
 const AHEAD_DIST_MIN: f64 = LANE_WIDTH + PRIUS_LENGTH * 0.2;
@@ -31,9 +37,10 @@ impl PurePursuitPolicyDebug {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PurePursuitPolicy {
     ahead_time: f64,
+    #[serde(skip)]
     debug_info: Option<Box<PurePursuitPolicyDebug>>,
 }
 
@@ -265,9 +272,7 @@ impl SideControlTrait for PurePursuitPolicy {
         let car_ref_x = car.x();
         let car_ref_y = car.y();
 
-        let target_ahead_dist = (self.ahead_time * car.vel)
-            .min(AHEAD_DIST_MAX)
-            .max(AHEAD_DIST_MIN);
+        let target_ahead_dist = (self.ahead_time * car.vel).clamp(AHEAD_DIST_MIN, AHEAD_DIST_MAX);
 
         let contact = polyline_contact(
             &Isometry::identity(),
@@ -315,24 +320,24 @@ impl SideControlTrait for PurePursuitPolicy {
         target_steer
     }
 
-    fn draw(&self, r: &mut Rvx) {
+    fn draw(&self, r: &mut Canvas) {
         if let Some(ref info) = self.debug_info {
             r.draw(
-                Rvx::circle()
+                Shape::circle()
                     .scale(0.5)
                     .translate(&[info.target_x, info.target_y])
-                    .color(RvxColor::WHITE),
+                    .color(Color::WHITE),
             );
 
             r.draw(
-                Rvx::circle()
+                Shape::circle()
                     .scale(info.ahead_dist)
                     .translate(&[info.car_x, info.car_y])
-                    .color(RvxColor::WHITE.set_a(0.5)),
+                    .color(Color::WHITE.set_a(0.5)),
             );
 
             for (a, b) in info.trajectory.iter().tuple_windows() {
-                r.draw(Rvx::line([a.x, a.y, b.x, b.y], 2.0).color(RvxColor::WHITE));
+                r.draw(Shape::line([a.x, a.y, b.x, b.y], 2.0).color(Color::WHITE));
             }
         }
     }
@@ -348,7 +353,7 @@ mod tests {
     fn test_polyline_contact1() {
         let contact = polyline_contact(
             &Isometry::identity(),
-            &vec![Point::new(0.0, 0.0), Point::new(20.0, 0.0)],
+            &[Point::new(0.0, 0.0), Point::new(20.0, 0.0)],
             &Isometry::identity(),
             &Ball::new(10.0),
             0.0,
@@ -364,7 +369,7 @@ mod tests {
     fn test_polyline_contact2() {
         let contact = polyline_contact(
             &Isometry::identity(),
-            &vec![Point::new(0.0, 0.0), Point::new(0.0, 20.0)],
+            &[Point::new(0.0, 0.0), Point::new(0.0, 20.0)],
             &Isometry::identity(),
             &Ball::new(10.0),
             0.0,
@@ -538,9 +543,9 @@ mod tests {
 
     #[test]
     fn test_circ_line_failure1() {
-        let p1 = Point2::new(-7.5302913661567965, 2.4791392629445359);
+        let p1 = Point2::new(-7.5302913661567965, 2.479139262944536);
         let p2 = Point2::new(-6.5, 2.5);
-        let circ_xy = Point2::new(-8.2679391016533117, 2.3513395997966295);
+        let circ_xy = Point2::new(-8.267939101653312, 2.3513395997966295);
         assert!(circle_line_contact(circ_xy, 0.2, p1, p2).is_none());
     }
 }
@@ -0,0 +1,242 @@
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arg_parameters::Parameters,
+    car::Car,
+    cost::{Cost, NamedCost},
+    graphics::{Canvas, RvxWindow},
+    mpdm::make_obstacle_vehicle_policy_choices,
+    rate_timer::RateTimer,
+    road::Road,
+    side_policies::{SidePolicy, SidePolicyTrait},
+    svg::SvgExporter,
+    trace::Trace,
+};
+
+// A recording is a JSON lines file: the parameters of the run,
+// followed by one RecordedStep per timestep.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedStep {
+    pub timesteps: usize,
+    pub t: f64,
+    pub cars: Vec<RecordedCar>,
+    // these are only present on timesteps where the planner ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ego_policy: Option<SidePolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub planner_traces: Option<Vec<Trace>>,
+    pub cost_increment: NamedCost,
+}
+
+// the same per-car data that goes into Road::car_traces, plus what's needed to draw the car
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedCar {
+    pub x: f64,
+    pub y: f64,
+    pub theta: f64,
+    pub vel: f64,
+    pub steer: f64,
+    pub preferred_vel: f64,
+    pub target_follow_time: f64,
+    pub crashed: bool,
+    pub policy_id: u32,
+}
+
+impl RecordedCar {
+    fn from_car(car: &Car) -> Self {
+        Self {
+            x: car.x(),
+            y: car.y(),
+            theta: car.theta(),
+            vel: car.vel,
+            steer: car.steer,
+            preferred_vel: car.preferred_vel,
+            target_follow_time: car.target_follow_time,
+            crashed: car.crashed,
+            policy_id: car.full_policy_id(),
+        }
+    }
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+    last_cost: Cost,
+}
+
+impl Recorder {
+    pub fn create(file_name: &str, road: &Road) -> Self {
+        let file = File::create(file_name)
+            .unwrap_or_else(|e| panic!("Could not create record file {}: {}", file_name, e));
+        let mut recorder = Self {
+            writer: BufWriter::new(file),
+            last_cost: road.cost,
+        };
+        recorder.write_line(road.params.as_ref());
+        recorder
+    }
+
+    fn write_line<T: Serialize>(&mut self, value: &T) {
+        serde_json::to_writer(&mut self.writer, value).unwrap();
+        self.writer.write_all(b"\n").unwrap();
+    }
+
+    // planner_traces should be Some only if the planner just ran
+    pub fn record_step(&mut self, road: &Road, planner_traces: Option<&[Trace]>) {
+        let step = RecordedStep {
+            timesteps: road.timesteps,
+            t: road.t,
+            cars: road.cars.iter().map(RecordedCar::from_car).collect(),
+            ego_policy: planner_traces.map(|_| road.ego_policy().clone()),
+            planner_traces: planner_traces.map(|traces| traces.to_vec()),
+            cost_increment: road
+                .params
+                .cost_term_registry
+                .named_cost(&(road.cost - self.last_cost)),
+        };
+        self.last_cost = road.cost;
+        self.write_line(&step);
+    }
+}
+
+// Re-renders a recorded run, without simulating anything, in a window and/or
+// as exported frames. The road layout comes from the recorded parameters,
+// but how it is displayed comes from the current ones.
+pub fn replay(params: &Parameters, file_name: &str) {
+    let file = File::open(file_name)
+        .unwrap_or_else(|e| panic!("Could not open replay file {}: {}", file_name, e));
+    let mut lines = BufReader::new(file).lines().map(|line| line.unwrap());
+
+    let mut recorded_params: Parameters = serde_json::from_str(&lines.next().unwrap())
+        .unwrap_or_else(|e| panic!("Could not read replay file {}: {}", file_name, e));
+    recorded_params.graphics_speedup = params.graphics_speedup;
+    recorded_params.graphics_for_paper = params.graphics_for_paper;
+    recorded_params.debug_car_i = params.debug_car_i;
+    recorded_params.run_fast = params.run_fast;
+    recorded_params.export = params.export.clone();
+    recorded_params.cost_term_registry = params.cost_term_registry.clone();
+    let params = Arc::new(recorded_params);
+
+    let mut road = Road::new(params.clone());
+    let obstacle_policies = make_obstacle_vehicle_policy_choices(&params);
+    let mut traces = Vec::new();
+
+    let mut window = if params.run_fast {
+        None
+    } else {
+        Some(RvxWindow::new())
+    };
+    let exporter = params
+        .export
+        .dir
+        .as_ref()
+        .map(|_| SvgExporter::new(&params.export));
+
+    let mut rate = RateTimer::new(Duration::from_millis(
+        (params.physics_dt * 1000.0 / params.graphics_speedup) as u64,
+    ));
+
+    for line in lines {
+        let step: RecordedStep = serde_json::from_str(&line)
+            .unwrap_or_else(|e| panic!("Could not read replay file {}: {}", file_name, e));
+
+        road.t = step.t;
+        road.timesteps = step.timesteps;
+        road.cars.truncate(step.cars.len());
+        while road.cars.len() < step.cars.len() {
+            let car = Car::new(&params, &road.path, road.cars.len(), 0);
+            road.cars.push(car);
+        }
+        for (car, recorded) in road.cars.iter_mut().zip(step.cars.iter()) {
+            car.set_pose(&road.path, recorded.x, recorded.y, recorded.theta);
+            car.vel = recorded.vel;
+            car.steer = recorded.steer;
+            car.preferred_vel = recorded.preferred_vel;
+            car.target_follow_time = recorded.target_follow_time;
+            car.crashed = recorded.crashed;
+            if !car.is_ego() {
+                car.side_policy = obstacle_policies
+                    .iter()
+                    .find(|p| p.policy_id() == recorded.policy_id)
+                    .cloned()
+                    .or_else(|| car.side_policy.take());
+            }
+        }
+        if let Some(ego_policy) = step.ego_policy {
+            road.cars[0].side_policy = Some(ego_policy);
+        }
+        if let Some(planner_traces) = step.planner_traces {
+            traces = planner_traces;
+        }
+
+        let export_frame = match exporter.as_ref() {
+            Some(exporter) => step.timesteps as u32 % exporter.interval() == 0,
+            None => false,
+        };
+        if window.is_none() && !export_frame {
+            continue;
+        }
+
+        let mut canvas = Canvas::new();
+        road.draw(&mut canvas);
+        canvas.draw_all(traces.iter().flat_map(|trace| trace.shapes(&params)));
+        canvas.set_global_rot(-PI / 2.0);
+
+        if export_frame {
+            let exporter = exporter.as_ref().unwrap();
+            exporter.save(&canvas, &format!("frame_{:06}", step.timesteps));
+        }
+        if let Some(window) = window.as_mut() {
+            window.show(&canvas);
+            rate.wait_until_ready();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{planner::PlannerRegistry, simulation::run_with_parameters};
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn recorded_cost_increments_add_up_to_the_final_cost() {
+        let file_name = std::env::temp_dir().join("recording_test.jsonl");
+        let mut params = Parameters::new().unwrap();
+        params.method = "mpdm".to_owned();
+        params.max_steps = 40;
+        params.run_fast = true;
+        params.record_file = Some(file_name.to_str().unwrap().to_owned());
+
+        let planners = PlannerRegistry::default();
+        let (cost, _) = run_with_parameters(params.clone(), planners.get(&params.method));
+
+        let contents = std::fs::read_to_string(&file_name).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+        let mut lines = contents.lines();
+        let recorded_params: Parameters = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert!(recorded_params == params);
+
+        let steps = lines
+            .map(|line| serde_json::from_str::<RecordedStep>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(steps.len(), params.max_steps as usize);
+        assert!(steps.iter().any(|step| step.ego_policy.is_some()));
+
+        let registry = &params.cost_term_registry;
+        let recorded_cost = steps
+            .iter()
+            .map(|step| registry.cost_from_named(&step.cost_increment))
+            .sum::<Cost>();
+        for (component, _) in registry.components() {
+            assert_abs_diff_eq!(recorded_cost[component], cost[component], epsilon = 1e-9);
+        }
+    }
+}
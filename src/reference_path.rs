@@ -0,0 +1,267 @@
+use nalgebra::{point, vector, Point2, Vector2};
+use serde::{Deserialize, Serialize};
+
+use crate::arg_parameters::Parameters;
+
+// curved sections are discretized into straight pieces no longer than this
+const CURVE_STEP_LENGTH: f64 = 1.0;
+
+// one piece of the road centerline; equal curvatures make a circular arc,
+// different ones a clothoid with linearly changing curvature
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CenterlineSegment {
+    pub length: f64,
+    pub start_curvature: f64,
+    pub end_curvature: f64,
+}
+
+// The road centerline as a polyline, starting at the origin heading along +x.
+// Positions along the road are given in Frenet coordinates: s is the arc length
+// along the centerline and d the signed lateral offset (positive to the left).
+// Before the start and after the end, the centerline continues straight.
+#[derive(Clone, Debug)]
+pub struct ReferencePath {
+    points: Vec<Point2<f64>>,
+    s: Vec<f64>,
+    dirs: Vec<Vector2<f64>>,
+    headings: Vec<f64>,
+}
+
+impl ReferencePath {
+    pub fn straight() -> Self {
+        Self::from_points(vec![point!(0.0, 0.0), point!(1.0, 0.0)])
+    }
+
+    pub fn from_params(params: &Parameters) -> Self {
+        Self::from_segments(&params.road.centerline)
+    }
+
+    pub fn from_segments(segments: &[CenterlineSegment]) -> Self {
+        if segments.is_empty() {
+            return Self::straight();
+        }
+
+        let mut points = vec![point!(0.0, 0.0)];
+        let mut pos = point!(0.0, 0.0);
+        let mut heading = 0.0;
+        for seg in segments.iter() {
+            let n_steps = if seg.start_curvature == 0.0 && seg.end_curvature == 0.0 {
+                1
+            } else {
+                (seg.length / CURVE_STEP_LENGTH).ceil().max(1.0) as usize
+            };
+            let step = seg.length / n_steps as f64;
+            for step_i in 0..n_steps {
+                let progress = (step_i as f64 + 0.5) / n_steps as f64;
+                let curvature =
+                    seg.start_curvature + (seg.end_curvature - seg.start_curvature) * progress;
+                let mid_heading = heading + curvature * step * 0.5;
+                pos += vector!(mid_heading.cos(), mid_heading.sin()) * step;
+                heading += curvature * step;
+                points.push(pos);
+            }
+        }
+
+        Self::from_points(points)
+    }
+
+    fn from_points(points: Vec<Point2<f64>>) -> Self {
+        assert!(points.len() >= 2);
+
+        let mut s = vec![0.0];
+        let mut dirs = Vec::with_capacity(points.len() - 1);
+        let mut headings = Vec::with_capacity(points.len() - 1);
+        for (p1, p2) in points.iter().zip(points.iter().skip(1)) {
+            let delta = p2 - p1;
+            let len = delta.magnitude();
+            s.push(s.last().unwrap() + len);
+            dirs.push(delta / len);
+            headings.push(delta.y.atan2(delta.x));
+        }
+
+        Self {
+            points,
+            s,
+            dirs,
+            headings,
+        }
+    }
+
+    fn n_segments(&self) -> usize {
+        self.dirs.len()
+    }
+
+    // index of the polyline segment that s falls in, with the first and last
+    // segments extending indefinitely
+    pub fn segment_at(&self, s: f64) -> usize {
+        let seg_i = self.s.partition_point(|&seg_s| seg_s <= s);
+        seg_i.max(1).min(self.n_segments()) - 1
+    }
+
+    fn project_onto(&self, seg_i: usize, p: Point2<f64>) -> (f64, f64, f64) {
+        let rel = p - self.points[seg_i];
+        let dir = self.dirs[seg_i];
+        let mut t = rel.dot(&dir);
+        if seg_i > 0 {
+            t = t.max(0.0);
+        }
+        if seg_i + 1 < self.n_segments() {
+            t = t.min(self.s[seg_i + 1] - self.s[seg_i]);
+        }
+        let d = dir.x * rel.y - dir.y * rel.x;
+        let dist_sq = (rel - dir * t).magnitude_squared();
+        (self.s[seg_i] + t, d, dist_sq)
+    }
+
+    // returns (s, d, segment index), searching locally from the segment hint
+    // since cars only move a short distance between updates
+    pub fn to_frenet_near(&self, p: Point2<f64>, seg_hint: usize) -> (f64, f64, usize) {
+        let mut seg_i = seg_hint.min(self.n_segments() - 1);
+        let (mut s, mut d, mut dist_sq) = self.project_onto(seg_i, p);
+
+        for &step in &[1isize, -1] {
+            loop {
+                let next_i = seg_i as isize + step;
+                if next_i < 0 || next_i as usize >= self.n_segments() {
+                    break;
+                }
+                let (next_s, next_d, next_dist_sq) = self.project_onto(next_i as usize, p);
+                if next_dist_sq >= dist_sq {
+                    break;
+                }
+                seg_i = next_i as usize;
+                s = next_s;
+                d = next_d;
+                dist_sq = next_dist_sq;
+            }
+        }
+
+        (s, d, seg_i)
+    }
+
+    #[allow(unused)]
+    pub fn to_frenet(&self, p: Point2<f64>) -> (f64, f64) {
+        let mut best = (0.0, 0.0, f64::MAX);
+        for seg_i in 0..self.n_segments() {
+            let projected = self.project_onto(seg_i, p);
+            if projected.2 < best.2 {
+                best = projected;
+            }
+        }
+        (best.0, best.1)
+    }
+
+    pub fn to_cartesian(&self, s: f64, d: f64) -> Point2<f64> {
+        let seg_i = self.segment_at(s);
+        let dir = self.dirs[seg_i];
+        let t = s - self.s[seg_i];
+        let p = self.points[seg_i];
+        point!(p.x + t * dir.x - d * dir.y, p.y + t * dir.y + d * dir.x)
+    }
+
+    pub fn heading(&self, s: f64) -> f64 {
+        self.headings[self.segment_at(s)]
+    }
+
+    // Appends points following the road from one Frenet position to another,
+    // with d changing linearly in s. The start point itself is not included.
+    pub fn append_frenet_line(
+        &self,
+        traj: &mut Vec<Point2<f64>>,
+        from: (f64, f64),
+        to: (f64, f64),
+    ) {
+        let (s1, d1) = from;
+        let (s2, d2) = to;
+        if s2 > s1 {
+            let mut seg_i = self.segment_at(s1) + 1;
+            while seg_i < self.n_segments() && self.s[seg_i] < s2 {
+                let seg_s = self.s[seg_i];
+                if seg_s > s1 {
+                    let d = d1 + (d2 - d1) * (seg_s - s1) / (s2 - s1);
+                    traj.push(self.to_cartesian(seg_s, d));
+                }
+                seg_i += 1;
+            }
+        }
+        traj.push(self.to_cartesian(s2, d2));
+    }
+
+    // the straight pieces of the centerline covering s from low_s to high_s,
+    // as (start s, end s) pairs
+    pub fn pieces_between(&self, low_s: f64, high_s: f64) -> Vec<(f64, f64)> {
+        let mut pieces = Vec::new();
+        let mut piece_low = low_s;
+        let mut seg_i = self.segment_at(low_s) + 1;
+        while seg_i < self.n_segments() && self.s[seg_i] < high_s {
+            pieces.push((piece_low, self.s[seg_i]));
+            piece_low = self.s[seg_i];
+            seg_i += 1;
+        }
+        pieces.push((piece_low, high_s));
+        pieces
+    }
+}
+
+impl Default for ReferencePath {
+    fn default() -> Self {
+        Self::straight()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn straight_is_identity() {
+        let path = ReferencePath::straight();
+        for &(x, y) in &[(0.0, 0.0), (-153.2, 1.85), (87.1, -1.85), (1e3, 5.55)] {
+            assert_eq!(path.to_frenet(point!(x, y)), (x, y));
+            assert_eq!(path.to_frenet_near(point!(x, y), 0), (x, y, 0));
+            assert_eq!(path.to_cartesian(x, y), point!(x, y));
+        }
+        assert_eq!(path.heading(-10.0), 0.0);
+    }
+
+    #[test]
+    fn arc_roundtrip() {
+        let radius = 50.0;
+        let path = ReferencePath::from_segments(&[
+            CenterlineSegment {
+                length: 20.0,
+                start_curvature: 0.0,
+                end_curvature: 0.0,
+            },
+            CenterlineSegment {
+                length: radius * PI * 0.5,
+                start_curvature: 1.0 / radius,
+                end_curvature: 1.0 / radius,
+            },
+        ]);
+
+        // a quarter turn to the left ends up heading along +y
+        let end_s = 20.0 + radius * PI * 0.5;
+        assert!((path.heading(end_s + 5.0) - PI * 0.5).abs() < 0.02);
+        let end = path.to_cartesian(end_s, 0.0);
+        assert!((end - point!(20.0 + radius, radius)).magnitude() < 0.1);
+
+        for &(s, d) in &[
+            (-5.0, 1.85),
+            (35.0, -1.85),
+            (60.0, 1.85),
+            (end_s + 30.0, 0.5),
+        ] {
+            let p = path.to_cartesian(s, d);
+            let (s2, d2) = path.to_frenet(p);
+            assert!((s - s2).abs() < 1e-3, "{} != {}", s, s2);
+            assert!((d - d2).abs() < 1e-3, "{} != {}", d, d2);
+
+            let (s3, d3, _) = path.to_frenet_near(p, 0);
+            assert!((s - s3).abs() < 1e-3, "{} != {}", s, s3);
+            assert!((d - d3).abs() < 1e-3, "{} != {}", d, d3);
+        }
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Default)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct Reward {
     pub crashed: bool,
     pub end_t: f64,
@@ -10,6 +12,11 @@ pub struct Reward {
     pub below997_planning_time: Option<f64>,
     pub max_planning_time: Option<f64>,
     pub stddev_planning_time: Option<f64>,
+    // trials completed in each planning step, for planners that report them
+    pub planning_trials: Vec<usize>,
+    pub mean_planning_trials: Option<f64>,
+    pub planning_trials_saved: Vec<usize>,
+    pub mean_planning_trials_saved: Option<f64>,
 }
 
 impl Reward {
@@ -35,6 +42,17 @@ impl Reward {
             .sqrt()
             / (n as f64).sqrt();
         self.stddev_planning_time = Some(stddev);
+
+        if !self.planning_trials.is_empty() {
+            let total_trials = self.planning_trials.iter().sum::<usize>();
+            self.mean_planning_trials =
+                Some(total_trials as f64 / self.planning_trials.len() as f64);
+        }
+        if !self.planning_trials_saved.is_empty() {
+            let total_saved = self.planning_trials_saved.iter().sum::<usize>();
+            self.mean_planning_trials_saved =
+                Some(total_saved as f64 / self.planning_trials_saved.len() as f64);
+        }
     }
 }
 
@@ -73,6 +91,12 @@ impl std::fmt::Debug for Reward {
         if let Some(t) = self.stddev_planning_time {
             write_f!(f, ", stddev: {:.3}", t * 1000.0)?;
         }
+        if let Some(trials) = self.mean_planning_trials {
+            write_f!(f, ", trials: {:.1}", trials)?;
+        }
+        if let Some(saved) = self.mean_planning_trials_saved {
+            write_f!(f, ", saved: {:.1}", saved)?;
+        }
         Ok(())
     }
 }
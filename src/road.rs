@@ -1,4 +1,4 @@
-use std::{f64::consts::PI, rc::Rc, u32};
+use std::{f64::consts::PI, sync::Arc};
 
 use itertools::Itertools;
 use nalgebra::{vector, Point2, Point3};
@@ -7,15 +7,23 @@ use parry2d_f64::{
     math::Isometry,
     na::point,
     query::{self, ClosestPoints},
-    shape::Shape,
 };
-use rand::{prelude::StdRng, Rng};
-use rvx::{Rvx, RvxColor};
+use rand::Rng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    arg_parameters::Parameters, belief::Belief, car::SpatialCar, cost::Cost,
-    mpdm::make_obstacle_vehicle_policy_belief_states, side_control::SideControlTrait,
+    arg_parameters::Parameters,
+    belief::{self, Belief},
+    car::SpatialCar,
+    cost::{Cost, CostComponent},
+    graphics::{Canvas, Color, Shape},
+    mpdm::{make_obstacle_vehicle_policy_belief_states, make_obstacle_vehicle_policy_choices},
+    reference_path::ReferencePath,
+    scenario::ScenarioKind,
+    side_control::SideControlTrait,
     side_policies::SidePolicy,
+    trace::{Trace, TraceKind},
 };
 use crate::{car::PRIUS_MAX_STEER, forward_control::ForwardControlTrait};
 
@@ -30,24 +38,38 @@ pub const ROAD_LENGTH: f64 = 400.0;
 
 pub const SIDE_MARGIN: f64 = 0.0;
 
-#[derive(Clone)]
+// each car's recent positions, with the timestep they were at
+type CarTraces = Vec<Vec<(Point3<f64>, u32)>>;
+
+// the path and other caches are skipped when serializing, see restore_caches()
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Road {
-    pub params: Rc<Parameters>,
+    pub params: Arc<Parameters>,
+    #[serde(skip)]
+    pub path: Arc<ReferencePath>,
     pub t: f64,           // current time in seconds
     pub timesteps: usize, // current time in timesteps (related by DT)
     pub cars: Vec<Car>,
     pub cars_spatial: Vec<SpatialCar>, // This is a copy for spatial queries, updated ONLY at the end of road.update()
-    pub belief: Option<Rc<Belief>>,
+    pub belief: Option<Arc<Belief>>,
     pub last_ego: Car,
+    // for the jerk cost term
+    #[serde(default)]
+    pub last_ego_accel: f64,
     pub switched_ego_policy: bool,
     pub cost: Cost,
-    pub car_traces: Option<Vec<Vec<(Point3<f64>, u32)>>>,
+    #[serde(skip)]
+    pub car_traces: Option<CarTraces>,
     pub last_reset_cost: Cost,
+    #[serde(skip)]
     pub trajectory_buffer: Vec<Point2<f64>>,
     pub debug: bool,
     pub is_truth: bool,
     pub sample_id: Option<usize>,
     pub particle: Option<Particle>,
+    // for tree reuse, the cost each time replan_dt passed during a trial's first step
+    #[serde(skip)]
+    pub replan_costs: Option<Vec<Cost>>,
 }
 
 fn range_dist(low_a: f64, high_a: f64, low_b: f64, high_b: f64) -> f64 {
@@ -62,22 +84,38 @@ fn range_dist(low_a: f64, high_a: f64, low_b: f64, high_b: f64) -> f64 {
     // sep
 }
 
-fn logistic(x: f64) -> f64 {
+pub fn logistic(x: f64) -> f64 {
     1.0 / (1.0 + (-x).exp())
 }
 
-fn change_range(x: f64, a_low: f64, a_high: f64, b_low: f64, b_high: f64) -> f64 {
+pub fn change_range(x: f64, a_low: f64, a_high: f64, b_low: f64, b_high: f64) -> f64 {
     b_low + (b_high - b_low) * (x - a_low) / (a_high - a_low)
 }
 
+// zero at or above the threshold time, growing quadratically to one as the time goes to zero
+fn time_shortfall(t: f64, thresh: f64) -> f64 {
+    ((thresh - t) / thresh).max(0.0).powi(2)
+}
+
 impl Road {
-    pub fn new(params: Rc<Parameters>) -> Self {
-        let ego_car = Car::new(&params, 0, 0);
+    pub fn new(params: Arc<Parameters>) -> Self {
+        if params.scenario.has_lane_end() {
+            assert!(
+                params.n_lanes >= 2,
+                "an ending lane needs another lane to merge into"
+            );
+            assert!((0..params.n_lanes).contains(&params.scenario.ending_lane));
+            assert!(params.scenario.merge_start_s < params.scenario.lane_end_s);
+        }
+
+        let path = Arc::new(ReferencePath::from_params(&params));
+        let ego_car = Car::new(&params, &path, 0, 0);
 
         Self {
             t: 0.0,
             timesteps: 0,
             last_ego: ego_car.clone(),
+            last_ego_accel: 0.0,
             cars_spatial: vec![SpatialCar::from(&ego_car)].into_iter().collect(),
             cars: vec![ego_car],
             belief: None,
@@ -88,9 +126,11 @@ impl Road {
             last_reset_cost: Cost::new(1.0, 1.0),
             trajectory_buffer: Vec::new(),
             params,
+            path,
             is_truth: true,
             sample_id: None,
             particle: None,
+            replan_costs: None,
         }
     }
 
@@ -107,11 +147,32 @@ impl Road {
     //     }
     // }
 
-    pub fn add_random_car(&mut self, rng: &mut StdRng) {
+    pub fn set_ego_start(&mut self, s: f64, lane_i: i32, vel: f64, preferred_vel: Option<f64>) {
+        let ego = &mut self.cars[0];
+        ego.set_frenet(&self.path, s, Road::get_lane_y(lane_i));
+        ego.vel = vel;
+        if let Some(preferred_vel) = preferred_vel {
+            ego.preferred_vel = preferred_vel;
+        }
+        self.last_ego = ego.clone();
+        self.update_cars_spatial();
+    }
+
+    // rebuilds everything not kept when serializing, after deserializing
+    pub fn restore_caches(&mut self) {
+        self.path = Arc::new(ReferencePath::from_params(&self.params));
+        for car in self.cars.iter_mut() {
+            car.restore_caches(&self.path);
+        }
+        self.last_ego.restore_caches(&self.path);
+        self.car_traces = Some(Vec::new());
+    }
+
+    pub fn add_random_car(&mut self, rng: &mut ChaCha12Rng) {
         for _ in 0..100 {
-            let mut car = Car::random_new(&self.params, self.cars.len(), rng);
+            let mut car = Car::random_new(&self.params, &self.path, self.cars.len(), rng);
             car.vel = 0.0;
-            if self.collides_any_car(&car) {
+            if self.collides_any_car(&car) || !self.car_on_road(&car) {
                 continue;
             }
             self.cars.push(car);
@@ -122,12 +183,12 @@ impl Road {
 
     pub fn init_belief(&mut self) {
         let n_policies = make_obstacle_vehicle_policy_belief_states(&self.params).len();
-        self.belief = Some(Rc::new(Belief::uniform(self.cars.len(), n_policies)));
+        self.belief = Some(Arc::new(Belief::uniform(self.cars.len(), n_policies)));
     }
 
     pub fn update_belief(&mut self) {
-        let mut belief_rc = self.belief.take().unwrap();
-        let belief = Rc::get_mut(&mut belief_rc).expect("update_belief should only be called when it has exclusive access to the top-level road");
+        let mut belief_arc = self.belief.take().unwrap();
+        let belief = Arc::get_mut(&mut belief_arc).expect("update_belief should only be called when it has exclusive access to the top-level road");
         belief.update(self);
 
         if self.super_debug() && self.params.obstacle_car_debug {
@@ -138,18 +199,28 @@ impl Road {
             }
         }
 
-        self.belief = Some(belief_rc);
+        self.belief = Some(belief_arc);
+    }
+
+    // Updates the belief from this (usually forward-simulated) road's own state,
+    // copying it first if it is still shared with other roads.
+    pub fn update_sim_belief(&mut self) {
+        let mut belief_arc = self.belief.take().unwrap();
+        Arc::make_mut(&mut belief_arc).update(self);
+        self.belief = Some(belief_arc);
     }
 
     pub fn clone_without_cars(&self) -> Self {
         Self {
             params: self.params.clone(),
+            path: self.path.clone(),
             t: self.t,
             timesteps: self.timesteps,
             cars: Vec::new(),
             cars_spatial: Vec::new(),
             belief: self.belief.clone(),
             last_ego: self.last_ego.clone(),
+            last_ego_accel: self.last_ego_accel,
             switched_ego_policy: false,
             cost: self.cost,
             car_traces: None,
@@ -159,6 +230,7 @@ impl Road {
             is_truth: false,
             sample_id: self.sample_id,
             particle: None,
+            replan_costs: None,
         }
     }
 
@@ -185,20 +257,63 @@ impl Road {
         road
     }
 
-    pub fn sample_belief(&self, rng: &mut StdRng) -> Self {
-        let belief = self.belief.clone().unwrap();
-        let policies = make_obstacle_vehicle_policy_belief_states(&self.params);
+    pub fn sample_belief(&self, rng: &mut ChaCha12Rng) -> Self {
+        let mut road = self.sim_estimate();
+        road.resample_obstacle_policies(rng);
+        road
+    }
 
+    // Like sample_belief, but from the belief mixed with a uniform distribution,
+    // along with the importance weight that corrects for it.
+    pub fn sample_belief_proposal(&self, rng: &mut ChaCha12Rng, uniform_mix: f64) -> (Self, f64) {
+        let (sample, weight) = self
+            .belief
+            .as_ref()
+            .unwrap()
+            .sample_proposal(rng, uniform_mix);
         let mut road = self.sim_estimate();
+        road.set_obstacle_policies(&sample);
+        (road, weight)
+    }
 
-        let sample = belief.sample(rng);
+    // sample policies from the belief state
+    pub fn resample_obstacle_policies(&mut self, rng: &mut ChaCha12Rng) {
+        let sample = self.belief.as_ref().unwrap().sample(rng);
+        self.set_obstacle_policies(&sample);
+    }
 
-        // sample policies from the belief state
-        for (car_i, car) in road.cars.iter_mut().enumerate().skip(1) {
+    // the obstacle policies by their index in the belief states
+    fn set_obstacle_policies(&mut self, sample: &[usize]) {
+        let policies = make_obstacle_vehicle_policy_belief_states(&self.params);
+        for (car_i, car) in self.cars.iter_mut().enumerate().skip(1) {
             car.side_policy = Some(policies[sample[car_i]].clone());
         }
+    }
 
-        road
+    // how likely the particle's obstacle policies make the cars' current motion
+    pub fn observation_likelihood(&self, particle: &Particle) -> f64 {
+        particle
+            .policies
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(car_i, policy)| {
+                let policy_id = policy.policy_id() as usize;
+                belief::observation_likelihoods(self, car_i)
+                    .get(policy_id)
+                    .copied()
+                    .unwrap_or(0.0)
+            })
+            .product()
+    }
+
+    // since the last step of dt
+    pub fn ego_accel(&self, dt: f64) -> f64 {
+        (self.cars[0].vel - self.last_ego.vel) / dt
+    }
+
+    pub fn ego_crashed(&self) -> bool {
+        self.cars[0].crashed
     }
 
     pub fn ego_policy(&self) -> &SidePolicy {
@@ -236,18 +351,18 @@ impl Road {
         &self,
         skip_car_i: usize,
         lane_i: i32,
-        low_x: f64,
-        high_x: f64,
+        low_s: f64,
+        high_s: f64,
     ) -> bool {
-        assert!(low_x < high_x);
+        assert!(low_s < high_s);
         for c in self.cars.iter() {
             if c.car_i == skip_car_i {
                 continue;
             }
-            if c.x() + c.length / 2.0 < low_x || c.x() - c.length / 2.0 > high_x {
+            if c.s() + c.length / 2.0 < low_s || c.s() - c.length / 2.0 > high_s {
                 continue;
             }
-            let small_theta = c.theta().abs() < 5.0 / 180.0 * PI;
+            let small_theta = c.frenet_theta().abs() < 5.0 / 180.0 * PI;
             if c.current_lane() != lane_i && small_theta {
                 continue;
             }
@@ -256,9 +371,11 @@ impl Road {
             }
 
             // larger theta... more complicated case!
+            let mid_s = (high_s + low_s) * 0.5;
+            let lane_center = self.path.to_cartesian(mid_s, Road::get_lane_y(lane_i));
             if parry2d_f64::query::intersection_test(
-                &Isometry::translation((high_x + low_x) * 0.5, Road::get_lane_y(lane_i)),
-                &parry2d_f64::shape::Cuboid::new(vector!((high_x - low_x) * 0.5, LANE_WIDTH * 0.5)),
+                &Isometry::new(lane_center.coords, self.path.heading(mid_s)),
+                &parry2d_f64::shape::Cuboid::new(vector!((high_s - low_s) * 0.5, LANE_WIDTH * 0.5)),
                 &c.pose(),
                 &c.shape(),
             )
@@ -276,7 +393,7 @@ impl Road {
         let car_a = &self.cars[car_i1];
         let car_b = &self.cars[car_i2];
 
-        if (car_a.x() - car_b.x()).abs() > (car_a.length + car_b.length) / 2.0 {
+        if (car_a.s() - car_b.s()).abs() > (car_a.length + car_b.length) / 2.0 {
             return false;
         }
 
@@ -301,7 +418,67 @@ impl Road {
         false
     }
 
+    fn car_on_road(&self, car: &Car) -> bool {
+        self.params
+            .scenario
+            .lane_exists_at(car.current_lane(), car.s())
+    }
+
+    pub fn lane_change_allowed(&self, car_i: usize, lane_i: i32) -> bool {
+        let car = &self.cars[car_i];
+        self.params
+            .scenario
+            .lane_change_allowed(car.current_lane(), lane_i, car.s())
+    }
+
+    // distance from the front of the car to where the lane physically ends, if it does
+    pub fn dist_to_lane_end(&self, car_i: usize, lane_i: i32) -> Option<f64> {
+        let scenario = &self.params.scenario;
+        let car = &self.cars[car_i];
+        if !scenario.has_lane_end()
+            || lane_i != scenario.ending_lane
+            || car.s() - car.length >= scenario.lane_end_s
+        {
+            return None;
+        }
+        Some(scenario.lane_end_s - car.s())
+    }
+
+    // obstacle cars in an ending lane give up on staying in it once they reach the merge zone
+    fn force_obstacle_merges(&mut self) {
+        let params = &self.params;
+        let scenario = &params.scenario;
+        if !scenario.has_lane_end() {
+            return;
+        }
+
+        for car in self.cars[1..].iter_mut() {
+            if car.crashed || !scenario.in_merge_zone(car.current_lane(), car.s()) {
+                continue;
+            }
+            let target_lane_i = match car.side_policy.as_ref().unwrap().operating_policy() {
+                SidePolicy::LaneChangePolicy(policy) => policy.target_lane_i(),
+                _ => None,
+            };
+            if target_lane_i.unwrap_or(scenario.ending_lane) == scenario.ending_lane {
+                // the "maintain" policy for the lane to merge into
+                let merge_lane = scenario.merge_lane(params.n_lanes);
+                let policies = make_obstacle_vehicle_policy_choices(params);
+                car.side_policy = Some(policies[2 * merge_lane as usize].clone());
+            }
+        }
+    }
+
     pub fn dist_clear_ahead_in_lane(&self, car_i: usize, lane_i: i32) -> Option<(f64, usize)> {
+        self.dist_clear_in_lane(car_i, lane_i, true)
+    }
+
+    pub fn dist_clear_behind_in_lane(&self, car_i: usize, lane_i: i32) -> Option<(f64, usize)> {
+        self.dist_clear_in_lane(car_i, lane_i, false)
+    }
+
+    // the closest car either ahead of or behind the car, as if it were in lane_i
+    fn dist_clear_in_lane(&self, car_i: usize, lane_i: i32, ahead: bool) -> Option<(f64, usize)> {
         let car = &self.cars[car_i];
 
         let mut min_dist = f64::MAX;
@@ -310,27 +487,23 @@ impl Road {
         // turns out this threshold just doesn't rule out enough to be useful
         // let dist_thresh = car.vel * 100.0 + 2.0 * car.length;
 
-        let pose = self.cars[car_i].pose();
+        // everything here is in (s, d) road coordinates
+        let frenet_aabb = car.frenet_aabb();
+        let center_s = (frenet_aabb.mins[0] + frenet_aabb.maxs[0]) * 0.5;
         // we remove rotation from the ego's aabb calculation because otherwise we will
         // see spurious potential collisions from the back of the car while turning.
         // no rotation just focuses on the front of the ego-car for this calculation
         let lane_y = Road::get_lane_y(lane_i);
 
         let aabb = AABB::new(
-            point!(
-                pose.translation.vector.x - car.length * 0.5,
-                lane_y - car.width * 0.5
-            ),
-            point!(
-                pose.translation.vector.x + car.length * 0.5,
-                lane_y + car.width * 0.5
-            ),
+            point!(center_s - car.length * 0.5, lane_y - car.width * 0.5),
+            point!(center_s + car.length * 0.5, lane_y + car.width * 0.5),
         );
 
         // for (i, c) in self.cars.iter().enumerate() {
-        let start_spacial_x = car.spatial_x();
+        let start_spacial_s = car.spatial_s();
         for spatial_car in &self.cars_spatial {
-            if spatial_car.x < start_spacial_x {
+            if (spatial_car.s >= start_spacial_s) != ahead {
                 continue;
             }
 
@@ -344,7 +517,7 @@ impl Road {
                 continue;
             }
 
-            let other_aabb = c.aabb();
+            let other_aabb = c.frenet_aabb();
             let side_sep = range_dist(
                 aabb.mins[1],
                 aabb.maxs[1],
@@ -353,7 +526,11 @@ impl Road {
             );
 
             if side_sep <= SIDE_MARGIN {
-                let dist = other_aabb.mins[0] - aabb.maxs[0];
+                let dist = if ahead {
+                    other_aabb.mins[0] - aabb.maxs[0]
+                } else {
+                    aabb.mins[0] - other_aabb.maxs[0]
+                };
                 if dist < min_dist {
                     min_dist = dist;
                     min_car_i = Some(i);
@@ -375,7 +552,7 @@ impl Road {
         Some((min_dist, min_car_i?))
     }
 
-    fn min_unsafe_dist(&self, car_i: usize) -> Option<f64> {
+    pub fn min_unsafe_dist(&self, car_i: usize) -> Option<f64> {
         let safety_margin_high = self.params.cost.safety_margin_high;
 
         let car = &self.cars[car_i];
@@ -385,16 +562,17 @@ impl Road {
 
         let pose = car.pose();
         let shape = car.shape();
-        let aabb = shape.compute_aabb(&pose);
+        // separations along and across the road
+        let aabb = car.frenet_aabb();
         for (i, c) in self.cars.iter().enumerate() {
             if i == car_i {
                 continue;
             }
-            if (c.x() - car.x()).abs() >= dist_thresh {
+            if (c.s() - car.s()).abs() >= dist_thresh {
                 continue;
             }
 
-            let other_aabb = c.shape().compute_aabb(&c.pose());
+            let other_aabb = c.frenet_aabb();
             let side_sep = range_dist(
                 aabb.mins[1],
                 aabb.maxs[1],
@@ -478,14 +656,21 @@ impl Road {
                 let target_steer = control.choose_steer(self, car_i, &trajectory);
 
                 let car = &mut self.cars[car_i];
-                car.steer = target_steer.max(-PRIUS_MAX_STEER).min(PRIUS_MAX_STEER);
+                car.steer = target_steer.clamp(-PRIUS_MAX_STEER, PRIUS_MAX_STEER);
                 self.cars[car_i].side_control = Some(control);
             }
         }
 
         for car in self.cars.iter_mut() {
             if !car.crashed {
-                car.update(dt);
+                car.update(dt, &self.path);
+            }
+        }
+
+        // driving past the end of an ending lane
+        for car_i in 0..self.cars.len() {
+            if !self.cars[car_i].crashed && !self.car_on_road(&self.cars[car_i]) {
+                self.cars[car_i].crashed = true;
             }
         }
 
@@ -569,7 +754,7 @@ impl Road {
         self.cars_spatial.clear();
         self.cars_spatial
             .extend(self.cars.iter().map(SpatialCar::from));
-        self.cars_spatial.sort_unstable_by(|a, b| a.x.cmp(&b.x));
+        self.cars_spatial.sort_unstable_by_key(|c| c.s);
     }
 
     pub fn update(&mut self, dt: f64) {
@@ -579,6 +764,8 @@ impl Road {
         }
 
         if !(self.is_truth && self.cars[0].crashed) {
+            // here so that forward simulations predict the forced merges too
+            self.force_obstacle_merges();
             self.update_inner(dt);
         }
 
@@ -591,34 +778,13 @@ impl Road {
     }
 
     fn update_cost(&mut self, dt: f64) {
-        let cparams = &self.params.cost;
-        let car = &self.cars[0];
-
-        self.cost.efficiency += cparams.efficiency_weight
-            * cparams.efficiency_speed_cost
-            * (car.preferred_vel - car.vel).abs()
-            * dt
-            * self.cost.discount;
-
-        let min_dist = self.min_unsafe_dist(0);
-        if let Some(min_dist) = min_dist {
-            let penalty = cparams.safety_weight
-                * logistic(change_range(
-                    min_dist,
-                    cparams.safety_margin_low,
-                    cparams.safety_margin_high,
-                    cparams.logistic_map_low,
-                    cparams.logistic_map_high,
-                ));
-            self.cost.safety += penalty * dt * self.cost.discount;
-            if self.debug && penalty > 10.0 {
-                eprintln!(
-                    "{}: safety distance: {:.2} -> penalty {:.2}",
-                    self.timesteps, min_dist, penalty
-                );
-            }
+        for name in self.params.cost.terms.iter() {
+            let (term, component) = self.params.cost_term_registry.get(name);
+            let cost = term.cost_rate(self, dt) * dt * self.cost.discount;
+            self.cost[component] += cost;
         }
 
+        let car = &self.cars[0];
         let policy_id = car.operating_policy_id();
         let last_policy_id = self.last_ego.operating_policy_id();
         if policy_id != last_policy_id {
@@ -642,72 +808,163 @@ impl Road {
             self.switched_ego_policy = false;
         }
 
-        let accel = (car.vel - self.last_ego.vel) / dt;
-        self.cost.accel += cparams.accel_weight * accel.powi(2) * dt * self.cost.discount;
-
-        let theta_accel = (car.theta() - self.last_ego.theta()) / dt;
-        self.cost.steer += cparams.steer_weight * theta_accel.powi(2) * dt * self.cost.discount;
-
+        self.last_ego_accel = self.ego_accel(dt);
         self.last_ego = self.cars[0].clone();
         self.cost.update_discount(dt);
     }
 
-    pub fn draw(&self, r: &mut Rvx) {
-        // draw a 'road'
-        r.draw(
-            Rvx::square()
-                .scale_xy(&[ROAD_LENGTH, LANE_WIDTH * 2.0])
-                .color(RvxColor::GRAY),
-        );
-        r.draw(
-            Rvx::square()
-                .scale_xy(&[ROAD_LENGTH, 0.2])
-                .translate(&[0.0, -LANE_WIDTH])
-                .color(RvxColor::WHITE),
-        );
-        r.draw(
-            Rvx::square()
-                .scale_xy(&[ROAD_LENGTH, 0.2])
-                .translate(&[0.0, LANE_WIDTH])
-                .color(RvxColor::WHITE),
-        );
+    // Time-to-collision and time-headway penalties with the ego's lead and following cars,
+    // so that closing in at speed costs something before the gap itself gets small.
+    pub fn time_gap_cost(&self) -> f64 {
+        let ego = &self.cars[0];
+        let lane_i = ego.current_lane();
+        let mut penalty = 0.0;
+
+        if let Some((dist, lead_i)) = self.dist_clear_ahead_in_lane(0, lane_i) {
+            penalty += self.time_gap_penalty(dist, ego.vel, self.cars[lead_i].vel);
+        }
+        if let Some((dist, follower_i)) = self.dist_clear_behind_in_lane(0, lane_i) {
+            penalty += self.time_gap_penalty(dist, self.cars[follower_i].vel, ego.vel);
+        }
+
+        penalty
+    }
+
+    // for a car at vel, dist behind another at lead_vel
+    fn time_gap_penalty(&self, dist: f64, vel: f64, lead_vel: f64) -> f64 {
+        let cparams = &self.params.cost;
+        let dist = dist.max(0.0);
+        let mut penalty = 0.0;
+
+        if let Some(ttc_weight) = cparams.ttc_weight {
+            let closing_vel = vel - lead_vel;
+            if closing_vel > 0.0 {
+                penalty += ttc_weight * time_shortfall(dist / closing_vel, cparams.ttc_thresh);
+            }
+        }
+        if let Some(headway_weight) = cparams.headway_weight {
+            if vel > 0.0 {
+                penalty += headway_weight * time_shortfall(dist / vel, cparams.headway_thresh);
+            }
+        }
+
+        penalty
+    }
+
+    fn draw_along_path(
+        &self,
+        r: &mut Canvas,
+        low_s: f64,
+        high_s: f64,
+        d: f64,
+        width: f64,
+        color: Color,
+    ) {
+        for (piece_low_s, piece_high_s) in self.path.pieces_between(low_s, high_s) {
+            let mid_s = (piece_low_s + piece_high_s) * 0.5;
+            let center = self.path.to_cartesian(mid_s, d);
+            r.draw(
+                Shape::square()
+                    .scale_xy(&[piece_high_s - piece_low_s, width])
+                    .rot(self.path.heading(mid_s))
+                    .translate(&[center.x, center.y])
+                    .color(color),
+            );
+        }
+    }
+
+    pub fn draw(&self, r: &mut Canvas) {
+        let n_lanes = self.params.n_lanes;
+        let road_low_y = Road::get_lane_y(0) - LANE_WIDTH * 0.5;
+        let road_high_y = Road::get_lane_y(n_lanes - 1) + LANE_WIDTH * 0.5;
 
         if !self.params.graphics_for_paper {
             r.draw(
-                Rvx::text(&format!("{}", self.timesteps), "Arial", 150.0)
+                Shape::text(&format!("{}", self.timesteps), "Arial", 150.0)
                     .rot(-PI / 2.0)
-                    .translate(&[0.0, 5.0 * LANE_WIDTH])
-                    .color(RvxColor::WHITE),
+                    .translate(&[0.0, road_high_y + 4.0 * LANE_WIDTH])
+                    .color(Color::WHITE),
             );
         }
 
         // adjust for ego car
-        r.set_translate_modifier(-self.cars[0].x(), 0.0);
+        let ego_s = self.cars[0].s();
+        let view_center = self.path.to_cartesian(ego_s, 0.0);
+        r.set_translate_modifier(-view_center.x, -view_center.y);
+
+        // draw a 'road' following the reference path around the ego car
+        let low_s = ego_s - ROAD_LENGTH * 0.5;
+        let high_s = ego_s + ROAD_LENGTH * 0.5;
+        self.draw_along_path(
+            r,
+            low_s,
+            high_s,
+            (road_low_y + road_high_y) * 0.5,
+            road_high_y - road_low_y,
+            Color::GRAY,
+        );
+        self.draw_along_path(r, low_s, high_s, road_low_y, 0.2, Color::WHITE);
+        self.draw_along_path(r, low_s, high_s, road_high_y, 0.2, Color::WHITE);
 
-        // draw the dashes in the middle
+        // draw the dashes between each pair of lanes
         let dash_interval = ROAD_DASH_LENGTH + ROAD_DASH_DIST;
-        let dash_offset = (self.cars[0].x() / dash_interval).round() * dash_interval;
-        for dash_i in -15..=15 {
-            r.draw(
-                Rvx::square()
-                    .scale_xy(&[ROAD_DASH_LENGTH, 0.2])
-                    .translate(&[dash_i as f64 * dash_interval + dash_offset, 0.0])
-                    .color(RvxColor::WHITE),
-            );
+        let dash_offset = (ego_s / dash_interval).round() * dash_interval;
+        for lane_i in 1..n_lanes {
+            let dash_y = Road::get_lane_y(lane_i) - LANE_WIDTH * 0.5;
+            for dash_i in -15..=15 {
+                let dash_s = dash_i as f64 * dash_interval + dash_offset;
+                self.draw_along_path(
+                    r,
+                    dash_s - ROAD_DASH_LENGTH * 0.5,
+                    dash_s + ROAD_DASH_LENGTH * 0.5,
+                    dash_y,
+                    0.2,
+                    Color::WHITE,
+                );
+            }
+        }
+
+        // mark where the ending lane stops, and the wall before an on-ramp's merge zone
+        let scenario = &self.params.scenario;
+        if scenario.has_lane_end() {
+            let end_y = Road::get_lane_y(scenario.ending_lane);
+            if scenario.lane_end_s < high_s {
+                let end_low_s = scenario.lane_end_s.max(low_s);
+                self.draw_along_path(r, end_low_s, high_s, end_y, LANE_WIDTH, Color::DARK_GRAY);
+                self.draw_along_path(
+                    r,
+                    end_low_s,
+                    end_low_s + 0.2,
+                    end_y,
+                    LANE_WIDTH,
+                    Color::WHITE,
+                );
+            }
+            if scenario.kind == ScenarioKind::OnRamp && low_s < scenario.merge_start_s {
+                let merge_y = Road::get_lane_y(scenario.merge_lane(n_lanes));
+                self.draw_along_path(
+                    r,
+                    low_s,
+                    scenario.merge_start_s.min(high_s),
+                    (end_y + merge_y) * 0.5,
+                    0.4,
+                    Color::WHITE,
+                );
+            }
         }
 
         // draw the cars
         for (i, car) in self.cars.iter().enumerate() {
             if i == 0 && car.crashed {
-                car.draw(&self.params, r, RvxColor::ORANGE.set_a(0.6));
+                car.draw(&self.params, r, Color::ORANGE.set_a(0.6));
             } else if i == 0 {
-                car.draw(&self.params, r, RvxColor::GREEN.set_a(0.6));
+                car.draw(&self.params, r, Color::GREEN.set_a(0.6));
             } else if car.crashed {
-                car.draw(&self.params, r, RvxColor::RED.set_a(0.6));
+                car.draw(&self.params, r, Color::RED.set_a(0.6));
             } else if car.vel == 0.0 {
-                car.draw(&self.params, r, RvxColor::WHITE.set_a(0.6));
+                car.draw(&self.params, r, Color::WHITE.set_a(0.6));
             } else {
-                car.draw(&self.params, r, RvxColor::BLUE.set_a(0.6));
+                car.draw(&self.params, r, Color::BLUE.set_a(0.6));
             }
         }
     }
@@ -725,23 +982,39 @@ impl Road {
         self.car_traces = None;
     }
 
-    pub fn make_traces(&self, depth_level: u32, include_obstacle_cars: bool) -> Vec<rvx::Shape> {
-        let mut shapes = Vec::new();
+    pub fn make_traces(&self, depth_level: u32, include_obstacle_cars: bool) -> Vec<Trace> {
+        let mut traces = Vec::new();
 
         if self.car_traces.is_none() {
-            return shapes;
+            return traces;
         }
 
         // if depth_level != 2 {
         //     return Vec::new();
         // }
 
-        let traces: &Vec<Vec<(Point3<f64>, u32)>> = self.car_traces.as_ref().unwrap();
-        for (car_i, trace) in traces.iter().enumerate() {
+        let car_traces: &CarTraces = self.car_traces.as_ref().unwrap();
+        for (car_i, trace) in car_traces.iter().enumerate() {
             if trace.is_empty() {
                 continue;
             }
 
+            let kind = if car_i == 0 && self.params.ego_traces_debug {
+                TraceKind::Ego {
+                    depth_level,
+                    crashed: self.cars[0].crashed,
+                    not_safe: self.cost[CostComponent::SAFETY]
+                        > self.last_reset_cost[CostComponent::SAFETY] + 20.0,
+                    policy_id: self.ego_policy().operating_policy().policy_id(),
+                }
+            } else if Some(car_i) == self.params.debug_car_i {
+                TraceKind::DebugCar
+            } else if include_obstacle_cars {
+                TraceKind::ObstacleCar
+            } else {
+                continue;
+            };
+
             // sparsify points that are _really_ close together
             let mut points_2d = trace.iter().map(|(p, _)| p).copied().collect_vec();
             let mut p_i = 0;
@@ -759,72 +1032,7 @@ impl Road {
                 .copied()
                 .collect_vec();
 
-            if car_i == 0 && self.params.ego_traces_debug {
-                // eprintln!("Points in trace: {}", trace.len());
-
-                let crashed = self.cars[0].crashed;
-                let not_safe = self.cost.safety > self.last_reset_cost.safety + 20.0;
-
-                let base_line_color = if crashed {
-                    RvxColor::RED.scale_rgb(0.5)
-                } else if not_safe {
-                    RvxColor::PINK
-                } else {
-                    RvxColor::GREEN
-                };
-
-                let mut line_color = match depth_level {
-                    0 => base_line_color.set_a(0.6),
-                    1 => base_line_color.scale_rgb(0.6).set_a(0.6),
-                    2 => base_line_color.scale_rgb(0.5).set_a(0.6),
-                    3 | _ => base_line_color.scale_rgb(0.4).set_a(0.6),
-                };
-
-                let mut line_width = match depth_level {
-                    0 => 12.0,
-                    1 => 6.0,
-                    2 => 3.0,
-                    3 | _ => 1.5,
-                };
-                if self.params.graphics_for_paper {
-                    line_color = base_line_color.scale_rgb(0.6).set_a(0.6);
-                    line_width = 4.0;
-                }
-                if crashed || not_safe {
-                    line_width += 4.0;
-                }
-
-                shapes.push(Rvx::lines(&points, line_width).color(line_color));
-
-                let dot_color = match self.ego_policy().operating_policy().policy_id() {
-                    1 | 3 => RvxColor::RED,
-                    4 => RvxColor::BLUE,
-                    _ => RvxColor::BLACK,
-                };
-
-                shapes.push(Rvx::array(
-                    Rvx::circle().scale(0.15).color(dot_color.set_a(0.4)),
-                    &points,
-                ));
-
-            // label the points with the policy_id active at that point in time
-            // for (xyt, policy_id) in trace.iter() {
-            //     shapes.push(
-            //         Rvx::text(&format!("{}", policy_id), "Arial", 30.0)
-            //             .rot(-PI / 2.0)
-            //             .translate(&[xyt.x, xyt.y])
-            //             .color(RvxColor::BLACK),
-            //     );
-            // }
-            } else if Some(car_i) == self.params.debug_car_i {
-                shapes.push(Rvx::lines(&points, 6.0).color(RvxColor::DARK_GRAY.set_a(0.9)));
-                // shapes.push(Rvx::array(
-                //     Rvx::circle().scale(0.2).color(RvxColor::DARK_GRAY),
-                //     &points,
-                // ));
-            } else if include_obstacle_cars {
-                shapes.push(Rvx::lines(&points, 6.0).color(RvxColor::WHITE.set_a(0.5)));
-            }
+            traces.push(Trace { kind, points });
 
             // let draw_trace = trace[1];
             // let mut draw_car = Car::new(car_i, 0);
@@ -832,13 +1040,13 @@ impl Road {
             // draw_car.y = draw_trace.y;
             // draw_car.theta = draw_trace.z;
             // if car_i == 0 {
-            //     draw_car.draw(r, RvxColor::GREEN.set_a(0.5));
+            //     draw_car.draw(r, Color::GREEN.set_a(0.5));
             // } else {
-            //     draw_car.draw(r, RvxColor::DARK_GRAY.set_a(0.5));
+            //     draw_car.draw(r, Color::DARK_GRAY.set_a(0.5));
             // }
         }
 
-        shapes
+        traces
     }
 
     pub fn get_lane_y(lane_i: i32) -> f64 {
@@ -849,21 +1057,21 @@ impl Road {
         (y / LANE_WIDTH + 0.5).round() as i32
     }
 
-    pub fn respawn_obstacle_cars(&mut self, rng: &mut StdRng) {
+    pub fn respawn_obstacle_cars(&mut self, rng: &mut ChaCha12Rng) {
         let remove_ahead_beyond = self.params.spawn.remove_ahead_beyond;
         let remove_behind_beyond = self.params.spawn.remove_behind_beyond;
         let place_ahead_beyond = self.params.spawn.place_ahead_beyond;
 
-        let ego_x = self.cars[0].x();
+        let ego_s = self.cars[0].s();
         for car_i in 1..self.cars.len() {
-            let car_x = self.cars[car_i].x();
-            if car_x < ego_x - remove_behind_beyond || car_x > ego_x + remove_ahead_beyond {
+            let car_s = self.cars[car_i].s();
+            if car_s < ego_s - remove_behind_beyond || car_s > ego_s + remove_ahead_beyond {
                 loop {
-                    let mut new_car = Car::random_new(&self.params, car_i, rng);
-                    let new_dx = rng.gen_range(place_ahead_beyond..remove_ahead_beyond);
-                    new_car.set_x(ego_x + new_dx);
+                    let mut new_car = Car::random_new(&self.params, &self.path, car_i, rng);
+                    let new_ds = rng.gen_range(place_ahead_beyond..remove_ahead_beyond);
+                    new_car.set_frenet(&self.path, ego_s + new_ds, new_car.d());
 
-                    if !self.collides_any_car(&new_car) {
+                    if !self.collides_any_car(&new_car) && self.car_on_road(&new_car) {
                         self.cars[car_i] = new_car;
                         break;
                     }
@@ -873,21 +1081,41 @@ impl Road {
     }
 
     pub fn save_particle(&mut self) {
-        self.particle = Some(Particle {
+        self.save_weighted_particle(1.0);
+    }
+
+    pub fn save_weighted_particle(&mut self, weight: f64) {
+        let mut particle = self.current_particle();
+        particle.weight = weight;
+        self.particle = Some(particle);
+    }
+
+    // the policies the cars are following now, under this road's sample id
+    // and with the importance weight of its particle
+    pub fn current_particle(&self) -> Particle {
+        Particle {
             id: self.sample_id.unwrap(),
             policies: self
                 .cars
                 .iter()
                 .map(|c| c.side_policy.clone().unwrap())
                 .collect(),
-        });
+            weight: self.particle.as_ref().map_or(1.0, |p| p.weight),
+        }
     }
 }
 
-#[derive(Clone, PartialOrd)]
+#[derive(Clone, PartialOrd, Serialize, Deserialize)]
 pub struct Particle {
     pub id: usize,
     pub policies: Vec<SidePolicy>,
+    // the importance weight, when sampled from something other than the belief
+    #[serde(default = "default_particle_weight")]
+    pub weight: f64,
+}
+
+fn default_particle_weight() -> f64 {
+    1.0
 }
 
 impl std::fmt::Debug for Particle {
@@ -906,6 +1134,7 @@ impl PartialEq for Particle {
 mod tests {
     use super::*;
     use approx::assert_abs_diff_eq;
+    use rand::SeedableRng;
 
     #[test]
     fn test_logistic_change_range() {
@@ -950,4 +1179,176 @@ mod tests {
             epsilon = 1e-6
         );
     }
+
+    #[test]
+    fn time_shortfall_grows_below_thresh() {
+        assert_eq!(time_shortfall(4.0, 3.0), 0.0);
+        assert_eq!(time_shortfall(3.0, 3.0), 0.0);
+        assert_abs_diff_eq!(time_shortfall(1.5, 3.0), 0.25, epsilon = 1e-12);
+        assert_abs_diff_eq!(time_shortfall(0.0, 3.0), 1.0, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn time_gap_cost_uses_the_nearest_cars_in_the_ego_lane() {
+        let mut params = Parameters::new().unwrap();
+        params.cost.ttc_weight = None;
+        params.cost.headway_weight = Some(1.0);
+        params.cost.headway_thresh = 2.0;
+        let params = Arc::new(params);
+
+        let mut road = Road::new(params.clone());
+        let path = road.path.clone();
+        let ego_s = 100.0;
+        let ego_d = Road::get_lane_y(0);
+        road.cars[0].set_frenet(&path, ego_s, ego_d);
+        road.cars[0].vel = 10.0;
+        let length = road.cars[0].length;
+
+        // (lane, gap ahead of the ego, or behind if negative, vel)
+        let cars: [(i32, f64, f64); 5] = [
+            (0, 10.0, 5.0),
+            (0, 40.0, 5.0),
+            (1, 2.0, 5.0),
+            (0, -5.0, 15.0),
+            (0, -30.0, 15.0),
+        ];
+        for (lane_i, gap, vel) in cars {
+            let car_i = road.cars.len();
+            let mut car = Car::new(&params, &path, car_i, lane_i);
+            let s = ego_s + gap.signum() * (gap.abs() + length);
+            car.set_frenet(&path, s, car.d());
+            car.vel = vel;
+            road.cars.push(car);
+        }
+        road.update_cars_spatial();
+
+        // headway of 1 second to the lead, and a third of a second for the follower
+        let headway = time_shortfall(1.0, 2.0) + time_shortfall(1.0 / 3.0, 2.0);
+        assert_abs_diff_eq!(road.time_gap_cost(), headway, epsilon = 1e-6);
+
+        // both gaps are closing at 5 m/s
+        let mut params = (*params).clone();
+        params.cost.ttc_weight = Some(1.0);
+        params.cost.ttc_thresh = 4.0;
+        params.cost.headway_weight = None;
+        road.params = Arc::new(params);
+        let ttc = time_shortfall(2.0, 4.0) + time_shortfall(1.0, 4.0);
+        assert_abs_diff_eq!(road.time_gap_cost(), ttc, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn forward_simulations_force_merges() {
+        let mut params = Parameters::new().unwrap();
+        params.run_fast = true;
+        params.scenario.kind = crate::scenario::ScenarioKind::LaneDrop;
+        let params = Arc::new(params);
+        let scenario = &params.scenario;
+        let merge_lane = scenario.merge_lane(params.n_lanes);
+
+        let mut road = Road::new(params.clone());
+        // a car still set on keeping to the ending lane, well inside the merge zone
+        let mut car = Car::new(&params, &road.path, 1, scenario.ending_lane);
+        let policies = make_obstacle_vehicle_policy_choices(&params);
+        car.side_policy = Some(policies[2 * scenario.ending_lane as usize].clone());
+        car.set_frenet(&road.path, scenario.merge_start_s + 10.0, car.d());
+        road.cars.push(car);
+
+        let mut sim_road = road.clone();
+        sim_road.take_update_steps(params.physics_dt, params.physics_dt);
+        let target_lane_i = match sim_road.cars[1].side_policy.as_ref().unwrap() {
+            SidePolicy::LaneChangePolicy(policy) => policy.target_lane_i(),
+            _ => None,
+        };
+        assert_eq!(target_lane_i, Some(merge_lane));
+    }
+
+    #[test]
+    fn road_can_cross_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Road>();
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut params = Parameters::new().unwrap();
+        params.run_fast = true;
+        let params = Arc::new(params);
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+
+        let mut road = Road::new(params.clone());
+        while road.cars.len() < params.n_cars + 1 {
+            road.add_random_car(&mut rng);
+        }
+        road.init_belief();
+        for _ in 0..50 {
+            road.update_belief();
+            road.update(params.physics_dt);
+        }
+
+        let json = serde_json::to_string(&road).unwrap();
+        let mut restored: Road = serde_json::from_str(&json).unwrap();
+        restored.params = params.clone();
+        restored.restore_caches();
+
+        for _ in 0..50 {
+            road.update_belief();
+            road.update(params.physics_dt);
+            restored.update_belief();
+            restored.update(params.physics_dt);
+        }
+        assert_eq!(road.cost, restored.cost);
+        for (car, restored_car) in road.cars.iter().zip(restored.cars.iter()) {
+            assert_eq!(car.pose(), restored_car.pose());
+            assert_eq!(car.s(), restored_car.s());
+        }
+    }
+
+    #[test]
+    fn standard_cost_terms_keep_the_pre_change_cost() {
+        let mut params = Parameters::new().unwrap();
+        params.n_cars = 10;
+        params.run_fast = true;
+        params.cost.ttc_weight = Some(100.0);
+        params.cost.headway_weight = Some(50.0);
+        params.cost.discount_factor = 0.9;
+        params.scenario.kind = ScenarioKind::LaneDrop;
+        params.scenario.merge_start_s = 0.0;
+        let params = Arc::new(params);
+
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+        let mut road = Road::new(params.clone());
+        road.cost = Cost::new(params.cost.discount_factor, 1.0);
+        while road.cars.len() < params.n_cars + 1 {
+            road.add_random_car(&mut rng);
+        }
+        // a stopped car close ahead of the ego, for the safety and time gap terms
+        let mut stopped_car = Car::random_new(&params, &road.path, road.cars.len(), &mut rng);
+        let ego = &road.cars[0];
+        stopped_car.set_frenet(&road.path, ego.s() + ego.length + 12.0, ego.d());
+        stopped_car.vel = 0.0;
+        road.cars.push(stopped_car);
+        // and off the lane center, for the steer term
+        let path = road.path.clone();
+        let ego = &mut road.cars[0];
+        ego.set_frenet(&path, ego.s(), ego.d() + 0.5);
+        ego.vel = 10.0;
+        road.last_ego = road.cars[0].clone();
+        road.update_cars_spatial();
+        road.init_belief();
+
+        for _ in 0..200 {
+            road.update(params.physics_dt);
+        }
+        // as update() computed them before the cost was made up of cost terms
+        let expected = [
+            (CostComponent::EFFICIENCY, 11.584291172920143),
+            (CostComponent::SAFETY, 34.70731821142561),
+            (CostComponent::ACCEL, 4.735137108639121),
+            (CostComponent::STEER, 0.2700836053751913),
+            (CostComponent::DEADLINE, 4.428924024840258),
+        ];
+        for (component, cost) in expected {
+            assert_abs_diff_eq!(road.cost[component], cost, epsilon = 1e-9);
+        }
+    }
 }
@@ -1,30 +1,50 @@
-use rand::prelude::StdRng;
+use rand_chacha::ChaCha12Rng;
 
-use crate::{cost::Cost, road::Road, side_policies::SidePolicy};
+use crate::{
+    cost::Cost, crash_probability::CrashProbability, road::Road, side_policies::SidePolicy,
+    trace::Trace,
+};
 
+// Forward simulations of sampled scenarios, each with an importance weight
+// for when they weren't sampled from the belief itself.
 #[derive(Clone)]
 pub struct RoadSet {
     roads: Vec<Road>,
+    weights: Vec<f64>,
 }
 
 impl RoadSet {
-    pub fn new(mut roads: Vec<Road>) -> Self {
+    pub fn new(roads: Vec<Road>) -> Self {
+        let weights = vec![1.0; roads.len()];
+        Self::new_weighted(roads, weights)
+    }
+
+    pub fn new_weighted(mut roads: Vec<Road>, weights: Vec<f64>) -> Self {
+        assert_eq!(roads.len(), weights.len());
         for (i, road) in roads.iter_mut().enumerate() {
             road.sample_id = Some(i);
         }
 
-        Self { roads }
+        Self { roads, weights }
     }
 
-    pub fn new_samples(road: &Road, rng: &mut StdRng, n: usize) -> Self {
+    pub fn new_samples(road: &Road, rng: &mut ChaCha12Rng, n: usize) -> Self {
         assert!(n > 0);
 
         if road.params.true_belief_sample_only {
             return Self {
                 roads: vec![road.sim_estimate()],
+                weights: vec![1.0],
             };
         }
 
+        if let Some(uniform_mix) = road.params.proposal_mix {
+            let (roads, weights) = (0..n)
+                .map(|_| road.sample_belief_proposal(rng, uniform_mix))
+                .unzip();
+            return Self::new_weighted(roads, weights);
+        }
+
         let mut roads = Vec::with_capacity(n);
         for _ in 0..n {
             roads.push(road.sample_belief(rng));
@@ -75,7 +95,7 @@ impl RoadSet {
         }
     }
 
-    pub fn make_traces(&self, depth_level: u32, include_obstacle_cars: bool) -> Vec<rvx::Shape> {
+    pub fn make_traces(&self, depth_level: u32, include_obstacle_cars: bool) -> Vec<Trace> {
         let mut traces = Vec::new();
         for road in self.roads.iter() {
             traces.append(&mut road.make_traces(depth_level, include_obstacle_cars));
@@ -83,8 +103,24 @@ impl RoadSet {
         traces
     }
 
+    // the self-normalized importance-weighted mean cost
     pub fn cost(&self) -> Cost {
-        self.roads.iter().map(|r| r.cost).sum::<Cost>() / self.roads.len() as f64
+        let sum_weights = self.weights.iter().sum::<f64>();
+        self.roads
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(r, w)| r.cost * *w)
+            .sum::<Cost>()
+            / sum_weights
+    }
+
+    // how often the ego car has crashed in these simulations so far
+    pub fn crash_probability(&self) -> CrashProbability {
+        let mut crash = CrashProbability::new();
+        for (road, weight) in self.roads.iter().zip(self.weights.iter()) {
+            crash.push(road.ego_crashed(), *weight);
+        }
+        crash
     }
 
     #[allow(unused)]
@@ -92,7 +128,12 @@ impl RoadSet {
         self.roads.iter_mut()
     }
 
-    pub fn pop(&mut self) -> Road {
-        self.roads.remove(0)
+    pub fn is_empty(&self) -> bool {
+        self.roads.is_empty()
+    }
+
+    // the next road, with its importance weight
+    pub fn pop(&mut self) -> (Road, f64) {
+        (self.roads.remove(0), self.weights.remove(0))
     }
 }
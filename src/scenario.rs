@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ScenarioKind {
+    // random cars on an endless road
+    Random,
+    // the ending lane terminates, and its cars have to merge out before the end
+    LaneDrop,
+    // like LaneDrop, but the ending lane is an on-ramp walled off from the
+    // rest of the road until the merge zone starts
+    OnRamp,
+}
+
+impl std::fmt::Display for ScenarioKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Random => write!(f, "random"),
+            Self::LaneDrop => write!(f, "lane_drop"),
+            Self::OnRamp => write!(f, "on_ramp"),
+        }
+    }
+}
+
+impl std::str::FromStr for ScenarioKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "random" => Ok(Self::Random),
+            "lane_drop" => Ok(Self::LaneDrop),
+            "on_ramp" => Ok(Self::OnRamp),
+            _ => Err(format!("Invalid ScenarioKind '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScenarioParameters {
+    pub kind: ScenarioKind,
+    pub ending_lane: i32,
+    pub merge_start_s: f64,
+    pub lane_end_s: f64,
+}
+
+impl ScenarioParameters {
+    pub fn has_lane_end(&self) -> bool {
+        self.kind != ScenarioKind::Random
+    }
+
+    pub fn lane_exists_at(&self, lane_i: i32, s: f64) -> bool {
+        !self.has_lane_end() || lane_i != self.ending_lane || s < self.lane_end_s
+    }
+
+    pub fn in_merge_zone(&self, lane_i: i32, s: f64) -> bool {
+        self.has_lane_end()
+            && lane_i == self.ending_lane
+            && s >= self.merge_start_s
+            && s < self.lane_end_s
+    }
+
+    pub fn lane_change_allowed(&self, from_lane_i: i32, to_lane_i: i32, s: f64) -> bool {
+        if from_lane_i == to_lane_i {
+            return true;
+        }
+        if !self.lane_exists_at(to_lane_i, s) {
+            return false;
+        }
+        let touches_ramp = from_lane_i == self.ending_lane || to_lane_i == self.ending_lane;
+        !(self.kind == ScenarioKind::OnRamp && touches_ramp && s < self.merge_start_s)
+    }
+
+    // the neighboring lane that cars in the ending lane merge into
+    pub fn merge_lane(&self, n_lanes: i32) -> i32 {
+        if self.ending_lane + 1 < n_lanes {
+            self.ending_lane + 1
+        } else {
+            self.ending_lane - 1
+        }
+    }
+}
@@ -1,13 +1,14 @@
 use parry2d_f64::na::Point2;
-use rvx::Rvx;
+use serde::{Deserialize, Serialize};
 
+use crate::graphics::Canvas;
 use crate::Road;
 
 use crate::open_loop_policy::OpenLoopSideControl;
 use crate::pure_pursuit::PurePursuitPolicy;
 
 #[enum_dispatch]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SideControl {
     PurePursuitPolicy,
     OpenLoopSideControl,
@@ -17,7 +18,7 @@ pub enum SideControl {
 pub trait SideControlTrait {
     fn choose_steer(&mut self, road: &Road, car_i: usize, trajectory: &[Point2<f64>]) -> f64;
 
-    fn draw(&self, r: &mut Rvx) {
+    fn draw(&self, r: &mut Canvas) {
         let _ = r;
     }
 }
@@ -1,4 +1,5 @@
 use parry2d_f64::na::Point2;
+use serde::{Deserialize, Serialize};
 
 use crate::delayed_policy::DelayedPolicy;
 use crate::lane_change_policy::LaneChangePolicy;
@@ -6,7 +7,7 @@ use crate::open_loop_policy::OpenLoopPolicy;
 use crate::Road;
 
 #[enum_dispatch]
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum SidePolicy {
     LaneChangePolicy,
     DelayedPolicy,
@@ -0,0 +1,335 @@
+use std::{
+    f64::consts::PI,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arg_parameters::Parameters,
+    cost::Cost,
+    graphics::{Canvas, Color, RvxWindow, Shape},
+    mpdm::make_obstacle_vehicle_policy_choices,
+    planner::Planner,
+    rate_timer::RateTimer,
+    recording::Recorder,
+    reward::Reward,
+    road::Road,
+    svg::SvgExporter,
+    trace::Trace,
+    traffic::Traffic,
+};
+
+// everything needed to continue the simulation is serialized into snapshots,
+// so that a single planner call can be replayed from a given timestep
+#[derive(Serialize, Deserialize)]
+pub struct State {
+    scenario_rng: ChaCha12Rng,
+    respawn_rng: ChaCha12Rng,
+    policy_rng: ChaCha12Rng,
+    pub params: Arc<Parameters>,
+    pub road: Road,
+    #[serde(skip)]
+    traces: Vec<Trace>,
+    #[serde(skip)]
+    window: Option<RvxWindow>,
+    #[serde(skip)]
+    exporter: Option<SvgExporter>,
+    pub timesteps: u32,
+    pub reward: Reward,
+    #[serde(skip)]
+    paper_graphics_sets: Vec<Vec<Shape>>,
+    traffic: Option<Traffic>,
+    next_switch_i: usize,
+    // no replanning before this timestep, while following a committed plan
+    #[serde(default)]
+    committed_until: u32,
+    #[serde(skip)]
+    recorder: Option<Recorder>,
+}
+
+impl State {
+    pub fn new(params: Arc<Parameters>) -> Self {
+        let mut full_seed = [0; 32];
+        full_seed[0..8].copy_from_slice(&params.rng_seed.to_le_bytes());
+
+        let mut scenario_rng = ChaCha12Rng::from_seed(full_seed);
+
+        let traffic = params.traffic_file.as_ref().map(|file_name| {
+            Traffic::load(file_name)
+                .unwrap_or_else(|e| panic!("Could not load traffic file {}: {}", file_name, e))
+        });
+
+        let mut road = Road::new(params.clone());
+        // road.add_obstacle(100.0, 0);
+        if let Some(traffic) = traffic.as_ref() {
+            traffic.populate(&mut road);
+        } else {
+            while road.cars.len() < params.n_cars + 1 {
+                road.add_random_car(&mut scenario_rng);
+            }
+        }
+        road.init_belief();
+
+        Self {
+            scenario_rng,
+            respawn_rng: ChaCha12Rng::from_seed(full_seed),
+            policy_rng: ChaCha12Rng::from_seed(full_seed),
+            road,
+            window: None,
+            exporter: None,
+            timesteps: 0,
+            params,
+            traces: Vec::new(),
+            reward: Default::default(),
+            paper_graphics_sets: Vec::new(),
+            traffic,
+            next_switch_i: 0,
+            committed_until: 0,
+            recorder: None,
+        }
+    }
+
+    pub fn save_snapshot(&self, file_name: &str) {
+        let file = std::fs::File::create(file_name)
+            .unwrap_or_else(|e| panic!("Could not create snapshot file {}: {}", file_name, e));
+        serde_json::to_writer(std::io::BufWriter::new(file), self)
+            .unwrap_or_else(|e| panic!("Could not write snapshot file {}: {}", file_name, e));
+    }
+
+    // The snapshot's own parameters are replaced by the given ones, so that
+    // debugging options and planner settings can be changed on resume.
+    pub fn load_snapshot(file_name: &str, params: Arc<Parameters>) -> Self {
+        let file = std::fs::File::open(file_name)
+            .unwrap_or_else(|e| panic!("Could not open snapshot file {}: {}", file_name, e));
+        let mut state: Self = serde_json::from_reader(std::io::BufReader::new(file))
+            .unwrap_or_else(|e| panic!("Could not read snapshot file {}: {}", file_name, e));
+        state.road.params = params.clone();
+        state.params = params;
+        state.road.restore_caches();
+        state
+    }
+
+    fn update_graphics(&mut self) {
+        let export_frame = match self.exporter.as_ref() {
+            Some(exporter) => self.timesteps % exporter.interval() == 0,
+            None => false,
+        };
+        let paper_frame =
+            self.params.graphics_for_paper && self.timesteps >= 1100 && self.timesteps % 50 == 25;
+        let needed =
+            self.window.is_some() || export_frame || (paper_frame && self.exporter.is_some());
+        if !needed {
+            return;
+        }
+
+        let mut canvas = Canvas::new();
+        self.road.draw(&mut canvas);
+        let params = &self.params;
+        canvas.draw_all(self.traces.iter().flat_map(|trace| trace.shapes(params)));
+
+        if paper_frame {
+            self.paper_graphics_sets.push(canvas.shapes().to_vec());
+        }
+
+        canvas.set_global_rot(-PI / 2.0);
+        if let Some(window) = self.window.as_mut() {
+            window.show(&canvas);
+        }
+        if export_frame {
+            let exporter = self.exporter.as_ref().unwrap();
+            exporter.save(&canvas, &format!("frame_{:06}", self.timesteps));
+        }
+    }
+
+    // advances the simulation by one timestep, replanning first when it's time to
+    pub fn update(&mut self, dt: f64, planner: &mut dyn Planner) {
+        let replan_interval = (self.params.replan_dt / self.params.physics_dt).round() as u32;
+
+        // method chooses the ego policy
+        let policy_rng = &mut self.policy_rng;
+        let replanned = self.timesteps % replan_interval == 0
+            && self.timesteps >= self.committed_until
+            && !self.road.cars[0].crashed;
+        if replanned {
+            let replan_real_time_start = Instant::now();
+
+            let plan = planner.choose_policy(&self.params, &self.road, policy_rng);
+
+            self.reward
+                .planning_times
+                .push(replan_real_time_start.elapsed().as_secs_f64());
+            if let Some(n_trials) = plan.n_trials {
+                self.reward.planning_trials.push(n_trials);
+            }
+            if let Some(n_trials_saved) = plan.n_trials_saved {
+                self.reward.planning_trials_saved.push(n_trials_saved);
+            }
+
+            self.traces = plan.traces;
+
+            if let Some(commit_t) = plan.commit_t {
+                self.committed_until =
+                    self.timesteps + (commit_t / self.params.physics_dt).round() as u32;
+            }
+
+            if let Some(policy) = plan.policy {
+                self.road.set_ego_policy(policy);
+            }
+        }
+
+        // random policy changes for the obstacle vehicles
+        let policy_change_interval =
+            (self.params.nonego_policy_change_dt / self.params.physics_dt).round() as u32;
+        let timesteps = self.timesteps;
+        let random_policy_changes = match self.traffic.as_ref() {
+            Some(traffic) => traffic.random_policy_changes,
+            None => true,
+        };
+        if random_policy_changes && self.timesteps % policy_change_interval == 0 {
+            let rng = &mut self.scenario_rng;
+            let policy_choices = make_obstacle_vehicle_policy_choices(&self.params);
+
+            for c in self.road.cars[1..].iter_mut() {
+                if rng.gen_bool(
+                    self.params.nonego_policy_change_prob * self.params.nonego_policy_change_dt,
+                ) {
+                    let new_policy_i = rng.gen_range(0..policy_choices.len());
+                    let new_policy = policy_choices[new_policy_i].clone();
+
+                    if self.road.debug && self.params.obstacle_car_debug {
+                        eprintln_f!("{timesteps}: obstacle car {c.car_i} switching to policy {new_policy_i}: {new_policy:?}");
+                    }
+
+                    c.side_policy = Some(new_policy);
+                }
+            }
+        }
+
+        // scripted policy changes from the traffic file
+        if let Some(traffic) = self.traffic.as_ref() {
+            while let Some(switch) = traffic.switches.get(self.next_switch_i) {
+                if switch.t > self.road.t {
+                    break;
+                }
+                let new_policy = switch.policy.make_policy(&self.params);
+                if self.road.debug && self.params.obstacle_car_debug {
+                    eprintln_f!("{timesteps}: obstacle car {switch.car_i} switching to scripted policy {new_policy:?}");
+                }
+                self.road.cars[switch.car_i].side_policy = Some(new_policy);
+                self.next_switch_i += 1;
+            }
+        }
+
+        // actual simulation
+        self.road.update_belief();
+        self.road.update(dt);
+        self.road.respawn_obstacle_cars(&mut self.respawn_rng);
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            let planner_traces = if replanned {
+                Some(self.traces.as_slice())
+            } else {
+                None
+            };
+            recorder.record_step(&self.road, planner_traces);
+        }
+
+        // final reporting reward (separate from cost function, though similar)
+        self.reward.dist_travelled += self.road.cars[0].vel * dt;
+        if self.road.cars[0].crashed {
+            self.reward.crashed = true;
+        }
+
+        self.timesteps += 1;
+    }
+
+    // the total cost, and the reward with its end-of-run metrics filled in
+    pub fn finish(mut self) -> (Cost, Reward) {
+        self.reward.end_t = self.road.t;
+        self.reward.avg_vel = self.reward.dist_travelled / self.road.t;
+        self.reward.calculate_timestep_metrics();
+
+        (self.road.cost, self.reward)
+    }
+}
+
+pub fn run_with_parameters(params: Parameters, planner: &dyn Planner) -> (Cost, Reward) {
+    let params = Arc::new(params);
+    let mut planner = planner.new_run();
+
+    let mut state = match params.resume_from.as_ref() {
+        Some(file_name) => State::load_snapshot(file_name, params.clone()),
+        None => State::new(params),
+    };
+
+    if let Some(file_name) = state.params.record_file.as_ref() {
+        state.recorder = Some(Recorder::create(file_name, &state.road));
+    }
+
+    let use_graphics = !state.params.run_fast;
+
+    if use_graphics {
+        state.window = Some(RvxWindow::new());
+    }
+    // exporting frames works headless, even when running fast
+    if state.params.export.dir.is_some() {
+        state.exporter = Some(SvgExporter::new(&state.params.export));
+    }
+
+    let mut rate = RateTimer::new(Duration::from_millis(
+        (state.params.physics_dt * 1000.0 / state.params.graphics_speedup) as u64,
+    ));
+
+    while state.timesteps < state.params.max_steps {
+        if Some(state.timesteps) == state.params.snapshot_at_step {
+            state.save_snapshot(&state.params.snapshot_file);
+        }
+
+        state.update(state.params.physics_dt, planner.as_mut());
+
+        state.update_graphics();
+        if use_graphics {
+            rate.wait_until_ready();
+        }
+
+        // if i == 1000 {
+        //     for side_policy in state.road.cars[0].side_policy.iter_mut() {
+        //         *side_policy = side_policies::SidePolicy::LaneChangePolicy(
+        //             lane_change_policy::LaneChangePolicy::new(1, LANE_CHANGE_TIME, None),
+        //         );
+        //     }
+        // }
+    }
+
+    if state.params.graphics_for_paper && !state.paper_graphics_sets.is_empty() {
+        let mut canvas = Canvas::new();
+        canvas.draw(Shape::square().scale(1000.0).color(Color::LIGHT_GRAY));
+
+        let x = 0.0;
+        let mut y = 0.0;
+
+        for shape_set in state.paper_graphics_sets.iter() {
+            canvas.set_translate_modifier(x, y);
+            canvas.draw_all(shape_set.iter().cloned());
+            y -= 9.0;
+        }
+        canvas.set_global_rot(-PI / 2.0);
+
+        if let Some(window) = state.window.as_mut() {
+            window.show(&canvas);
+        }
+        if let Some(exporter) = state.exporter.as_ref() {
+            exporter.save(&canvas, "paper");
+        }
+    }
+
+    if use_graphics {
+        std::thread::sleep(Duration::from_millis(1000));
+    }
+
+    state.finish()
+}
@@ -0,0 +1,202 @@
+use std::fmt::Write;
+
+use nalgebra::{Matrix3, Vector3};
+
+use crate::{
+    arg_parameters::ExportParameters,
+    graphics::{Canvas, Color, Shape, ShapeKind},
+};
+
+// Saves canvases as svg files, for generating figures and videos without a display.
+pub struct SvgExporter {
+    params: ExportParameters,
+}
+
+fn color_attrs(color: Option<Color>) -> (String, f64) {
+    let [r, g, b, a] = color.unwrap_or(Color::WHITE).rgba();
+    let to_byte = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    (
+        format!("#{:02x}{:02x}{:02x}", to_byte(r), to_byte(g), to_byte(b)),
+        a,
+    )
+}
+
+fn matrix_attr(m: &Matrix3<f64>) -> String {
+    format!(
+        "matrix({:.4} {:.4} {:.4} {:.4} {:.4} {:.4})",
+        m[(0, 0)],
+        m[(1, 0)],
+        m[(0, 1)],
+        m[(1, 1)],
+        m[(0, 2)],
+        m[(1, 2)]
+    )
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl SvgExporter {
+    pub fn new(params: &ExportParameters) -> Self {
+        let dir = params.dir.as_ref().expect("exporting requires export.dir");
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|e| panic!("Could not create export directory {}: {}", dir, e));
+        Self {
+            params: params.clone(),
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.params.interval
+    }
+
+    // Line widths and font sizes are in pixels, like with rvx,
+    // while everything else is in meters.
+    fn write_shape(&self, out: &mut String, shape: &Shape, parent: &Matrix3<f64>) {
+        let ppm = self.params.pixels_per_meter;
+        let m = parent * shape.matrix();
+        let (fill, opacity) = color_attrs(shape.color);
+
+        match &shape.kind {
+            ShapeKind::Square => {
+                writeln!(
+                    out,
+                    r#"<rect x="-0.5" y="-0.5" width="1" height="1" transform="{}" fill="{}" fill-opacity="{}"/>"#,
+                    matrix_attr(&m),
+                    fill,
+                    opacity
+                )
+                .unwrap();
+            }
+            ShapeKind::Circle => {
+                writeln!(
+                    out,
+                    r#"<circle r="0.5" transform="{}" fill="{}" fill-opacity="{}"/>"#,
+                    matrix_attr(&m),
+                    fill,
+                    opacity
+                )
+                .unwrap();
+            }
+            ShapeKind::Text { text, font, size } => {
+                // flip back, since the whole drawing has y pointing up
+                write!(
+                    out,
+                    r#"<text transform="{} scale(1 -1)" font-family="{}" font-size="{:.3}" fill="{}" fill-opacity="{}">"#,
+                    matrix_attr(&m),
+                    font,
+                    size / ppm,
+                    fill,
+                    opacity
+                )
+                .unwrap();
+                for (line_i, line) in text.lines().enumerate() {
+                    let dy = if line_i == 0 { "0" } else { "1.2em" };
+                    write!(
+                        out,
+                        r#"<tspan x="0" dy="{}">{}</tspan>"#,
+                        dy,
+                        escape_text(line)
+                    )
+                    .unwrap();
+                }
+                writeln!(out, "</text>").unwrap();
+            }
+            ShapeKind::Lines { points, width } => {
+                let points = points
+                    .chunks_exact(2)
+                    .map(|p| {
+                        let p = m * Vector3::new(p[0], p[1], 1.0);
+                        format!("{:.3},{:.3}", p.x, p.y)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                writeln!(
+                    out,
+                    r#"<polyline points="{}" fill="none" stroke="{}" stroke-opacity="{}" stroke-width="{:.3}" stroke-linejoin="round" stroke-linecap="round"/>"#,
+                    points,
+                    fill,
+                    opacity,
+                    width / ppm
+                )
+                .unwrap();
+            }
+            ShapeKind::Array { shape, points } => {
+                for p in points.chunks_exact(2) {
+                    let at = m * Matrix3::new(1.0, 0.0, p[0], 0.0, 1.0, p[1], 0.0, 0.0, 1.0);
+                    self.write_shape(out, shape, &at);
+                }
+            }
+        }
+    }
+
+    pub fn to_svg(&self, canvas: &Canvas) -> String {
+        let params = &self.params;
+        let mut out = String::new();
+        writeln!(
+            out,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            w = params.width,
+            h = params.height
+        )
+        .unwrap();
+        writeln!(out, r#"<rect width="100%" height="100%" fill="black"/>"#).unwrap();
+        // the view is centered on the origin, with y pointing up
+        writeln!(
+            out,
+            r#"<g transform="translate({} {}) scale({} {}) rotate({:.4})">"#,
+            params.width as f64 / 2.0,
+            params.height as f64 / 2.0,
+            params.pixels_per_meter,
+            -params.pixels_per_meter,
+            canvas.global_rot().to_degrees()
+        )
+        .unwrap();
+        for shape in canvas.shapes() {
+            self.write_shape(&mut out, shape, &Matrix3::identity());
+        }
+        writeln!(out, "</g>\n</svg>").unwrap();
+        out
+    }
+
+    // saves the canvas as <name>.svg in the export directory
+    pub fn save(&self, canvas: &Canvas, name: &str) {
+        let dir = self.params.dir.as_ref().unwrap();
+        let file_name = format!("{}/{}.svg", dir, name);
+        std::fs::write(&file_name, self.to_svg(canvas))
+            .unwrap_or_else(|e| panic!("Could not write {}: {}", file_name, e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg_shapes() {
+        let exporter = SvgExporter {
+            params: ExportParameters {
+                dir: None,
+                interval: 1,
+                width: 200,
+                height: 100,
+                pixels_per_meter: 10.0,
+            },
+        };
+        let mut canvas = Canvas::new();
+        canvas.draw(Shape::square().scale(2.0).color(Color::RED.set_a(0.5)));
+        canvas.draw(Shape::lines(&[0.0, 0.0, 3.0, 4.0], 20.0).color(Color::GREEN));
+        canvas.draw(Shape::text("a < b\nc", "Arial", 30.0));
+        canvas.draw(Shape::array(Shape::circle(), &[1.0, 1.0, 2.0, 2.0]));
+
+        let svg = exporter.to_svg(&canvas);
+        assert!(svg.contains(r##"fill="#ff0000" fill-opacity="0.5""##));
+        assert!(svg.contains(r#"points="0.000,0.000 3.000,4.000""#));
+        assert!(svg.contains(r#"stroke-width="2.000""#));
+        assert!(svg.contains("a &lt; b</tspan>"));
+        assert_eq!(svg.matches("<circle").count(), 2);
+    }
+}
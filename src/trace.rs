@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arg_parameters::Parameters,
+    graphics::{Color, Shape},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TraceKind {
+    // the ego car in a forward simulation, along with how that simulation went
+    Ego {
+        depth_level: u32,
+        crashed: bool,
+        not_safe: bool,
+        policy_id: u32,
+    },
+    DebugCar,
+    ObstacleCar,
+}
+
+// The path a car took through a forward simulation of the planner,
+// kept as plain data so it can be recorded and drawn later.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trace {
+    pub kind: TraceKind,
+    // x, y pairs
+    pub points: Vec<f64>,
+}
+
+impl Trace {
+    pub fn shapes(&self, params: &Parameters) -> Vec<Shape> {
+        let points = &self.points;
+        match self.kind {
+            TraceKind::Ego {
+                depth_level,
+                crashed,
+                not_safe,
+                policy_id,
+            } => {
+                let base_line_color = if crashed {
+                    Color::RED.scale_rgb(0.5)
+                } else if not_safe {
+                    Color::PINK
+                } else {
+                    Color::GREEN
+                };
+
+                let mut line_color = match depth_level {
+                    0 => base_line_color.set_a(0.6),
+                    1 => base_line_color.scale_rgb(0.6).set_a(0.6),
+                    2 => base_line_color.scale_rgb(0.5).set_a(0.6),
+                    _ => base_line_color.scale_rgb(0.4).set_a(0.6),
+                };
+
+                let mut line_width = match depth_level {
+                    0 => 12.0,
+                    1 => 6.0,
+                    2 => 3.0,
+                    _ => 1.5,
+                };
+                if params.graphics_for_paper {
+                    line_color = base_line_color.scale_rgb(0.6).set_a(0.6);
+                    line_width = 4.0;
+                }
+                if crashed || not_safe {
+                    line_width += 4.0;
+                }
+
+                // see make_policy_choices: odd ids accelerate, and the last one decelerates
+                let decelerate_policy_id = 2 * params.n_lanes as u32;
+                let dot_color = match policy_id {
+                    id if id == decelerate_policy_id => Color::BLUE,
+                    id if id < decelerate_policy_id && id % 2 == 1 => Color::RED,
+                    _ => Color::BLACK,
+                };
+
+                vec![
+                    Shape::lines(points, line_width).color(line_color),
+                    Shape::array(
+                        Shape::circle().scale(0.15).color(dot_color.set_a(0.4)),
+                        points,
+                    ),
+                ]
+            }
+            TraceKind::DebugCar => {
+                vec![Shape::lines(points, 6.0).color(Color::DARK_GRAY.set_a(0.9))]
+            }
+            TraceKind::ObstacleCar => {
+                vec![Shape::lines(points, 6.0).color(Color::WHITE.set_a(0.5))]
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    arg_parameters::Parameters,
+    car::{Car, FOLLOW_TIME_DEFAULT, PREFERRED_ACCEL_DEFAULT},
+    lane_change_policy::{LaneChangePolicy, LongitudinalPolicy},
+    mpdm::make_obstacle_vehicle_policy_choices,
+    reference_path::ReferencePath,
+    road::Road,
+    side_policies::{SidePolicy, SidePolicyTrait},
+};
+
+// A hand-crafted starting state for the road, loaded from a toml or json file
+// (by extension), used in place of randomly spawned cars.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Traffic {
+    pub ego: Option<TrafficEgo>,
+    pub cars: Vec<TrafficCar>,
+    #[serde(default)]
+    pub switches: Vec<PolicySwitch>,
+    // whether obstacle cars also change policies randomly, as they normally do
+    #[serde(default)]
+    pub random_policy_changes: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TrafficEgo {
+    pub s: f64,
+    pub lane: i32,
+    pub vel: f64,
+    pub preferred_vel: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TrafficCar {
+    pub s: f64,
+    pub lane: i32,
+    pub vel: f64,
+    // these default to vel and the usual defaults
+    pub preferred_vel: Option<f64>,
+    pub preferred_accel: Option<f64>,
+    pub preferred_follow_time: Option<f64>,
+    pub policy: PolicySpec,
+}
+
+// one of the obstacle vehicle policies, picked by its target lane
+// (none to keep the current lane) and longitudinal behavior
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PolicySpec {
+    pub lane: Option<i32>,
+    pub long: LongitudinalPolicy,
+    // false to change lanes without waiting for a gap, as in a cut-in
+    #[serde(default = "default_wait_for_clear")]
+    pub wait_for_clear: bool,
+}
+
+fn default_wait_for_clear() -> bool {
+    true
+}
+
+// at time t, obstacle car car_i switches to the given policy
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PolicySwitch {
+    pub t: f64,
+    pub car_i: usize,
+    pub policy: PolicySpec,
+}
+
+impl Traffic {
+    pub fn load(file_name: &str) -> Result<Self, config::ConfigError> {
+        let mut s = config::Config::new();
+        s.merge(config::File::with_name(file_name))?;
+        let mut traffic: Self = s.try_into()?;
+        // obstacle cars follow the ego car, which is car 0 and can't be switched
+        if let Some(switch) = traffic
+            .switches
+            .iter()
+            .find(|switch| switch.car_i == 0 || switch.car_i > traffic.cars.len())
+        {
+            return Err(config::ConfigError::Message(format!(
+                "Policy switch at t = {} has car_i {}, but obstacle cars are 1 to {}",
+                switch.t,
+                switch.car_i,
+                traffic.cars.len()
+            )));
+        }
+        traffic
+            .switches
+            .sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap());
+        Ok(traffic)
+    }
+
+    pub fn populate(&self, road: &mut Road) {
+        if let Some(ego) = &self.ego {
+            road.set_ego_start(ego.s, ego.lane, ego.vel, ego.preferred_vel);
+        }
+        for car in self.cars.iter() {
+            let car = car.make_car(&road.params, &road.path, road.cars.len());
+            road.cars.push(car);
+        }
+    }
+}
+
+impl TrafficCar {
+    fn make_car(&self, params: &Parameters, path: &ReferencePath, car_i: usize) -> Car {
+        let mut car = Car::new(params, path, car_i, self.lane);
+        car.set_frenet(path, self.s, Road::get_lane_y(self.lane));
+        car.vel = self.vel;
+        car.preferred_vel = self.preferred_vel.unwrap_or(self.vel);
+        car.preferred_accel = self.preferred_accel.unwrap_or(PREFERRED_ACCEL_DEFAULT);
+        car.preferred_follow_time = self.preferred_follow_time.unwrap_or(FOLLOW_TIME_DEFAULT);
+        car.side_policy = Some(self.policy.make_policy(params));
+        car
+    }
+}
+
+impl PolicySpec {
+    pub fn make_policy(&self, params: &Parameters) -> SidePolicy {
+        // reuse the policy id of the matching obstacle vehicle policy
+        let policy_id = make_obstacle_vehicle_policy_choices(params)
+            .into_iter()
+            .find_map(|p| match p {
+                SidePolicy::LaneChangePolicy(p)
+                    if p.target_lane_i() == self.lane && p.long_policy() == self.long =>
+                {
+                    Some(p.policy_id())
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("No obstacle vehicle policy matches {:?}", self));
+
+        SidePolicy::LaneChangePolicy(LaneChangePolicy::new(
+            policy_id,
+            self.lane,
+            params.lane_change_time,
+            self.wait_for_clear,
+            self.long,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_examples() {
+        let cut_in = Traffic::load("traffic/cut_in.toml").unwrap();
+        assert_eq!(cut_in.cars.len(), 2);
+        assert_eq!(cut_in.ego.as_ref().unwrap().vel, 11.0);
+        assert_eq!(cut_in.cars[0].preferred_accel, None);
+        assert!(cut_in.cars[0].policy.wait_for_clear);
+        assert!(!cut_in.switches[0].policy.wait_for_clear);
+        assert!(!cut_in.random_policy_changes);
+
+        let braking = Traffic::load("traffic/sudden_braking.json").unwrap();
+        assert_eq!(braking.cars.len(), 2);
+        assert_eq!(braking.switches[0].car_i, 1);
+        assert_eq!(braking.switches[0].policy.lane, None);
+        assert_eq!(
+            braking.switches[0].policy.long,
+            LongitudinalPolicy::Decelerate
+        );
+    }
+
+    #[test]
+    fn rejects_switches_for_missing_cars() {
+        let car = r#"{ "s": 50.0, "lane": 0, "vel": 10.0, "policy": { "lane": null, "long": "maintain" } }"#;
+        // car 1 is the only obstacle car
+        for &(car_i, valid) in [(0, false), (1, true), (2, false)].iter() {
+            let file_name = std::env::temp_dir().join(format!("switch_car_{}.json", car_i));
+            let contents = format!(
+                r#"{{ "cars": [{}], "switches": [{{ "t": 1.0, "car_i": {}, "policy": {{ "lane": null, "long": "decelerate" }} }}] }}"#,
+                car, car_i
+            );
+            std::fs::write(&file_name, contents).unwrap();
+            let result = Traffic::load(file_name.to_str().unwrap());
+            std::fs::remove_file(&file_name).unwrap();
+            assert_eq!(result.is_ok(), valid, "car_i {}", car_i);
+        }
+    }
+}
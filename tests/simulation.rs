@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use selfdriving::{
+    arg_parameters::Parameters,
+    planner::PlannerRegistry,
+    simulation::{run_with_parameters, State},
+};
+
+fn short_run(method: &str) -> Parameters {
+    let mut params = Parameters::new().unwrap();
+    params.method = method.to_owned();
+    params.max_steps = 40;
+    params.run_fast = true;
+    params
+}
+
+#[test]
+fn stepping_matches_full_run() {
+    let planners = PlannerRegistry::default();
+    let params = short_run("mpdm");
+    let planner = planners.get(&params.method);
+
+    let (cost, _) = run_with_parameters(params.clone(), planner);
+
+    let mut state = State::new(Arc::new(params));
+    let mut planner = planner.new_run();
+    while state.timesteps < state.params.max_steps {
+        state.update(state.params.physics_dt, planner.as_mut());
+    }
+    let (stepped_cost, reward) = state.finish();
+
+    assert!(stepped_cost == cost);
+    assert!(reward.dist_travelled > 0.0);
+}
+
+#[test]
+fn every_planner_runs() {
+    let planners = PlannerRegistry::default();
+    for method in ["fixed", "mpdm", "eudm", "mcts"] {
+        let mut params = short_run(method);
+        params.max_steps = 10;
+        let (cost, reward) = run_with_parameters(params, planners.get(method));
+        assert!(cost.total().is_finite(), "{}", method);
+        assert!(!reward.crashed, "{}", method);
+    }
+}